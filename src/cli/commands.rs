@@ -0,0 +1,518 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use clap::ArgMatches;
+use rusoto_ec2::Tag;
+use serde_json::{json, Value};
+
+use rust_ec2::config::InstanceConfig;
+use rust_ec2::minecraft::{MinecraftServer, RCON_PASSWORD_VAR};
+use rust_ec2::minecraft::idle::{IdleWatcher, WatchOutcome};
+use rust_ec2::minecraft::shutdown::{GracefulStop, PreStopHook};
+use rust_ec2::scheduler::{Clock, Fired, Schedule, Scheduler, SystemClock};
+use rust_ec2::scheduler::state::ScheduleState;
+use rust_ec2::ssh::parallel::ParallelSSH;
+use rust_ec2::ssh::ssh_agent::SSHAgent;
+use rust_ec2::virtual_machine::ec2::instance::Ec2Object;
+use rust_ec2::virtual_machine::ec2::plan::{self, Change, DesiredInstance, Plan};
+use rust_ec2::virtual_machine::ec2::spot::SpotOptions;
+use rust_ec2::virtual_machine::ec2::user_data::UserData;
+use rust_ec2::virtual_machine::ec2::waiter::Waiter;
+use rust_ec2::virtual_machine::vm::{VMCore, VMNetwork};
+
+use crate::cli::{Context, key_path, ssh_user};
+use crate::cli::output::{Output, table, or_dash};
+use crate::cli::target::{CopyPath, Target};
+
+const INSTANCE_HEADERS: &[&str] = &["INSTANCE", "TYPE", "STATE", "PUBLIC IP", "PRIVATE IP"];
+
+/// One row of the instance table and its json
+async fn describe(ec2: &Ec2Object) -> (Vec<String>, Value) {
+    let (state, public_ip, private_ip) = match ec2.network_info().await {
+        Ok(info) => (info.state, info.public_ip, info.private_ip),
+        Err(_) => (None, None, None)
+    };
+    let json = json!({
+        "instance_id": ec2.instance_id,
+        "instance_type": ec2.instance_type,
+        "image_id": ec2.image_id,
+        "state": state,
+        "public_ip": public_ip,
+        "private_ip": private_ip
+    });
+    let row = vec![ec2.instance_id.clone(), ec2.instance_type.clone(), or_dash(&state), or_dash(&public_ip), or_dash(&private_ip)];
+    (row, json)
+}
+
+async fn describe_all(instances: &[Ec2Object]) -> Output {
+    let mut rows = vec![];
+    let mut json = vec![];
+    for ec2 in instances {
+        let (row, value) = describe(ec2).await;
+        rows.push(row);
+        json.push(value);
+    }
+    Output::new(Value::Array(json), table(INSTANCE_HEADERS, &rows))
+}
+
+/// key=value as an ec2 tag
+fn parse_tag(tag: &str) -> Result<Tag, Box<dyn Error>> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok(Tag {
+            key: Some(key.to_string()),
+            value: Some(value.to_string())
+        }),
+        _ => Err(format!("expected a tag as key=value, got <{}>", tag).into())
+    }
+}
+
+/// target argument resolved with its settings
+async fn targets(ctx: &Context, args: &ArgMatches<'_>) -> Result<Vec<Ec2Object>, Box<dyn Error>> {
+    let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+    target.resolve(&target.settings(ctx)?).await
+}
+
+/// (instance id, state) after a state change
+fn state_changes(changes: Vec<(String, Option<String>)>) -> Output {
+    let json = changes.iter()
+        .map(|(id, state)| json!({"instance_id": id, "state": state}))
+        .collect();
+    let rows: Vec<Vec<String>> = changes.iter()
+        .map(|(id, state)| vec![id.clone(), or_dash(state)])
+        .collect();
+    Output::new(Value::Array(json), table(&["INSTANCE", "STATE"], &rows))
+}
+
+pub async fn list(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let client = ctx.settings(None)?.ec2_client()?;
+    let instances = match args.value_of("tag") {
+        Some(tag) => Ec2Object::retrieve_by_tags_with(client, &[parse_tag(tag)?]).await?,
+        None => Ec2Object::list_with(client).await?
+    };
+    Ok(describe_all(&instances).await)
+}
+
+pub async fn status(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let instances = targets(ctx, args).await?;
+    Ok(describe_all(&instances).await)
+}
+
+pub async fn start(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let mut changes = vec![];
+    for mut ec2 in targets(ctx, args).await? {
+        ec2.start().await?;
+        changes.push((ec2.instance_id.clone(), ec2.status().await));
+    }
+    Ok(state_changes(changes))
+}
+
+pub async fn stop(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    if args.is_present("graceful") {
+        return stop_gracefully(ctx, args).await;
+    }
+    let mut changes = vec![];
+    for mut ec2 in targets(ctx, args).await? {
+        ec2.stop().await?;
+        changes.push((ec2.instance_id.clone(), ec2.status().await));
+    }
+    Ok(state_changes(changes))
+}
+
+/// --graceful and the arguments that go with it, for settings' instances
+fn graceful_stop(settings: &InstanceConfig, args: &ArgMatches<'_>) -> Result<GracefulStop, Box<dyn Error>> {
+    let hook = match args.value_of("ssh-command") {
+        Some(command) => PreStopHook::Ssh {
+            command: command.to_string(),
+            user: ssh_user(settings),
+            key_path: key_path(settings)?
+        },
+        None => PreStopHook::Rcon
+    };
+    let mut graceful = GracefulStop {
+        hook,
+        snapshot: args.is_present("snapshot"),
+        world_volume_id: args.value_of("volume").map(str::to_string),
+        ..Default::default()
+    };
+    graceful.waiter.timeout = Duration::from_secs(args.value_of("timeout").unwrap().parse()?);
+    Ok(graceful)
+}
+
+/// stop after shutting minecraft down, see minecraft::shutdown
+async fn stop_gracefully(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+    let settings = target.settings(ctx)?;
+    let graceful = graceful_stop(&settings, args)?;
+
+    let mut json = vec![];
+    let mut rows = vec![];
+    for mut ec2 in target.resolve(&settings).await? {
+        let mut server = MinecraftServer::on_vm(&ec2).await?;
+        server.rcon_password = env::var(RCON_PASSWORD_VAR).ok();
+        let snapshot_ids = graceful.stop_gracefully(&mut ec2, &server).await?;
+        let state = ec2.status().await;
+        let snapshots = if snapshot_ids.is_empty() { "-".to_string() } else { snapshot_ids.join(", ") };
+        json.push(json!({"instance_id": ec2.instance_id, "state": state, "snapshot_ids": snapshot_ids}));
+        rows.push(vec![ec2.instance_id.clone(), or_dash(&state), snapshots]);
+    }
+    Ok(Output::new(Value::Array(json), table(&["INSTANCE", "STATE", "SNAPSHOTS"], &rows)))
+}
+
+pub async fn reboot(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let mut changes = vec![];
+    for ec2 in targets(ctx, args).await? {
+        ec2.reboot().await?;
+        changes.push((ec2.instance_id.clone(), Some("rebooting".to_string())));
+    }
+    Ok(state_changes(changes))
+}
+
+pub async fn terminate(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let instances = targets(ctx, args).await?;
+    if !args.is_present("yes") {
+        let ids: Vec<&str> = instances.iter().map(|ec2| ec2.instance_id.as_str()).collect();
+        return Err(format!("this deletes {:?}, pass --yes to terminate", ids).into());
+    }
+    let mut changes = vec![];
+    for mut ec2 in instances {
+        let state = ec2.terminate().await?;
+        changes.push((ec2.instance_id.clone(), Some(state)));
+    }
+    Ok(state_changes(changes))
+}
+
+/// Launches with the settings of the named config instance if given, flags over them
+pub async fn launch(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let settings = ctx.settings(args.value_of("name"))?;
+    let mut options = settings.launch_options();
+    if let Some(image) = args.value_of("image") {
+        options.image_id = image.to_string();
+    }
+    if let Some(instance_type) = args.value_of("type") {
+        options.instance_type = instance_type.to_string();
+    }
+    if let Some(key_name) = args.value_of("key-name") {
+        options.key_name = Some(key_name.to_string());
+    }
+    if let Some(tags) = args.values_of("tag") {
+        options.tags = tags.map(parse_tag).collect::<Result<Vec<Tag>, Box<dyn Error>>>()?;
+    }
+    if let Some(path) = args.value_of("user-data") {
+        let contents = fs::read_to_string(path)?;
+        options.user_data = Some(UserData::Script(contents));
+    }
+    if args.is_present("spot") {
+        options.spot = Some(SpotOptions {
+            max_price: args.value_of("max-price").map(str::to_string),
+            ..Default::default()
+        });
+    }
+
+    let ec2 = Ec2Object::launch_with(settings.ec2_client()?, &options).await?;
+    let (row, json) = describe(&ec2).await;
+    Ok(Output::new(json, table(INSTANCE_HEADERS, &[row])))
+}
+
+/// minecraft server on the one instance the target argument matches
+async fn minecraft_server(ctx: &Context, args: &ArgMatches<'_>) -> Result<(Ec2Object, MinecraftServer), Box<dyn Error>> {
+    let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+    let ec2 = target.resolve_one(&target.settings(ctx)?).await?;
+    let mut server = MinecraftServer::on_vm(&ec2).await?;
+    server.rcon_password = env::var(RCON_PASSWORD_VAR).ok();
+    Ok((ec2, server))
+}
+
+pub async fn ping(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let (ec2, server) = minecraft_server(ctx, args).await?;
+    let status = server.status().await?;
+    let json = json!({
+        "instance_id": ec2.instance_id,
+        "version": status.version,
+        "protocol": status.protocol,
+        "motd": status.motd,
+        "players_online": status.players_online,
+        "players_max": status.players_max,
+        "player_sample": status.player_sample
+    });
+    let players = format!("{}/{} {}", status.players_online, status.players_max, status.player_sample.join(", "));
+    let human = table(&["INSTANCE", "VERSION", "PLAYERS", "MOTD"],
+                      &[vec![ec2.instance_id.clone(), status.version, players.trim_end().to_string(), status.motd]]);
+    Ok(Output::new(json, human))
+}
+
+pub async fn rcon(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let (ec2, server) = minecraft_server(ctx, args).await?;
+    let command = args.values_of("command").unwrap().collect::<Vec<&str>>().join(" ");
+    let output = server.rcon().await?.command(&command).await?;
+    Ok(Output::new(json!({"instance_id": ec2.instance_id, "output": output}), output))
+}
+
+/// Blocks until the instance has had no players for --idle minutes, then stops it,
+/// shutting minecraft down first with --graceful
+pub async fn watch(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let (mut ec2, server) = minecraft_server(ctx, args).await?;
+    let graceful = if args.is_present("graceful") {
+        let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+        Some(graceful_stop(&target.settings(ctx)?, args)?)
+    } else {
+        None
+    };
+    let watcher = IdleWatcher {
+        check_interval: Duration::from_secs(args.value_of("interval").unwrap().parse()?),
+        idle_timeout: Duration::from_secs(args.value_of("idle").unwrap().parse::<u64>()? * 60),
+        grace_period: Duration::from_secs(args.value_of("grace").unwrap().parse()?),
+        unreachable_is_idle: args.is_present("stop-unreachable"),
+        graceful
+    };
+    let state = match watcher.watch(&mut ec2, &server).await? {
+        WatchOutcome::Stopped => "stopped",
+        WatchOutcome::StoppedElsewhere => "stopped elsewhere"
+    };
+    Ok(state_changes(vec![(ec2.instance_id.clone(), Some(state.to_string()))]))
+}
+
+pub async fn schedule(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let schedules = ctx.config.schedules()?;
+    if schedules.is_empty() {
+        return Err(format!("no [schedules] in the config, see {}", rust_ec2::config::PROJECT_FILE).into());
+    }
+    if args.is_present("list") {
+        return Ok(upcoming(&schedules));
+    }
+    let state_path = match args.value_of("state") {
+        Some(path) => PathBuf::from(path),
+        None => ScheduleState::default_path().ok_or("no data directory to keep the schedule state in, use --state")?
+    };
+    let mut scheduler = Scheduler::new(schedules, SystemClock, ScheduleState::load(&state_path)?);
+    let resolve = move |target: String| async move {
+        let target = Target::parse(&target, &ctx.config)?;
+        target.resolve(&target.settings(ctx)?).await
+    };
+    if args.is_present("once") {
+        return Ok(fired(&scheduler.tick(resolve).await?));
+    }
+    let interval = Duration::from_secs(args.value_of("interval").unwrap().parse()?);
+    scheduler.run(resolve, interval, |event| fired(std::slice::from_ref(event)).print(ctx.json)).await?;
+    Ok(Output::new(Value::Null, String::new()))
+}
+
+/// one row per vm each event started or stopped
+fn fired(events: &[Fired]) -> Output {
+    let mut json = vec![];
+    let mut rows = vec![];
+    for event in events {
+        let results: Vec<Value> = event.results.iter()
+            .map(|result| match result {
+                Ok(state) => json!({"state": state}),
+                Err(e) => json!({"error": e})
+            })
+            .collect();
+        json.push(json!({
+            "schedule": event.schedule,
+            "target": event.target,
+            "event": event.event.to_string(),
+            "at": event.at.to_rfc3339(),
+            "results": results
+        }));
+        for result in &event.results {
+            let result = match result {
+                Ok(state) => state.clone(),
+                Err(e) => format!("error: {}", e)
+            };
+            rows.push(vec![event.schedule.clone(), event.event.to_string(), event.target.clone(), event.at.to_rfc3339(), result]);
+        }
+    }
+    Output::new(Value::Array(json), table(&["SCHEDULE", "EVENT", "TARGET", "DUE", "RESULT"], &rows))
+}
+
+/// each schedule's next event, in the schedule's time zone
+fn upcoming(schedules: &[Schedule]) -> Output {
+    let now = SystemClock.now();
+    let mut json = vec![];
+    let mut rows = vec![];
+    for schedule in schedules {
+        let next = schedule.next_event(now);
+        let at = next.map(|(_, at)| at.with_timezone(&schedule.timezone).to_rfc3339());
+        let event = next.map(|(event, _)| event.to_string());
+        json.push(json!({"schedule": schedule.name, "target": schedule.target, "event": event, "at": at}));
+        rows.push(vec![schedule.name.clone(), schedule.target.clone(), or_dash(&event), or_dash(&at)]);
+    }
+    Output::new(Value::Array(json), table(&["SCHEDULE", "TARGET", "NEXT", "AT"], &rows))
+}
+
+/// Settings an ec2 client is made from and the desired instances it reaches
+type ClientGroup = (InstanceConfig, Vec<DesiredInstance>);
+
+/// Config instances grouped by the account and region they are in, so each is described once
+fn desired_by_client(ctx: &Context) -> Result<Vec<ClientGroup>, Box<dyn Error>> {
+    let mut groups: Vec<ClientGroup> = vec![];
+    for name in ctx.config.instances.keys() {
+        let settings = ctx.settings(Some(name))?;
+        let desired = settings.desired(name);
+        let same_client = |other: &InstanceConfig| other.region == settings.region
+            && other.role_arn == settings.role_arn
+            && other.credentials == settings.credentials;
+        match groups.iter_mut().find(|(other, _)| same_client(other)) {
+            Some((_, group)) => group.push(desired),
+            None => groups.push((settings, vec![desired]))
+        }
+    }
+    Ok(groups)
+}
+
+/// Plans the changes that bring every instance in line with the config, making them unless dry_run.
+///     Errors without changing anything if an instance would be replaced or terminated and yes isn't set
+pub async fn apply(ctx: &Context, dry_run: bool, yes: bool) -> Result<Output, Box<dyn Error>> {
+    let mut plans = vec![];
+    for (settings, desired) in desired_by_client(ctx)? {
+        let client = settings.ec2_client()?;
+        let actual = plan::snapshot(&client).await?;
+        plans.push((client, Plan::new(&desired, &actual)?));
+    }
+
+    let changes: Vec<Value> = plans.iter()
+        .flat_map(|(_, plan)| plan.changes.iter())
+        .map(|change| json!({"name": change.name, "instance_id": change.instance_id, "change": change.action.to_string()}))
+        .collect();
+    let mut human = plans.iter()
+        .filter(|(_, plan)| !plan.is_empty())
+        .map(|(_, plan)| plan.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    if human.is_empty() {
+        human = Plan::default().to_string();
+    }
+    let destructive: Vec<String> = plans.iter()
+        .flat_map(|(_, plan)| plan.destructive())
+        .map(Change::to_string)
+        .collect();
+    if !dry_run && !yes && !destructive.is_empty() {
+        return Err(format!("this deletes instances {:?}, pass --yes to apply", destructive).into());
+    }
+    if !dry_run {
+        //show what is about to happen, applying can take minutes
+        Output::new(Value::Null, human.clone()).print(ctx.json);
+        for (client, plan) in &plans {
+            plan.apply(client, &Waiter::default()).await?;
+        }
+        human = format!("applied {} changes", changes.len());
+    }
+    Ok(Output::new(Value::Array(changes), human))
+}
+
+/// Hands the terminal to the system ssh client, it handles ptys and keys better than ssh2
+pub async fn ssh(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+    let settings = target.settings(ctx)?;
+    let ec2 = target.resolve_one(&settings).await?;
+    let ip = match ec2.get_public_ip().await {
+        Some(ip) => ip,
+        None => return Err(format!("<{}> has no public ip, is it running?", ec2.instance_id).into())
+    };
+    let status = Command::new("ssh")
+        .arg("-i").arg(key_path(&settings)?)
+        .arg(format!("{}@{}", ssh_user(&settings), ip))
+        .status()?;
+    if !status.success() {
+        return Err(format!("ssh exited with {}", status).into());
+    }
+    Ok(Output::new(Value::Null, String::new()))
+}
+
+pub async fn exec(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let target = Target::parse(args.value_of("target").unwrap(), &ctx.config)?;
+    let settings = target.settings(ctx)?;
+    let instances = target.resolve(&settings).await?;
+    let command = args.values_of("command").unwrap().collect::<Vec<&str>>().join(" ");
+    let max_parallel = args.value_of("parallel").unwrap().parse::<usize>()?;
+
+    let mut pssh = ParallelSSH::new(&key_path(&settings)?, max_parallel);
+    pssh.user = ssh_user(&settings);
+    let hosts: Vec<(String, Ec2Object)> = instances.into_iter()
+        .map(|ec2| (ec2.instance_id.clone(), ec2))
+        .collect();
+    let summary = pssh.execute(&hosts, &command).await;
+
+    let json = summary.outputs.iter()
+        .map(|output| match &output.result {
+            Ok(result) => json!({"instance_id": output.host, "exit_status": result.exit_status, "stdout": result.stdout}),
+            Err(e) => json!({"instance_id": output.host, "error": e})
+        })
+        .collect();
+    let human = summary.outputs.iter()
+        .map(|output| match &output.result {
+            Ok(result) => format!("== {} (exit {})\n{}", output.host, result.exit_status, result.stdout.trim_end()),
+            Err(e) => format!("== {} (failed)\n{}", output.host, e)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    if !summary.all_succeeded() {
+        Output::new(json, human).print(ctx.json);
+        return Err(format!("command failed on {:?}", summary.failed()).into());
+    }
+    Ok(Output::new(json, human))
+}
+
+pub async fn cp(ctx: &Context, args: &ArgMatches<'_>) -> Result<Output, Box<dyn Error>> {
+    let source = CopyPath::parse(args.value_of("source").unwrap(), &ctx.config);
+    let destination = CopyPath::parse(args.value_of("destination").unwrap(), &ctx.config);
+    let (target, local, remote, upload) = match (source, destination) {
+        (CopyPath::Local(local), CopyPath::Remote { target, path }) => {
+            //copying into a directory keeps the file name, like scp
+            let remote = if path.is_empty() || path.ends_with('/') {
+                format!("{}{}", path, file_name(&local)?)
+            } else {
+                path
+            };
+            (target, local, remote, true)
+        },
+        (CopyPath::Remote { target, path }, CopyPath::Local(local)) => {
+            let local = if local.is_dir() {
+                local.join(file_name(Path::new(&path))?)
+            } else {
+                local
+            };
+            (target, local, path, false)
+        },
+        _ => return Err("cp needs exactly one side on an instance, eg. cp world.zip i-0123:/opt/minecraft/".into())
+    };
+
+    let settings = target.settings(ctx)?;
+    let ec2 = target.resolve_one(&settings).await?;
+    let ip = match ec2.get_public_ip().await {
+        Some(ip) => ip,
+        None => return Err(format!("<{}> has no public ip, is it running?", ec2.instance_id).into())
+    };
+    let agent = SSHAgent::connect(&ip, &ssh_user(&settings), &key_path(&settings)?)?;
+    let bytes = if upload {
+        agent.upload(&local, &remote)?
+    } else {
+        agent.download(&remote, &local)?
+    };
+    Ok(Output::new(json!({"instance_id": ec2.instance_id, "bytes": bytes}),
+                   format!("copied {} bytes", bytes)))
+}
+
+fn file_name(path: &Path) -> Result<String, Box<dyn Error>> {
+    match path.file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(format!("<{}> has no file name", path.display()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tag = parse_tag("Name=survival").unwrap();
+        assert_eq!(tag.key, Some("Name".to_string()));
+        assert_eq!(tag.value, Some("survival".to_string()));
+        assert!(parse_tag("survival").is_err());
+    }
+}
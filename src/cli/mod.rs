@@ -0,0 +1,267 @@
+//! rust_ec2 command line, wraps the library so instances can be managed without writing code
+extern crate clap;
+
+pub mod commands;
+pub mod output;
+pub mod target;
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use self::clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use rust_ec2::config::{Config, CredentialsConfig, InstanceConfig};
+use rust_ec2::ssh::ssh_agent::SSH_USER;
+
+/// Everything commands share, built from the config files and global arguments
+pub struct Context {
+    pub config: Config,
+    /// settings given on the command line, these win over the config
+    pub overrides: InstanceConfig,
+    pub json: bool
+}
+
+impl Context {
+    pub fn from_args(matches: &ArgMatches, sub: &ArgMatches) -> Result<Context, Box<dyn Error>> {
+        let config = match global_value(matches, sub, "config") {
+            Some(path) => Config::load_file(&PathBuf::from(path))?,
+            None => Config::load()?
+        };
+        let credentials = CredentialsConfig {
+            profile: global_value(matches, sub, "profile").map(str::to_string),
+            csv: global_value(matches, sub, "credentials-csv").map(PathBuf::from),
+            vault: global_value(matches, sub, "vault").map(PathBuf::from),
+            vault_entry: global_value(matches, sub, "vault-entry").map(str::to_string)
+        };
+        let has_credentials = credentials != CredentialsConfig::default();
+        Ok(Context {
+            config,
+            overrides: InstanceConfig {
+                region: global_value(matches, sub, "region").map(str::to_string),
+                role_arn: global_value(matches, sub, "role-arn").map(str::to_string),
+                session_name: global_value(matches, sub, "session-name").map(str::to_string),
+                credentials: Some(credentials).filter(|_| has_credentials),
+                key_path: global_value(matches, sub, "key").map(PathBuf::from),
+                ssh_user: global_value(matches, sub, "user").map(str::to_string),
+                ..Default::default()
+            },
+            json: global_flag(matches, sub, "json")
+        })
+    }
+
+    /// Settings of the named instance in the config, or the config defaults, with the command line over them.
+    ///     Errors if name isn't in the config
+    pub fn settings(&self, name: Option<&str>) -> Result<InstanceConfig, Box<dyn Error>> {
+        let base = match name {
+            Some(name) => match self.config.instance(name) {
+                Some(instance) => instance,
+                None => return Err(format!("no instance named <{}> in the config", name).into())
+            },
+            None => self.config.defaults.clone()
+        };
+        Ok(base.merge(self.overrides.clone()))
+    }
+}
+
+/// --key or key_path in the config, needed by anything that connects with ssh
+pub fn key_path(settings: &InstanceConfig) -> Result<PathBuf, Box<dyn Error>> {
+    match settings.key_path() {
+        Some(key_path) => Ok(key_path),
+        None => Err("pass the private key to connect with, --key <path> or key_path in the config".into())
+    }
+}
+
+/// --user or ssh_user in the config, ubuntu if neither is set
+pub fn ssh_user(settings: &InstanceConfig) -> String {
+    settings.ssh_user.clone().unwrap_or_else(|| SSH_USER.to_string())
+}
+
+/// global arguments end up on whichever side of the subcommand they were given
+fn global_value<'a>(matches: &'a ArgMatches, sub: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    sub.value_of(name).or_else(|| matches.value_of(name))
+}
+
+fn global_flag(matches: &ArgMatches, sub: &ArgMatches, name: &str) -> bool {
+    sub.is_present(name) || matches.is_present(name)
+}
+
+fn target_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("target")
+        .required(true)
+        .help("instance id (i-...), tag (key=value) or name of an instance in the config")
+}
+
+/// how to shut minecraft down before stopping, see commands::graceful_stop
+fn graceful_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("graceful").long("graceful")
+            .help("shut minecraft down first, with save-all flush and stop over rcon unless --ssh-command is given"),
+        Arg::with_name("ssh-command").long("ssh-command").takes_value(true).requires("graceful")
+            .help("command shutting the server down, eg. \"sudo systemctl stop minecraft\""),
+        Arg::with_name("timeout").long("timeout").takes_value(true).default_value("120").requires("graceful")
+            .help("seconds to wait for the server to exit before giving up without stopping"),
+        Arg::with_name("snapshot").long("snapshot").requires("graceful")
+            .help("snapshot the world once the server has exited"),
+        Arg::with_name("volume").long("volume").takes_value(true).requires("snapshot")
+            .help("volume the world is on, otherwise every volume is snapshotted")
+    ]
+}
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("rust_ec2")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Manage ec2 instances")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("json").long("json").global(true).help("print json instead of tables"))
+        .arg(Arg::with_name("config").long("config").takes_value(true).global(true)
+            .help("config file to use instead of ./rust_ec2.toml over the user config"))
+        .arg(Arg::with_name("region").long("region").takes_value(true).global(true)
+            .help("aws region, eg. us-east-2"))
+        .arg(Arg::with_name("profile").long("profile").takes_value(true).global(true)
+            .help("profile in ~/.aws/credentials or ~/.aws/config"))
+        .arg(Arg::with_name("credentials-csv").long("credentials-csv").takes_value(true).global(true)
+            .help("csv downloaded from the IAM console"))
+        .arg(Arg::with_name("vault").long("vault").takes_value(true).global(true)
+            .help("encrypted credential vault, passphrase read from RUST_EC2_VAULT_PASSPHRASE"))
+        .arg(Arg::with_name("vault-entry").long("vault-entry").takes_value(true).global(true)
+            .help("name of the credential in --vault [default: default]"))
+        .arg(Arg::with_name("role-arn").long("role-arn").takes_value(true).global(true)
+            .help("role to assume with the credentials"))
+        .arg(Arg::with_name("session-name").long("session-name").takes_value(true).global(true)
+            .help("session name to assume --role-arn as [default: minecraft-session]"))
+        .arg(Arg::with_name("key").long("key").short("i").takes_value(true).global(true)
+            .help("private key for ssh, exec and cp"))
+        .arg(Arg::with_name("user").long("user").takes_value(true).global(true)
+            .help("user to ssh as [default: ubuntu]"))
+        .subcommand(SubCommand::with_name("list").about("List instances")
+            .arg(Arg::with_name("tag").long("tag").takes_value(true).help("only instances with tag key=value")))
+        .subcommand(SubCommand::with_name("status").about("Show the state and addresses of instances")
+            .arg(target_arg()))
+        .subcommand(SubCommand::with_name("start").about("Start instances and wait until they are running")
+            .arg(target_arg()))
+        .subcommand(SubCommand::with_name("stop").about("Stop instances and wait until they are stopped")
+            .arg(target_arg())
+            .args(&graceful_args()))
+        .subcommand(SubCommand::with_name("reboot").about("Reboot instances")
+            .arg(target_arg()))
+        .subcommand(SubCommand::with_name("terminate").about("Terminate instances, deleting them")
+            .arg(target_arg())
+            .arg(Arg::with_name("yes").long("yes").help("confirm terminating every matching instance")))
+        .subcommand(SubCommand::with_name("launch").about("Launch a new instance")
+            .arg(Arg::with_name("name").help("instance in the config to launch with the settings of"))
+            .arg(Arg::with_name("image").long("image").takes_value(true).help("ami id"))
+            .arg(Arg::with_name("type").long("type").takes_value(true).help("instance type, eg. t3.medium"))
+            .arg(Arg::with_name("key-name").long("key-name").takes_value(true).help("key pair to allow ssh with"))
+            .arg(Arg::with_name("tag").long("tag").takes_value(true).multiple(true).number_of_values(1)
+                .help("tag key=value, replaces the default tag"))
+            .arg(Arg::with_name("user-data").long("user-data").takes_value(true).help("script or cloud-config file run on first boot"))
+            .arg(Arg::with_name("spot").long("spot").help("launch as a one time spot instance"))
+            .arg(Arg::with_name("max-price").long("max-price").takes_value(true).requires("spot")
+                .help("highest hourly spot price in USD")))
+        .subcommand(SubCommand::with_name("ping").about("Show the version, motd and players of the minecraft server on an instance")
+            .arg(target_arg()))
+        .subcommand(SubCommand::with_name("rcon").about("Run a minecraft server command, rcon password read from RUST_EC2_RCON_PASSWORD")
+            .arg(target_arg())
+            .arg(Arg::with_name("command").required(true).multiple(true).last(true)))
+        .subcommand(SubCommand::with_name("watch").about("Stop an instance once nobody has played minecraft on it for a while")
+            .arg(target_arg())
+            .arg(Arg::with_name("idle").long("idle").takes_value(true).default_value("15")
+                .help("minutes without players before stopping"))
+            .arg(Arg::with_name("grace").long("grace").takes_value(true).default_value("120")
+                .help("seconds between warning players and stopping, rcon password read from RUST_EC2_RCON_PASSWORD"))
+            .arg(Arg::with_name("interval").long("interval").takes_value(true).default_value("60")
+                .help("seconds between player checks"))
+            .arg(Arg::with_name("stop-unreachable").long("stop-unreachable")
+                .help("count a server that doesn't answer as empty, eg. after it crashed"))
+            .args(&graceful_args()))
+        .subcommand(SubCommand::with_name("schedule").about("Start and stop instances on the config's [schedules], running until killed")
+            .arg(Arg::with_name("once").long("once").help("check once and exit, eg. when run from cron"))
+            .arg(Arg::with_name("list").long("list").help("only show when each schedule next starts or stops"))
+            .arg(Arg::with_name("interval").long("interval").takes_value(true).default_value("30")
+                .help("seconds between checks"))
+            .arg(Arg::with_name("state").long("state").takes_value(true)
+                .help("file remembering what has already fired, defaults to ~/.local/share/rust_ec2/schedule.json")))
+        .subcommand(SubCommand::with_name("plan").about("Show what apply would change to match the instances in the config"))
+        .subcommand(SubCommand::with_name("apply").about("Launch, change, start, stop or terminate instances to match the config")
+            .arg(Arg::with_name("dry-run").long("dry-run").help("only show the changes, like plan"))
+            .arg(Arg::with_name("yes").long("yes").help("confirm replacing or terminating instances")))
+        .subcommand(SubCommand::with_name("ssh").about("Open an interactive ssh session")
+            .arg(target_arg()))
+        .subcommand(SubCommand::with_name("exec").about("Run a command over ssh on instances")
+            .arg(target_arg())
+            .arg(Arg::with_name("command").required(true).multiple(true).last(true))
+            .arg(Arg::with_name("parallel").long("parallel").takes_value(true).default_value("8")
+                .help("most instances to connect to at once")))
+        .subcommand(SubCommand::with_name("cp").about("Copy a file to or from an instance, eg. cp world.zip i-0123:/opt/minecraft/")
+            .arg(Arg::with_name("source").required(true))
+            .arg(Arg::with_name("destination").required(true)))
+}
+
+/// Runs the subcommand in matches
+pub async fn run(matches: &ArgMatches<'_>) -> Result<(), Box<dyn Error>> {
+    let (name, sub) = match matches.subcommand() {
+        (name, Some(sub)) => (name, sub),
+        _ => return Err("no subcommand given".into())
+    };
+    let ctx = Context::from_args(matches, sub)?;
+
+    let output = match name {
+        "list" => commands::list(&ctx, sub).await?,
+        "status" => commands::status(&ctx, sub).await?,
+        "start" => commands::start(&ctx, sub).await?,
+        "stop" => commands::stop(&ctx, sub).await?,
+        "reboot" => commands::reboot(&ctx, sub).await?,
+        "terminate" => commands::terminate(&ctx, sub).await?,
+        "launch" => commands::launch(&ctx, sub).await?,
+        "ping" => commands::ping(&ctx, sub).await?,
+        "rcon" => commands::rcon(&ctx, sub).await?,
+        "watch" => commands::watch(&ctx, sub).await?,
+        "schedule" => commands::schedule(&ctx, sub).await?,
+        "plan" => commands::apply(&ctx, true, false).await?,
+        "apply" => commands::apply(&ctx, sub.is_present("dry-run"), sub.is_present("yes")).await?,
+        "ssh" => commands::ssh(&ctx, sub).await?,
+        "exec" => commands::exec(&ctx, sub).await?,
+        "cp" => commands::cp(&ctx, sub).await?,
+        other => return Err(format!("unknown subcommand <{}>", other).into())
+    };
+    output.print(ctx.json);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globals_either_side_of_subcommand() {
+        let matches = app().get_matches_from(vec!["rust_ec2", "--json", "status", "i-0123", "--key", "game.pem"]);
+        let sub = matches.subcommand_matches("status").unwrap();
+        assert!(global_flag(&matches, sub, "json"));
+        assert_eq!(global_value(&matches, sub, "key"), Some("game.pem"));
+        assert_eq!(sub.value_of("target"), Some("i-0123"));
+    }
+
+    #[test]
+    fn command_line_over_config() -> Result<(), Box<dyn Error>> {
+        let matches = app().get_matches_from(vec!["rust_ec2", "status", "minecraft", "--user", "admin", "--profile", "other"]);
+        let sub = matches.subcommand_matches("status").unwrap();
+        let mut ctx = Context::from_args(&matches, sub)?;
+        ctx.config = Config::parse("[instances.minecraft]\nssh_user = \"ubuntu\"\nregion = \"eu-west-2\"")?;
+
+        let settings = ctx.settings(Some("minecraft"))?;
+        assert_eq!(ssh_user(&settings), "admin");
+        assert_eq!(settings.region, Some("eu-west-2".to_string()));
+        assert_eq!(settings.credentials.unwrap().profile, Some("other".to_string()));
+        assert!(ctx.settings(Some("missing")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn exec_command_after_separator() {
+        let matches = app().get_matches_from(vec!["rust_ec2", "exec", "minecraft=minecraft", "--", "df", "-h"]);
+        let sub = matches.subcommand_matches("exec").unwrap();
+        let command: Vec<&str> = sub.values_of("command").unwrap().collect();
+        assert_eq!(command, vec!["df", "-h"]);
+    }
+}
@@ -0,0 +1,73 @@
+use serde_json::Value;
+
+/// What a command prints, a table for people or json for scripts
+pub struct Output {
+    pub json: Value,
+    pub human: String
+}
+
+impl Output {
+    pub fn new(json: Value, human: String) -> Output {
+        Output {
+            json,
+            human
+        }
+    }
+
+    pub fn print(&self, as_json: bool) {
+        if as_json {
+            //nothing to report, eg. after an interactive ssh session
+            if self.json.is_null() {
+                return;
+            }
+            println!("{}", serde_json::to_string_pretty(&self.json).unwrap());
+        } else if !self.human.is_empty() {
+            println!("{}", self.human);
+        }
+    }
+}
+
+/// Rows under headers with each column padded to its widest value
+pub fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, value) in widths.iter_mut().zip(row) {
+            *width = (*width).max(value.len());
+        }
+    }
+
+    let line = |values: Vec<&str>| -> String {
+        values.iter()
+            .zip(&widths)
+            .map(|(value, width)| format!("{:width$}", value, width = width))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let mut lines = vec![line(headers.to_vec())];
+    lines.extend(rows.iter().map(|row| line(row.iter().map(String::as_str).collect())));
+    lines.join("\n")
+}
+
+/// value, or - for a missing one
+pub fn or_dash(value: &Option<String>) -> String {
+    value.clone().unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_table() {
+        let rows = vec![
+            vec!["i-0123".to_string(), "running".to_string()],
+            vec!["i-0123456789".to_string(), "stopped".to_string()]
+        ];
+        assert_eq!(table(&["ID", "STATE"], &rows), "\
+ID            STATE
+i-0123        running
+i-0123456789  stopped");
+    }
+}
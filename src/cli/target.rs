@@ -0,0 +1,147 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use rust_ec2::config::{Config, InstanceConfig};
+use rust_ec2::virtual_machine::ec2::instance::Ec2Object;
+
+use crate::cli::Context;
+
+/// Instances a command runs on, given as an instance id, a key=value tag or an instance in the config
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Id(String),
+    Tag {
+        key: String,
+        val: String
+    },
+    Named(String)
+}
+
+impl Target {
+    /// i-... is an instance id, key=value a tag, anything else has to be an instance in config
+    pub fn parse(target: &str, config: &Config) -> Result<Target, Box<dyn Error>> {
+        if target.starts_with("i-") {
+            return Ok(Target::Id(target.to_string()));
+        }
+        match target.split_once('=') {
+            Some((key, val)) if !key.is_empty() => Ok(Target::Tag {
+                key: key.to_string(),
+                val: val.to_string()
+            }),
+            _ if config.instances.contains_key(target) => Ok(Target::Named(target.to_string())),
+            _ => Err(format!("expected an instance id (i-...), a tag (key=value) or an instance in {}, got <{}>",
+                             rust_ec2::config::PROJECT_FILE, target).into())
+        }
+    }
+
+    /// Settings to reach target with, the named instance's for Named
+    pub fn settings(&self, ctx: &Context) -> Result<InstanceConfig, Box<dyn Error>> {
+        match self {
+            Target::Named(name) => ctx.settings(Some(name)),
+            _ => ctx.settings(None)
+        }
+    }
+
+    /// Every instance target matches, looked up with settings.
+    ///     Errors if there are none
+    pub async fn resolve(&self, settings: &InstanceConfig) -> Result<Vec<Ec2Object>, Box<dyn Error>> {
+        let client = settings.ec2_client()?;
+        let instances = match self {
+            Target::Id(id) => Ec2Object::retrieve_with(client, id).await?.into_iter().collect(),
+            Target::Tag { key, val } => Ec2Object::retrieve_by_tag_with(client, key, val).await?,
+            Target::Named(_) => settings.retrieve(client).await?
+        };
+        if instances.is_empty() {
+            return Err(format!("no instances match <{}>", self).into());
+        }
+        Ok(instances)
+    }
+
+    /// The one instance target matches.
+    ///     Errors if there are none or several
+    pub async fn resolve_one(&self, settings: &InstanceConfig) -> Result<Ec2Object, Box<dyn Error>> {
+        let mut instances = self.resolve(settings).await?;
+        if instances.len() > 1 {
+            let ids: Vec<&str> = instances.iter().map(|ec2| ec2.instance_id.as_str()).collect();
+            return Err(format!("<{}> matches several instances {:?}, use an instance id", self, ids).into());
+        }
+        Ok(instances.remove(0))
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Id(id) => write!(f, "{}", id),
+            Target::Tag { key, val } => write!(f, "{}={}", key, val),
+            Target::Named(name) => write!(f, "{}", name)
+        }
+    }
+}
+
+/// One side of a cp, a local path or target:path on an instance
+#[derive(Debug, PartialEq)]
+pub enum CopyPath {
+    Local(PathBuf),
+    Remote {
+        target: Target,
+        path: String
+    }
+}
+
+impl CopyPath {
+    /// target:path is remote when target parses, anything else is local,
+    /// including windows paths like C:\world
+    pub fn parse(arg: &str, config: &Config) -> CopyPath {
+        if let Some((target, path)) = arg.split_once(':') {
+            let is_path = target.len() <= 1 || target.contains('/') || target.contains('\\');
+            if !is_path {
+                if let Ok(target) = Target::parse(target, config) {
+                    return CopyPath::Remote {
+                        target,
+                        path: path.to_string()
+                    };
+                }
+            }
+        }
+        CopyPath::Local(PathBuf::from(arg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_targets() {
+        let config = Config::parse("[instances.survival]\ninstance_id = \"i-0123\"").unwrap();
+        assert_eq!(Target::parse("i-0123", &config).unwrap(), Target::Id("i-0123".to_string()));
+        assert_eq!(Target::parse("minecraft=survival", &config).unwrap(), Target::Tag {
+            key: "minecraft".to_string(),
+            val: "survival".to_string()
+        });
+        assert_eq!(Target::parse("survival", &config).unwrap(), Target::Named("survival".to_string()));
+        assert!(Target::parse("creative", &config).is_err());
+        assert!(Target::parse("=survival", &config).is_err());
+    }
+
+    #[test]
+    fn parse_copy_paths() {
+        let config = Config::parse("[instances.survival]").unwrap();
+        assert_eq!(CopyPath::parse("i-0123:/opt/minecraft/world.zip", &config), CopyPath::Remote {
+            target: Target::Id("i-0123".to_string()),
+            path: "/opt/minecraft/world.zip".to_string()
+        });
+        assert_eq!(CopyPath::parse("Name=survival:server.properties", &config), CopyPath::Remote {
+            target: Target::Tag { key: "Name".to_string(), val: "survival".to_string() },
+            path: "server.properties".to_string()
+        });
+        assert_eq!(CopyPath::parse("survival:/opt/minecraft/", &config), CopyPath::Remote {
+            target: Target::Named("survival".to_string()),
+            path: "/opt/minecraft/".to_string()
+        });
+        assert_eq!(CopyPath::parse("world.zip", &config), CopyPath::Local(PathBuf::from("world.zip")));
+        assert_eq!(CopyPath::parse("C:\\worlds\\world.zip", &config), CopyPath::Local(PathBuf::from("C:\\worlds\\world.zip")));
+        assert_eq!(CopyPath::parse("./i-0123:backup", &config), CopyPath::Local(PathBuf::from("./i-0123:backup")));
+    }
+}
@@ -0,0 +1,507 @@
+//! Settings for named instances loaded from toml, so code and the command line don't need
+//! regions, roles, images or keys spelled out. A project's rust_ec2.toml is layered over the
+//! user's ~/.config/rust_ec2/config.toml:
+//!
+//! ```toml
+//! [defaults]
+//! region = "us-east-2"
+//! credentials = { profile = "minecraft" }
+//! key_path = "~/.ssh/game.pem"
+//!
+//! [instances.minecraft]
+//! instance_type = "t3.medium"
+//! tags = { minecraft = "survival" }
+//! state = "running"
+//! volumes = [{ device = "/dev/sdf", size = 20 }]
+//! ```
+//!
+//! Instances double as the desired state planned and applied by virtual_machine::ec2::plan.
+//! Schedules start and stop instances at set times, see scheduler:
+//!
+//! ```toml
+//! [schedules.evenings]
+//! target = "minecraft"
+//! start = "0 18 * * mon-fri"
+//! stop = "0 23 * * *"
+//! timezone = "Europe/London"
+//! ```
+extern crate chrono_tz;
+extern crate dirs;
+extern crate serde;
+extern crate toml;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rusoto_core::Region;
+use rusoto_ec2::{Ec2Client, Tag};
+use self::chrono_tz::Tz;
+use self::serde::Deserialize;
+
+use crate::credentials::provider::{CredentialChain, CredentialSource};
+use crate::scheduler::Schedule;
+use crate::virtual_machine::ec2::instance::{Ec2Object, LaunchOptions};
+use crate::virtual_machine::ec2::plan::{DesiredInstance, DesiredState, DesiredVolume};
+use crate::virtual_machine::ec2::volume::VolumeOptions;
+
+/// Config file looked for in the current directory
+pub const PROJECT_FILE: &str = "rust_ec2.toml";
+/// Passphrase for vault credentials, read from the environment so it isn't in a file
+pub const VAULT_PASSPHRASE_VAR: &str = "RUST_EC2_VAULT_PASSPHRASE";
+
+/// Where an instance's credentials come from. Sources that are set are tried in the order
+/// csv, vault then profile
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub profile: Option<String>,
+    /// csv downloaded from the IAM console
+    pub csv: Option<PathBuf>,
+    pub vault: Option<PathBuf>,
+    /// name of the credential in vault, defaults to default
+    pub vault_entry: Option<String>
+}
+
+/// A volume an instance should have, see plan::DesiredVolume
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeConfig {
+    /// eg. /dev/sdf
+    pub device: String,
+    /// size in GiB
+    pub size: i64,
+    /// defaults to gp2
+    pub volume_type: Option<String>
+}
+
+/// Settings for one instance. Anything not set falls back to [defaults] then the library defaults
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// eg. us-east-2
+    pub region: Option<String>,
+    pub role_arn: Option<String>,
+    /// session name role_arn is assumed with, defaults to minecraft-session
+    pub session_name: Option<String>,
+    pub credentials: Option<CredentialsConfig>,
+    /// finds the instance by id instead of by tags
+    pub instance_id: Option<String>,
+    pub image_id: Option<String>,
+    pub instance_type: Option<String>,
+    /// key pair launched with
+    pub key_name: Option<String>,
+    /// launched with and used to find the instance when there's no instance_id
+    pub tags: Option<BTreeMap<String, String>>,
+    /// private key ssh connects with
+    pub key_path: Option<PathBuf>,
+    pub ssh_user: Option<String>,
+    /// running, stopped or terminated, the rest of these are only used by plans
+    pub state: Option<DesiredState>,
+    pub security_group_ids: Option<Vec<String>>,
+    pub volumes: Option<Vec<VolumeConfig>>,
+    /// allocation id of an elastic ip
+    pub elastic_ip: Option<String>
+}
+
+impl InstanceConfig {
+    /// self with every setting other has replacing self's
+    pub fn merge(self, other: InstanceConfig) -> InstanceConfig {
+        InstanceConfig {
+            region: other.region.or(self.region),
+            role_arn: other.role_arn.or(self.role_arn),
+            session_name: other.session_name.or(self.session_name),
+            credentials: other.credentials.or(self.credentials),
+            instance_id: other.instance_id.or(self.instance_id),
+            image_id: other.image_id.or(self.image_id),
+            instance_type: other.instance_type.or(self.instance_type),
+            key_name: other.key_name.or(self.key_name),
+            tags: other.tags.or(self.tags),
+            key_path: other.key_path.or(self.key_path),
+            ssh_user: other.ssh_user.or(self.ssh_user),
+            state: other.state.or(self.state),
+            security_group_ids: other.security_group_ids.or(self.security_group_ids),
+            volumes: other.volumes.or(self.volumes),
+            elastic_ip: other.elastic_ip.or(self.elastic_ip)
+        }
+    }
+
+    /// region, or the default region if not set.
+    ///     Errors if region isn't an aws region name
+    pub fn region(&self) -> Result<Region, Box<dyn Error>> {
+        match &self.region {
+            Some(region) => match Region::from_str(region) {
+                Ok(region) => Ok(region),
+                Err(_) => Err(format!("unknown region <{}>", region).into())
+            },
+            None => Ok(Ec2Object::default_region())
+        }
+    }
+
+    /// Sources in credentials, or the default chain if there are none.
+    ///     Errors if a vault is set but its passphrase isn't in VAULT_PASSPHRASE_VAR
+    pub fn credential_chain(&self) -> Result<CredentialChain, Box<dyn Error>> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(CredentialChain::default())
+        };
+        let mut sources = vec![];
+        if let Some(csv) = &credentials.csv {
+            sources.push(CredentialSource::ConsoleCsv(expand_home(csv)));
+        }
+        if let Some(vault) = &credentials.vault {
+            let passphrase = match env::var(VAULT_PASSPHRASE_VAR) {
+                Ok(passphrase) => passphrase,
+                Err(_) => return Err(format!("set {} to open the vault", VAULT_PASSPHRASE_VAR).into())
+            };
+            let name = credentials.vault_entry.as_deref().unwrap_or("default");
+            sources.push(CredentialSource::vault(expand_home(vault), name, &passphrase));
+        }
+        if let Some(profile) = &credentials.profile {
+            sources.push(CredentialSource::Profile(profile.clone()));
+        }
+        if sources.is_empty() {
+            return Ok(CredentialChain::default());
+        }
+        Ok(CredentialChain::new(sources))
+    }
+
+    /// ec2 client in region using the credential chain, assuming role_arn as session_name if set
+    pub fn ec2_client(&self) -> Result<Ec2Client, Box<dyn Error>> {
+        let chain = self.credential_chain()?;
+        Ok(Ec2Object::ec2_client_in(chain, self.role_arn.as_deref(), self.session_name.as_deref(), self.region()?))
+    }
+
+    /// tags, or the default tag if not set
+    pub fn ec2_tags(&self) -> Vec<Tag> {
+        match &self.tags {
+            Some(tags) => tags.iter()
+                .map(|(key, value)| Tag { key: Some(key.clone()), value: Some(value.clone()) })
+                .collect(),
+            None => vec![Ec2Object::default_tag()]
+        }
+    }
+
+    /// LaunchOptions with every launch setting that is set
+    pub fn launch_options(&self) -> LaunchOptions {
+        let mut options = LaunchOptions {
+            key_name: self.key_name.clone(),
+            security_group_ids: self.security_group_ids.clone().unwrap_or_default(),
+            tags: self.ec2_tags(),
+            ..Default::default()
+        };
+        if let Some(image_id) = &self.image_id {
+            options.image_id = image_id.clone();
+        }
+        if let Some(instance_type) = &self.instance_type {
+            options.instance_type = instance_type.clone();
+        }
+        options
+    }
+
+    /// This config as the desired state of the instance called name
+    pub fn desired(&self, name: &str) -> DesiredInstance {
+        DesiredInstance {
+            name: name.to_string(),
+            instance_id: self.instance_id.clone(),
+            state: self.state.unwrap_or_default(),
+            instance_type: self.instance_type.clone(),
+            image_id: self.image_id.clone(),
+            security_group_ids: self.security_group_ids.clone(),
+            tags: self.tags.as_ref().map(|_| self.ec2_tags()),
+            volumes: self.volumes.iter()
+                .flatten()
+                .map(|volume| {
+                    let mut options = VolumeOptions {
+                        size: volume.size,
+                        ..Default::default()
+                    };
+                    if let Some(volume_type) = &volume.volume_type {
+                        options.volume_type = volume_type.clone();
+                    }
+                    DesiredVolume {
+                        device: volume.device.clone(),
+                        options
+                    }
+                })
+                .collect(),
+            elastic_ip: self.elastic_ip.clone(),
+            launch: self.launch_options()
+        }
+    }
+
+    /// Instances this config describes, by instance_id or else every instance with all of its tags
+    pub async fn retrieve(&self, client: Ec2Client) -> Result<Vec<Ec2Object>, Box<dyn Error>> {
+        match &self.instance_id {
+            Some(instance_id) => Ok(Ec2Object::retrieve_with(client, instance_id).await?.into_iter().collect()),
+            None => Ec2Object::retrieve_by_tags_with(client, &self.ec2_tags()).await
+        }
+    }
+
+    /// key_path with ~ expanded
+    pub fn key_path(&self) -> Option<PathBuf> {
+        self.key_path.as_deref().map(expand_home)
+    }
+}
+
+/// When to start and stop the instances target matches, see scheduler::Schedule
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// instance id, key=value tag or instance name, as on the command line
+    pub target: String,
+    /// cron expressions, eg. 0 18 * * mon-fri
+    pub start: Option<String>,
+    pub stop: Option<String>,
+    /// eg. Europe/London, defaults to UTC
+    pub timezone: Option<String>
+}
+
+impl ScheduleConfig {
+    /// This config as the schedule called name.
+    ///     Errors if it has neither start nor stop, or they or timezone are invalid
+    pub fn schedule(&self, name: &str) -> Result<Schedule, Box<dyn Error>> {
+        if self.start.is_none() && self.stop.is_none() {
+            return Err(format!("schedule <{}> needs a start or a stop", name).into());
+        }
+        let cron = |expression: &Option<String>| match expression {
+            Some(expression) => match expression.parse() {
+                Ok(cron) => Ok(Some(cron)),
+                Err(e) => Err(format!("invalid cron in schedule <{}>: {}", name, e))
+            },
+            None => Ok(None)
+        };
+        let timezone = match &self.timezone {
+            Some(timezone) => match timezone.parse() {
+                Ok(timezone) => timezone,
+                Err(_) => return Err(format!("unknown time zone <{}> in schedule <{}>", timezone, name).into())
+            },
+            None => Tz::UTC
+        };
+        Ok(Schedule {
+            name: name.to_string(),
+            target: self.target.clone(),
+            start: cron(&self.start)?,
+            stop: cron(&self.stop)?,
+            timezone
+        })
+    }
+}
+
+/// Contents of one or more config files
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub defaults: InstanceConfig,
+    #[serde(default)]
+    pub instances: BTreeMap<String, InstanceConfig>,
+    #[serde(default)]
+    pub schedules: BTreeMap<String, ScheduleConfig>
+}
+
+impl Config {
+    pub fn parse(contents: &str) -> Result<Config, Box<dyn Error>> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Errors if path can't be read or isn't a valid config, saying which file it was
+    pub fn load_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        match Self::parse(&contents) {
+            Ok(config) => Ok(config),
+            Err(e) => Err(format!("invalid config <{}>: {}", path.display(), e).into())
+        }
+    }
+
+    /// The user config with the project config in the current directory over it.
+    /// Either file being missing is fine, both missing gives an empty config
+    pub fn load() -> Result<Config, Box<dyn Error>> {
+        let mut config = Config::default();
+        let paths = Self::user_path().into_iter().chain(Some(PathBuf::from(PROJECT_FILE)));
+        for path in paths {
+            if path.exists() {
+                config = config.merge(Self::load_file(&path)?);
+            }
+        }
+        Ok(config)
+    }
+
+    /// ~/.config/rust_ec2/config.toml on linux, see dirs::config_dir for other platforms
+    pub fn user_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rust_ec2").join("config.toml"))
+    }
+
+    /// self with other's defaults and instances layered over it, setting by setting.
+    /// Schedules are replaced whole
+    pub fn merge(mut self, other: Config) -> Config {
+        self.schedules.extend(other.schedules);
+        self.defaults = self.defaults.merge(other.defaults);
+        for (name, instance) in other.instances {
+            let merged = match self.instances.remove(&name) {
+                Some(existing) => existing.merge(instance),
+                None => instance
+            };
+            self.instances.insert(name, merged);
+        }
+        self
+    }
+
+    /// defaults with the named instance's settings over them, None if there's no such instance
+    pub fn instance(&self, name: &str) -> Option<InstanceConfig> {
+        let instance = self.instances.get(name)?;
+        Some(self.defaults.clone().merge(instance.clone()))
+    }
+
+    /// Every schedule, in name order.
+    ///     Errors if any is invalid
+    pub fn schedules(&self) -> Result<Vec<Schedule>, Box<dyn Error>> {
+        self.schedules.iter()
+            .map(|(name, schedule)| schedule.schedule(name))
+            .collect()
+    }
+}
+
+/// path with a leading ~ replaced by the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = r#"
+        [defaults]
+        region = "us-east-2"
+        credentials = { profile = "personal" }
+        ssh_user = "ubuntu"
+        session_name = "home-laptop"
+
+        [instances.minecraft]
+        instance_type = "t2.micro"
+        key_path = "~/.ssh/game.pem"
+    "#;
+
+    const PROJECT: &str = r#"
+        [defaults]
+        credentials = { profile = "minecraft" }
+
+        [instances.minecraft]
+        instance_type = "t3.medium"
+        tags = { minecraft = "survival", Name = "survival" }
+
+        [instances.creative]
+        instance_id = "i-0123"
+        region = "eu-west-2"
+    "#;
+
+    #[test]
+    fn project_over_user() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(USER)?.merge(Config::parse(PROJECT)?);
+
+        let minecraft = config.instance("minecraft").unwrap();
+        assert_eq!(minecraft.instance_type, Some("t3.medium".to_string()));
+        assert_eq!(minecraft.ssh_user, Some("ubuntu".to_string()));
+        assert_eq!(minecraft.session_name, Some("home-laptop".to_string()));
+        assert_eq!(minecraft.credentials.unwrap().profile, Some("minecraft".to_string()));
+        assert!(minecraft.key_path.is_some());
+
+        let creative = config.instance("creative").unwrap();
+        assert_eq!(creative.region()?, Region::EuWest2);
+        assert!(config.instance("missing").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn launch_options_from_config() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(PROJECT)?;
+        let options = config.instance("minecraft").unwrap().launch_options();
+        assert_eq!(options.instance_type, "t3.medium");
+        assert_eq!(options.image_id, LaunchOptions::default().image_id);
+        assert_eq!(options.tags, vec![
+            Tag { key: Some("Name".to_string()), value: Some("survival".to_string()) },
+            Tag { key: Some("minecraft".to_string()), value: Some("survival".to_string()) }
+        ]);
+        //no tags configured falls back to the default tag
+        assert_eq!(config.instance("creative").unwrap().ec2_tags(), vec![Ec2Object::default_tag()]);
+        Ok(())
+    }
+
+    #[test]
+    fn desired_state_from_config() -> Result<(), Box<dyn Error>> {
+        let config = Config::parse(r#"
+            [instances.minecraft]
+            state = "stopped"
+            security_group_ids = ["sg-0123"]
+            volumes = [{ device = "/dev/sdf", size = 20, volume_type = "gp3" }]
+        "#)?;
+        let desired = config.instance("minecraft").unwrap().desired("minecraft");
+        assert_eq!(desired.state, DesiredState::Stopped);
+        assert_eq!(desired.image_id, None);
+        assert_eq!(desired.volumes[0].options.size, 20);
+        assert_eq!(desired.volumes[0].options.volume_type, "gp3");
+        assert_eq!(desired.launch.security_group_ids, vec!["sg-0123"]);
+
+        assert!(Config::parse("[instances.minecraft]\nstate = \"paused\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn schedules_from_config() -> Result<(), Box<dyn Error>> {
+        let user = Config::parse(r#"
+            [schedules.evenings]
+            target = "minecraft"
+            stop = "0 22 * * *"
+
+            [schedules.weekends]
+            target = "minecraft=creative"
+            start = "0 10 * * sat,sun"
+        "#)?;
+        let project = Config::parse(r#"
+            [schedules.evenings]
+            target = "minecraft"
+            start = "0 18 * * mon-fri"
+            stop = "0 23 * * *"
+            timezone = "Europe/London"
+        "#)?;
+        let schedules = user.merge(project).schedules()?;
+        assert_eq!(schedules.len(), 2);
+        assert_eq!(schedules[0].name, "evenings");
+        assert_eq!(schedules[0].stop.as_ref().unwrap().to_string(), "0 23 * * *");
+        assert_eq!(schedules[0].timezone, chrono_tz::Europe::London);
+        assert_eq!(schedules[1].timezone, Tz::UTC);
+        assert!(schedules[1].stop.is_none());
+
+        for invalid in ["target = \"minecraft\"", "target = \"minecraft\"\nstart = \"0 25 * * *\"",
+                        "target = \"minecraft\"\nstart = \"0 18 * * *\"\ntimezone = \"Mars/Olympus\""] {
+            assert!(Config::parse(&format!("[schedules.evenings]\n{}", invalid))?.schedules().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(Config::parse("[instances.minecraft]\ninstance_typo = \"t3.medium\"").is_err());
+        let config = Config::parse("[defaults]\nregion = \"mars-north-1\"").unwrap();
+        assert!(config.defaults.region().is_err());
+    }
+
+    #[test]
+    fn home_expansion() {
+        let config = InstanceConfig {
+            key_path: Some(PathBuf::from("~/.ssh/game.pem")),
+            ..Default::default()
+        };
+        if let Some(home) = dirs::home_dir() {
+            assert_eq!(config.key_path(), Some(home.join(".ssh/game.pem")));
+        }
+        assert_eq!(expand_home(Path::new("game.pem")), PathBuf::from("game.pem"));
+    }
+}
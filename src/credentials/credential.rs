@@ -1,54 +1,241 @@
+extern crate chrono;
 extern crate csv;
+extern crate rusoto_credential;
 
 use std::fs::File;
 use std::error::Error;
+use std::io::{Read, Write};
+use self::chrono::{DateTime, Duration, Utc};
 use self::csv::StringRecord;
+use self::rusoto_credential::{AwsCredentials, StaticProvider};
 
-/// In order array of headers in credentials file
-const HEADERS: &'static [&'static str] = &["User name","Password","Access key ID","Secret access key","Console login link"];
+/// Columns read from credential csvs, matched by name so they can be in any order
+const USER_NAME: &str = "User name";
+const ACCESS_KEY_ID: &str = "Access key ID";
+const SECRET_ACCESS_KEY: &str = "Secret access key";
+const SESSION_TOKEN: &str = "Session token";
+const EXPIRATION: &str = "Expiration";
+
+/// In order array of headers in the access key file downloaded when creating an access key
+const ACCESS_KEY_HEADERS: &[&str] = &[ACCESS_KEY_ID, SECRET_ACCESS_KEY];
+/// ACCESS_KEY_HEADERS followed by the columns of a temporary credential
+const TEMPORARY_KEY_HEADERS: &[&str] = &[ACCESS_KEY_ID, SECRET_ACCESS_KEY, SESSION_TOKEN, EXPIRATION];
+
+/// How long before its expiration a temporary credential counts as expired,
+/// so it isn't handed to a request that outlives it
+const EXPIRY_MARGIN_SECS: i64 = 60;
 
 /// One single credential needed to access AWS
+#[derive(Clone)]
 pub struct Credential {
+    /// IAM user the credential belongs to, if the source says
+    pub user_name: Option<String>,
     pub access_key_id: String,
-    pub secret_access_key: String
+    pub secret_access_key: String,
+    /// only set for temporary credentials, eg. from sso or an assumed role
+    pub session_token: Option<String>,
+    /// None if the credential doesn't expire
+    pub expiration: Option<DateTime<Utc>>
+}
+
+/// Position of each known column in the header row of a credential csv
+struct Columns {
+    user_name: Option<usize>,
+    access_key_id: usize,
+    secret_access_key: usize,
+    session_token: Option<usize>,
+    expiration: Option<usize>
+}
+
+impl Columns {
+    /// Ignores case, surrounding whitespace, the byte order mark the console starts its
+    /// csvs with and any columns that aren't used.
+    ///     Errors if the access key id or secret access key column is missing
+    fn from_headers(headers: &StringRecord) -> Result<Columns, Box<dyn Error>> {
+        let find = |key: &str| headers.iter()
+            .position(|header| header.trim_start_matches('\u{feff}').trim().eq_ignore_ascii_case(key));
+        let require = |key: &str| match find(key) {
+            Some(pos) => Ok(pos),
+            None => Err(format!("could not find: <{}> in <{:?}>", key, headers.iter().collect::<Vec<&str>>()))
+        };
+        Ok(Columns {
+            user_name: find(USER_NAME),
+            access_key_id: require(ACCESS_KEY_ID)?,
+            secret_access_key: require(SECRET_ACCESS_KEY)?,
+            session_token: find(SESSION_TOKEN),
+            expiration: find(EXPIRATION)
+        })
+    }
 }
 
 /// Gets a credential from a StringRecord previously retrieved from a credential.csv
-/// Must have all values included in HEADERS present otherwise will be considered malformed
-fn cred_from_str_rec(rec: &StringRecord) -> Result<Credential, Box<dyn Error>> {
-    assert_eq!(rec.len(), HEADERS.len());
+/// Must have a value for the access key id and secret access key otherwise will be considered malformed
+fn cred_from_str_rec(rec: &StringRecord, columns: &Columns) -> Result<Credential, Box<dyn Error>> {
+    let line = rec.position().map(|pos| pos.line()).unwrap_or_default();
+    let require = |pos: usize, key: &str| match rec.get(pos).filter(|val| !val.is_empty()) {
+        Some(val) => Ok(val.to_string()),
+        None => Err(format!("line {} has no <{}>", line, key))
+    };
+    //optional columns are empty for long term keys
+    let optional = |pos: Option<usize>| pos
+        .and_then(|pos| rec.get(pos))
+        .filter(|val| !val.is_empty())
+        .map(str::to_string);
+
+    let expiration = match optional(columns.expiration) {
+        Some(time) => Some(DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc)),
+        None => None
+    };
+    Ok(Credential {
+        user_name: optional(columns.user_name),
+        access_key_id: require(columns.access_key_id, ACCESS_KEY_ID)?,
+        secret_access_key: require(columns.secret_access_key, SECRET_ACCESS_KEY)?,
+        session_token: optional(columns.session_token),
+        expiration
+    })
+}
 
-    let rec_vec: Vec<&str> = rec.into_iter().collect();
+/// Every credential in a credential csv, in file order.
+///     Errors if the csv is malformed or has no credentials
+fn all_from_reader(csv: impl Read) -> Result<Vec<Credential>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(csv);
+    let columns = Columns::from_headers(reader.headers()?)?;
 
-    let find_val_pos = |key: &str| -> usize {
-        match HEADERS.iter().position(|header| header == &key) {
-            Some(val) => val,
-            None => panic!(format!("could not find: <{}> in <{:?}>", key, HEADERS))
-        }
+    let mut creds = vec![];
+    for result in reader.records() {
+        creds.push(cred_from_str_rec(&result?, &columns)?);
+    }
+    if creds.is_empty() {
+        return Err("Expected value in csv, found none!".into());
+    }
+    Ok(creds)
+}
+
+/// contents of a credential csv with cred in place of the key in its row, keeping every column
+/// and the other rows as they were. The row is the one of cred's user, or the only row if the
+/// csv has no user names.
+///     Errors if there's no row for cred, or cred is temporary and the csv can't hold its token
+pub(crate) fn update_csv(contents: &str, cred: &Credential) -> Result<String, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader.headers()?.clone();
+    let columns = Columns::from_headers(&headers)?;
+    let mut records = reader.records().collect::<Result<Vec<StringRecord>, csv::Error>>()?;
+
+    let row = match (columns.user_name, cred.user_name.as_deref()) {
+        (Some(pos), Some(user_name)) => records.iter().position(|rec| rec.get(pos) == Some(user_name)),
+        _ if records.len() == 1 => Some(0),
+        _ => None
     };
-    let cred = Credential{
-        access_key_id: rec_vec[find_val_pos(&"Access key ID")].to_string(),
-        secret_access_key: rec_vec[find_val_pos(&"Secret access key")].to_string()
+    let row = match row {
+        Some(row) => row,
+        None => return Err(format!("no row in csv for <{}>", cred.user_name.as_deref().unwrap_or(&cred.access_key_id)).into())
     };
-    Ok(cred)
+    if (cred.session_token.is_some() && columns.session_token.is_none())
+        || (cred.expiration.is_some() && columns.expiration.is_none()) {
+        return Err(format!("csv has no <{}> or <{}> column for a temporary credential", SESSION_TOKEN, EXPIRATION).into());
+    }
+
+    let expiration = cred.expiration.map(|time| time.to_rfc3339()).unwrap_or_default();
+    let mut updated: Vec<String> = records[row].iter().map(str::to_string).collect();
+    updated.resize(headers.len(), String::new());
+    updated[columns.access_key_id] = cred.access_key_id.clone();
+    updated[columns.secret_access_key] = cred.secret_access_key.clone();
+    if let Some(pos) = columns.session_token {
+        updated[pos] = cred.session_token.clone().unwrap_or_default();
+    }
+    if let Some(pos) = columns.expiration {
+        updated[pos] = expiration;
+    }
+    records[row] = StringRecord::from(updated);
+
+    //the reader drops the byte order mark the console starts its csvs with
+    let bom = if contents.starts_with('\u{feff}') { "\u{feff}" } else { "" };
+    let mut writer = csv::Writer::from_writer(bom.as_bytes().to_vec());
+    writer.write_record(&headers)?;
+    for rec in &records {
+        writer.write_record(rec)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
 }
 
 impl Credential {
     pub fn new(key_id: &str, secret_access_key: &str) -> Credential {
-        return Credential {
+        Credential {
+            user_name: None,
             access_key_id:key_id.to_string(),
-            secret_access_key:secret_access_key.to_string()
+            secret_access_key:secret_access_key.to_string(),
+            session_token: None,
+            expiration: None
+        }
+    }
+    /// Temporary credential, eg. one issued by sso
+    pub fn new_temporary(key_id: &str, secret_access_key: &str, session_token: &str, expiration: DateTime<Utc>) -> Credential {
+        Credential {
+            session_token: Some(session_token.to_string()),
+            expiration: Some(expiration),
+            ..Self::new(key_id, secret_access_key)
         }
     }
+    /// true if the credential expires within EXPIRY_MARGIN_SECS of now
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+    /// true if the credential expires within EXPIRY_MARGIN_SECS of now
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        match self.expiration {
+            Some(expiration) => expiration <= now + Duration::seconds(EXPIRY_MARGIN_SECS),
+            None => false
+        }
+    }
+    /// First credential in a csv downloaded from the IAM console, see all_with_csv
     pub fn new_with_csv(csv: &File) -> Result<Credential, Box<dyn Error>> {
-        let mut reader = csv::Reader::from_reader(csv);
-
-        for result in reader.records() { //returns first
-            let record = result?;
-            let cred = cred_from_str_rec(&record);
-            return cred;
+        Ok(all_from_reader(csv)?.remove(0))
+    }
+    /// Every credential in a csv downloaded from the IAM console, eg. when creating several users.
+    ///     Errors if a column is missing or the csv has no credentials
+    pub fn all_with_csv(csv: &File) -> Result<Vec<Credential>, Box<dyn Error>> {
+        all_from_reader(csv)
+    }
+    /// Credential of user_name in a csv downloaded from the IAM console.
+    ///     Errors if the csv has no credential for user_name
+    pub fn new_with_csv_for_user(csv: &File, user_name: &str) -> Result<Credential, Box<dyn Error>> {
+        match all_from_reader(csv)?.into_iter().find(|cred| cred.user_name.as_deref() == Some(user_name)) {
+            Some(cred) => Ok(cred),
+            None => Err(format!("no credential for user <{}> in csv", user_name).into())
         }
-        panic!("Expected value in csv, found none!");
+    }
+    /// Credentials for a single request, see to_provider for a client
+    pub fn to_aws_credentials(&self) -> AwsCredentials {
+        AwsCredentials::new(self.access_key_id.clone(), self.secret_access_key.clone(), self.session_token.clone(), self.expiration)
+    }
+    /// Provider that always gives this credential.
+    /// Each client gets its own so clients in one process can use different accounts
+    pub fn to_provider(&self) -> StaticProvider {
+        StaticProvider::from(self.to_aws_credentials())
+    }
+    /// Reads the 2 column csv downloaded when creating an access key (Access key ID, Secret access key),
+    /// or the 4 column one written by write_access_key_csv for a temporary credential
+    pub fn new_with_access_key_csv(csv: &File) -> Result<Credential, Box<dyn Error>> {
+        Self::new_with_csv(csv)
+    }
+    /// Writes the credential in the format new_with_access_key_csv reads.
+    /// Temporary credentials get the session token and expiration columns
+    pub fn write_access_key_csv(&self, out: impl Write) -> Result<(), Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(out);
+        if self.session_token.is_none() && self.expiration.is_none() {
+            writer.write_record(ACCESS_KEY_HEADERS)?;
+            writer.write_record([&self.access_key_id, &self.secret_access_key])?;
+        } else {
+            writer.write_record(TEMPORARY_KEY_HEADERS)?;
+            writer.write_record([
+                self.access_key_id.clone(),
+                self.secret_access_key.clone(),
+                self.session_token.clone().unwrap_or_default(),
+                self.expiration.map(|time| time.to_rfc3339()).unwrap_or_default()
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
     }
 }
 
@@ -65,6 +252,19 @@ mod tests{
         assert_eq!(cred.access_key_id, ID);
     }
     #[test]
+    fn separate_providers() {
+        use self::rusoto_credential::ProvideAwsCredentials;
+
+        let first = Credential::new("first-id", "first-key").to_provider();
+        let second = Credential::new("second-id", "second-key").to_provider();
+
+        let first = tokio_test::block_on(first.credentials()).unwrap();
+        let second = tokio_test::block_on(second.credentials()).unwrap();
+        assert_eq!(first.aws_access_key_id(), "first-id");
+        assert_eq!(second.aws_access_key_id(), "second-id");
+        assert_eq!(second.aws_secret_access_key(), "second-key");
+    }
+    #[test]
     fn correct_val_new_with_csv() -> Result<(), Box<dyn Error>> {
         let file = File::open("data/test_user_credentials_valid")?;
         let cred = Credential::new_with_csv(&file)?;
@@ -73,6 +273,103 @@ mod tests{
         Ok(())
     }
 
+    #[test]
+    fn correct_val_new_with_access_key_csv() -> Result<(), Box<dyn Error>> {
+        let file = File::open("data/test_access_key_valid")?;
+        let cred = Credential::new_with_access_key_csv(&file)?;
+        assert_eq!(cred.access_key_id, "id");
+        assert_eq!(cred.secret_access_key, "key");
+        Ok(())
+    }
+
+    #[test]
+    fn temporary_csv_round_trip() -> Result<(), Box<dyn Error>> {
+        use self::chrono::TimeZone;
+
+        let expiration = Utc.with_ymd_and_hms(2020, 10, 18, 8, 0, 0).unwrap();
+        let cred = Credential::new_temporary("id", "key", "token", expiration);
+        let path = std::env::temp_dir().join(format!("rust_ec2_temporary_{}.csv", std::process::id()));
+        cred.write_access_key_csv(File::create(&path)?)?;
+
+        let read = Credential::new_with_access_key_csv(&File::open(&path)?);
+        std::fs::remove_file(&path)?;
+        let read = read?;
+        assert_eq!(read.access_key_id, "id");
+        assert_eq!(read.session_token, Some("token".to_string()));
+        assert_eq!(read.expiration, Some(expiration));
+        Ok(())
+    }
+
+    #[test]
+    fn expiry() {
+        use self::chrono::TimeZone;
+
+        let now = Utc.with_ymd_and_hms(2020, 10, 18, 8, 0, 0).unwrap();
+        let cred = Credential::new_temporary("id", "key", "token", now + Duration::minutes(30));
+        assert!(!cred.is_expired_at(now));
+        assert!(cred.is_expired_at(now + Duration::minutes(30)));
+        //within the margin
+        assert!(cred.is_expired_at(now + Duration::minutes(30) - Duration::seconds(EXPIRY_MARGIN_SECS - 1)));
+        assert!(!Credential::new("id", "key").is_expired_at(now + Duration::weeks(52)));
+    }
+
+    #[test]
+    fn reordered_and_extra_columns() -> Result<(), Box<dyn Error>> {
+        let csv = "\u{feff}Secret access key,Console login link,access key id,User name,Notes\nkey,https://console.aws.amazon.com,id,user,extra\n";
+        let creds = all_from_reader(csv.as_bytes())?;
+        assert_eq!(creds.len(), 1);
+        assert_eq!(creds[0].user_name, Some("user".to_string()));
+        assert_eq!(creds[0].access_key_id, "id");
+        assert_eq!(creds[0].secret_access_key, "key");
+        Ok(())
+    }
+
+    #[test]
+    fn several_users() -> Result<(), Box<dyn Error>> {
+        let csv = "User name,Access key ID,Secret access key\nalice,alice-id,alice-key\nbob,bob-id,bob-key\n";
+        let creds = all_from_reader(csv.as_bytes())?;
+        let names: Vec<Option<&str>> = creds.iter().map(|cred| cred.user_name.as_deref()).collect();
+        assert_eq!(names, vec![Some("alice"), Some("bob")]);
+        assert_eq!(creds[1].access_key_id, "bob-id");
+
+        let cred = Credential::new_with_csv_for_user(&File::open("data/test_user_credentials_valid")?, "user")?;
+        assert_eq!(cred.access_key_id, "id");
+        assert!(Credential::new_with_csv_for_user(&File::open("data/test_user_credentials_valid")?, "nobody").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn update_console_csv() -> Result<(), Box<dyn Error>> {
+        let csv = "\u{feff}User name,Password,Access key ID,Secret access key,Console login link\n\
+            alice,,alice-id,alice-key,https://alice.signin.aws.amazon.com/console\n\
+            bob,,bob-id,bob-key,https://bob.signin.aws.amazon.com/console\n";
+        let mut cred = Credential::new("new-id", "new-key");
+        cred.user_name = Some("bob".to_string());
+
+        let updated = update_csv(csv, &cred)?;
+        assert_eq!(updated, "\u{feff}User name,Password,Access key ID,Secret access key,Console login link\n\
+            alice,,alice-id,alice-key,https://alice.signin.aws.amazon.com/console\n\
+            bob,,new-id,new-key,https://bob.signin.aws.amazon.com/console\n");
+
+        cred.user_name = Some("carol".to_string());
+        assert!(update_csv(csv, &cred).is_err());
+        //a 2 column access key csv has one row and no user names
+        assert_eq!(update_csv("Access key ID,Secret access key\nid,key\n", &cred)?, "Access key ID,Secret access key\nnew-id,new-key\n");
+        Ok(())
+    }
+
+    #[test]
+    fn missing_column_and_empty_errors() {
+        let missing = all_from_reader("User name,Access key ID\nuser,id\n".as_bytes());
+        assert!(missing.err().unwrap().to_string().contains(SECRET_ACCESS_KEY));
+
+        let empty_value = all_from_reader("Access key ID,Secret access key\nid,\n".as_bytes());
+        assert!(empty_value.is_err());
+
+        assert!(all_from_reader("Access key ID,Secret access key\n".as_bytes()).is_err());
+        assert!(all_from_reader("".as_bytes()).is_err());
+    }
+
     #[test]
     fn error_new_with_csv() -> Result<(), Box<dyn Error>> {
         let file = File::open("data/test_user_credentials_invalid")?;
@@ -0,0 +1,130 @@
+extern crate chrono;
+
+use std::error::Error;
+
+use rusoto_core::{HttpClient, Region};
+use rusoto_sts::{Sts, StsClient};
+use rusoto_sts::{AssumeRoleRequest, GetCallerIdentityRequest};
+use self::chrono::{DateTime, Utc};
+
+use crate::credentials::credential::Credential;
+
+/// Who AWS thinks is making requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallerIdentity {
+    pub account: String,
+    /// eg. arn:aws:iam::123456789012:user/minecraft
+    pub arn: String,
+    pub user_id: String
+}
+
+/// sts client that calls sts as cred. sts is global, us-east-1 is its endpoint
+pub fn sts_client(cred: &Credential) -> Result<StsClient, Box<dyn Error>> {
+    Ok(StsClient::new_with(HttpClient::new()?, cred.to_provider(), Region::UsEast1))
+}
+
+/// Identity of whoever sts is called as.
+///     Errors if sts rejects the credentials, eg. a deleted or expired key
+pub async fn caller_identity(sts: &StsClient) -> Result<CallerIdentity, Box<dyn Error>> {
+    let identity = match sts.get_caller_identity(GetCallerIdentityRequest {}).await {
+        Ok(identity) => identity,
+        Err(e) => return Err(format!("sts rejected the credentials: {}", e).into())
+    };
+    match (identity.account, identity.arn, identity.user_id) {
+        (Some(account), Some(arn), Some(user_id)) => Ok(CallerIdentity { account, arn, user_id }),
+        _ => Err("sts returned an incomplete caller identity".into())
+    }
+}
+
+/// Assumes role_arn as whoever sts is called as, returning the role's temporary credential.
+///     Errors saying so if assuming the role isn't permitted
+pub async fn assume_role(sts: &StsClient, role_arn: &str, session_name: &str) -> Result<Credential, Box<dyn Error>> {
+    let assume_req = AssumeRoleRequest {
+        role_arn: role_arn.to_string(),
+        role_session_name: session_name.to_string(),
+        ..Default::default()
+    };
+    let assumed = match sts.assume_role(assume_req).await {
+        Ok(assumed) => assumed,
+        Err(e) => return Err(format!("not permitted to assume role <{}>: {}", role_arn, e).into())
+    };
+    match assumed.credentials {
+        Some(creds) => {
+            let expiration = DateTime::parse_from_rfc3339(&creds.expiration)?.with_timezone(&Utc);
+            Ok(Credential::new_temporary(&creds.access_key_id, &creds.secret_access_key, &creds.session_token, expiration))
+        },
+        None => Err(format!("assuming role <{}> returned no credentials", role_arn).into())
+    }
+}
+
+impl Credential {
+    /// Checks the credential works by asking sts who it belongs to
+    pub async fn validate(&self) -> Result<CallerIdentity, Box<dyn Error>> {
+        caller_identity(&sts_client(self)?).await
+    }
+
+    /// Checks the credential is permitted to assume role_arn, returning the assumed role's identity
+    pub async fn validate_role(&self, role_arn: &str, session_name: &str) -> Result<CallerIdentity, Box<dyn Error>> {
+        let assumed = assume_role(&sts_client(self)?, role_arn, session_name).await?;
+        assumed.validate().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+
+    /// rusoto skips the element after a result expecting it to be this
+    const METADATA: &str = "<ResponseMetadata><RequestId>0123</RequestId></ResponseMetadata>";
+
+    fn mock_sts(status: u16, body: &str) -> StsClient {
+        let dispatcher = MockRequestDispatcher::with_status(status).with_body(body);
+        StsClient::new_with(dispatcher, MockCredentialsProvider, Region::UsEast1)
+    }
+
+    #[test]
+    fn identity() -> Result<(), Box<dyn Error>> {
+        let body = format!("<GetCallerIdentityResponse><GetCallerIdentityResult>\
+            <Arn>arn:aws:iam::123456789012:user/minecraft</Arn><UserId>AIDA0123</UserId>\
+            <Account>123456789012</Account></GetCallerIdentityResult>{}</GetCallerIdentityResponse>", METADATA);
+        let identity = tokio_test::block_on(caller_identity(&mock_sts(200, &body)))?;
+        assert_eq!(identity, CallerIdentity {
+            account: "123456789012".to_string(),
+            arn: "arn:aws:iam::123456789012:user/minecraft".to_string(),
+            user_id: "AIDA0123".to_string()
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_credentials() {
+        let body = "<ErrorResponse><Error><Type>Sender</Type><Code>InvalidClientTokenId</Code>\
+            <Message>The security token included in the request is invalid.</Message></Error></ErrorResponse>";
+        let error = tokio_test::block_on(caller_identity(&mock_sts(403, body))).err().unwrap();
+        assert!(error.to_string().contains("sts rejected the credentials"));
+    }
+
+    #[test]
+    fn assumed_role_credential() -> Result<(), Box<dyn Error>> {
+        let body = format!("<AssumeRoleResponse><AssumeRoleResult><Credentials>\
+            <AccessKeyId>ASIA0123</AccessKeyId><SecretAccessKey>secret</SecretAccessKey>\
+            <SessionToken>token</SessionToken><Expiration>2020-10-18T09:00:00Z</Expiration>\
+            </Credentials><AssumedRoleUser><Arn>arn:aws:sts::123456789012:assumed-role/minecraft/session</Arn>\
+            <AssumedRoleId>AROA0123:session</AssumedRoleId></AssumedRoleUser></AssumeRoleResult>{}</AssumeRoleResponse>", METADATA);
+        let cred = tokio_test::block_on(assume_role(&mock_sts(200, &body), "arn:aws:iam::123456789012:role/minecraft", "session"))?;
+        assert_eq!(cred.access_key_id, "ASIA0123");
+        assert_eq!(cred.session_token, Some("token".to_string()));
+        assert!(cred.expiration.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn role_not_permitted() {
+        let body = "<ErrorResponse><Error><Type>Sender</Type><Code>AccessDenied</Code>\
+            <Message>not authorized to perform: sts:AssumeRole</Message></Error></ErrorResponse>";
+        let error = tokio_test::block_on(assume_role(&mock_sts(403, body), "arn:aws:iam::123456789012:role/minecraft", "session"))
+            .err().unwrap();
+        assert!(error.to_string().contains("not permitted to assume role <arn:aws:iam::123456789012:role/minecraft>"));
+    }
+}
@@ -1,2 +1,5 @@
-pub mod set_env;
-pub mod credential;
\ No newline at end of file
+pub mod credential;
+pub mod identity;
+pub mod provider;
+pub mod rotation;
+pub mod vault;
\ No newline at end of file
@@ -0,0 +1,449 @@
+extern crate chrono;
+extern crate dirs;
+extern crate rusoto_credential;
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use self::chrono::{DateTime, Utc};
+use rusoto_credential::{AwsCredentials, AutoRefreshingProvider, CredentialsError, ProvideAwsCredentials};
+
+use crate::credentials::credential::Credential;
+use crate::credentials::vault::Vault;
+
+const ACCESS_KEY_ID: &str = "AWS_ACCESS_KEY_ID";
+const SECRET_ACCESS_KEY: &str = "AWS_SECRET_ACCESS_KEY";
+const SESSION_TOKEN: &str = "AWS_SESSION_TOKEN";
+/// rfc3339, read by rusoto's EnvironmentProvider too
+const CREDENTIAL_EXPIRATION: &str = "AWS_CREDENTIAL_EXPIRATION";
+const PROFILE: &str = "AWS_PROFILE";
+const DEFAULT_PROFILE: &str = "default";
+
+/// Somewhere a Credential can be loaded from
+#[derive(Clone)]
+pub enum CredentialSource {
+    /// 5 column csv downloaded from the IAM console when creating a user
+    ConsoleCsv(PathBuf),
+    /// 2 column csv downloaded from the IAM console when creating an access key
+    AccessKeyCsv(PathBuf),
+    /// named profile in ~/.aws/credentials, falling back to ~/.aws/config
+    Profile(String),
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, with AWS_SESSION_TOKEN and
+    /// AWS_CREDENTIAL_EXPIRATION for temporary credentials
+    Environment,
+    /// credential stored as name in an encrypted vault, see vault::Vault.
+    /// Build with CredentialSource::vault, which decrypts it once
+    Vault {
+        path: PathBuf,
+        name: String,
+        /// the decrypted credential, or why it couldn't be decrypted
+        credential: Result<Credential, String>
+    },
+    Static(Credential)
+}
+
+impl CredentialSource {
+    /// Profile named by AWS_PROFILE, or the default profile
+    pub fn default_profile() -> CredentialSource {
+        CredentialSource::Profile(env::var(PROFILE).unwrap_or_else(|_| DEFAULT_PROFILE.to_string()))
+    }
+
+    /// Opens the vault at path and keeps the credential called name, so the slow key derivation
+    /// happens when the source is built rather than on every load. A vault that can't be opened
+    /// fails on load, like any other source
+    pub fn vault(path: PathBuf, name: &str, passphrase: &str) -> CredentialSource {
+        let credential = match Vault::open(&path, passphrase) {
+            Ok(vault) => match vault.get(name) {
+                Some(cred) => Ok(cred.clone()),
+                None => Err(format!("vault has no credential named <{}>", name))
+            },
+            Err(e) => Err(e.to_string())
+        };
+        CredentialSource::Vault {
+            path,
+            name: name.to_string(),
+            credential
+        }
+    }
+
+    pub fn load(&self) -> Result<Credential, Box<dyn Error>> {
+        match self {
+            CredentialSource::ConsoleCsv(path) => Credential::new_with_csv(&File::open(path)?),
+            CredentialSource::AccessKeyCsv(path) => Credential::new_with_access_key_csv(&File::open(path)?),
+            CredentialSource::Profile(profile) => load_profile(profile),
+            CredentialSource::Environment => from_vars(|name| env::var(name).ok()),
+            CredentialSource::Vault { credential, .. } => Ok(credential.clone()?),
+            CredentialSource::Static(cred) => Ok(cred.clone())
+        }
+    }
+
+    /// short description for error messages, never includes secrets
+    fn describe(&self) -> String {
+        match self {
+            CredentialSource::ConsoleCsv(path) => format!("console csv <{}>", path.display()),
+            CredentialSource::AccessKeyCsv(path) => format!("access key csv <{}>", path.display()),
+            CredentialSource::Profile(profile) => format!("profile <{}>", profile),
+            CredentialSource::Environment => "environment".to_string(),
+            CredentialSource::Vault { path, name, .. } => format!("vault <{}> credential <{}>", path.display(), name),
+            CredentialSource::Static(_) => "static credential".to_string()
+        }
+    }
+}
+
+/// Tries each source in order and uses the first one that loads.
+/// Can be given straight to a rusoto client as its credentials provider
+#[derive(Clone)]
+pub struct CredentialChain {
+    pub sources: Vec<CredentialSource>
+}
+
+impl Default for CredentialChain {
+    /// environment then the AWS_PROFILE or default profile, like the aws cli
+    fn default() -> Self {
+        CredentialChain {
+            sources: vec![CredentialSource::Environment, CredentialSource::default_profile()]
+        }
+    }
+}
+
+impl CredentialChain {
+    pub fn new(sources: Vec<CredentialSource>) -> CredentialChain {
+        CredentialChain {
+            sources
+        }
+    }
+
+    /// Loads from the first source that works. Expired credentials count as not working.
+    ///     Errors with the reason every source failed if none work
+    pub fn load(&self) -> Result<Credential, Box<dyn Error>> {
+        let mut failures = vec![];
+        for source in &self.sources {
+            match source.load() {
+                Ok(cred) if cred.is_expired() => failures.push(format!("{}: expired at {}",
+                    source.describe(), cred.expiration.map(|time| time.to_rfc3339()).unwrap_or_default())),
+                Ok(cred) => return Ok(cred),
+                Err(e) => failures.push(format!("{}: {}", source.describe(), e))
+            }
+        }
+        Err(format!("no credential source worked [{}]", failures.join(", ")).into())
+    }
+
+    /// Caches the loaded credential until it expires, then loads again from the sources.
+    /// Use for temporary credentials whose sources get refreshed, eg. by `aws sso login`
+    pub fn auto_refreshing(self) -> Result<AutoRefreshingProvider<CredentialChain>, CredentialsError> {
+        AutoRefreshingProvider::new(self)
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for CredentialChain {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        match self.load() {
+            Ok(cred) => Ok(cred.to_aws_credentials()),
+            Err(e) => Err(CredentialsError::new(e))
+        }
+    }
+}
+
+/// Loads a credential from environment variables looked up with var
+fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Credential, Box<dyn Error>> {
+    let mut cred = match (var(ACCESS_KEY_ID), var(SECRET_ACCESS_KEY)) {
+        (Some(key_id), Some(secret)) => Credential::new(&key_id, &secret),
+        _ => return Err(format!("{} and {} must both be set", ACCESS_KEY_ID, SECRET_ACCESS_KEY).into())
+    };
+    cred.session_token = var(SESSION_TOKEN).filter(|token| !token.is_empty());
+    if let Some(time) = var(CREDENTIAL_EXPIRATION).filter(|time| !time.is_empty()) {
+        cred.expiration = Some(parse_expiration(&time)?);
+    }
+    Ok(cred)
+}
+
+/// Environment variables the Environment source reads cred back from,
+/// eg. for Command::envs when running the aws cli as a child process
+pub fn env_vars(cred: &Credential) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        (ACCESS_KEY_ID, cred.access_key_id.clone()),
+        (SECRET_ACCESS_KEY, cred.secret_access_key.clone())
+    ];
+    if let Some(token) = &cred.session_token {
+        vars.push((SESSION_TOKEN, token.clone()));
+    }
+    if let Some(expiration) = cred.expiration {
+        vars.push((CREDENTIAL_EXPIRATION, expiration.to_rfc3339()));
+    }
+    vars
+}
+
+/// Section of a credentials file the Profile source reads cred back from
+pub fn render_profile(cred: &Credential, profile: &str) -> String {
+    let mut ini = format!("[{}]\naws_access_key_id = {}\naws_secret_access_key = {}\n",
+                          profile, cred.access_key_id, cred.secret_access_key);
+    if let Some(token) = &cred.session_token {
+        ini.push_str(&format!("aws_session_token = {}\n", token));
+    }
+    if let Some(expiration) = cred.expiration {
+        ini.push_str(&format!("aws_credential_expiration = {}\n", expiration.to_rfc3339()));
+    }
+    ini
+}
+
+/// contents of a credentials file with profile's keys replaced by cred's, keeping the section's
+/// other settings. Appends the profile if it isn't in contents
+pub(crate) fn update_profile(contents: &str, profile: &str, cred: &Credential) -> String {
+    const KEYS: &[&str] = &["aws_access_key_id", "aws_secret_access_key", "aws_session_token", "aws_credential_expiration"];
+    let rendered = render_profile(cred, profile);
+
+    let mut updated = String::new();
+    let mut in_profile = false;
+    let mut found = false;
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            in_profile = trimmed[1..trimmed.len() - 1].trim() == profile;
+            if in_profile {
+                found = true;
+                updated.push_str(&rendered);
+                continue;
+            }
+        }
+        let is_key = match trimmed.split_once('=') {
+            Some((key, _)) => KEYS.contains(&key.trim()),
+            None => false
+        };
+        if !(in_profile && is_key) {
+            updated.push_str(line);
+            updated.push('\n');
+        }
+    }
+    if !found {
+        if !updated.is_empty() && !updated.ends_with("\n\n") {
+            updated.push('\n');
+        }
+        updated.push_str(&rendered);
+    }
+    updated
+}
+
+fn parse_expiration(time: &str) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(time) => Ok(time.with_timezone(&Utc)),
+        Err(e) => Err(format!("invalid expiration <{}>: {}", time, e).into())
+    }
+}
+
+/// ~/.aws/credentials and ~/.aws/config
+fn aws_files() -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
+    match dirs::home_dir() {
+        Some(home) => Ok((home.join(".aws").join("credentials"), home.join(".aws").join("config"))),
+        None => Err("couldn't find home directory".into())
+    }
+}
+
+fn load_profile(profile: &str) -> Result<Credential, Box<dyn Error>> {
+    let (credentials_path, config_path) = aws_files()?;
+    if let Ok(contents) = fs::read_to_string(&credentials_path) {
+        if let Some(cred) = parse_profile(&contents, profile, false)? {
+            return Ok(cred);
+        }
+    }
+    if let Ok(contents) = fs::read_to_string(&config_path) {
+        if let Some(cred) = parse_profile(&contents, profile, true)? {
+            return Ok(cred);
+        }
+    }
+    Err(format!("no access keys for profile <{}> in <{}> or <{}>", profile, credentials_path.display(), config_path.display()).into())
+}
+
+/// Finds the keys of profile in the ini contents of a credentials or config file.
+/// Sections in config files are named [profile name], except for [default].
+///     Errors if the profile has keys but an invalid aws_credential_expiration
+fn parse_profile(contents: &str, profile: &str, is_config: bool) -> Result<Option<Credential>, Box<dyn Error>> {
+    let section = if is_config && profile != DEFAULT_PROFILE {
+        format!("profile {}", profile)
+    } else {
+        profile.to_string()
+    };
+
+    let mut in_profile = false;
+    let mut key_id = None;
+    let mut secret = None;
+    let mut session_token = None;
+    let mut expiration = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            in_profile = line[1..line.len() - 1].trim() == section;
+            continue;
+        }
+        if !in_profile {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            match key.trim() {
+                "aws_access_key_id" => key_id = Some(value.trim().to_string()),
+                "aws_secret_access_key" => secret = Some(value.trim().to_string()),
+                "aws_session_token" => session_token = Some(value.trim().to_string()),
+                "aws_credential_expiration" => expiration = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut cred = match (key_id, secret) {
+        (Some(key_id), Some(secret)) => Credential::new(&key_id, &secret),
+        _ => return Ok(None)
+    };
+    cred.session_token = session_token;
+    if let Some(time) = expiration {
+        cred.expiration = Some(parse_expiration(&time)?);
+    }
+    Ok(Some(cred))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREDENTIALS: &str = "
+[default]
+aws_access_key_id = default-id
+aws_secret_access_key = default-key
+
+# game server account
+[minecraft]
+aws_access_key_id=mc-id
+aws_secret_access_key=mc-key
+";
+
+    const CONFIG: &str = "
+[default]
+region = us-east-2
+
+[profile minecraft]
+region = us-east-2
+aws_access_key_id = config-id
+aws_secret_access_key = config-key
+";
+
+    #[test]
+    fn credentials_file_profiles() {
+        let cred = parse_profile(CREDENTIALS, "minecraft", false).unwrap().unwrap();
+        assert_eq!(cred.access_key_id, "mc-id");
+        assert_eq!(cred.secret_access_key, "mc-key");
+
+        let cred = parse_profile(CREDENTIALS, "default", false).unwrap().unwrap();
+        assert_eq!(cred.access_key_id, "default-id");
+
+        assert!(parse_profile(CREDENTIALS, "missing", false).unwrap().is_none());
+    }
+
+    #[test]
+    fn config_file_profiles() {
+        let cred = parse_profile(CONFIG, "minecraft", true).unwrap().unwrap();
+        assert_eq!(cred.access_key_id, "config-id");
+
+        //config sections are named [profile name], a bare [minecraft] isn't one
+        assert!(parse_profile(CREDENTIALS, "minecraft", true).unwrap().is_none());
+        //default has a region but no keys
+        assert!(parse_profile(CONFIG, "default", true).unwrap().is_none());
+    }
+
+    #[test]
+    fn temporary_round_trip() -> Result<(), Box<dyn Error>> {
+        use self::chrono::TimeZone;
+
+        let expiration = Utc.with_ymd_and_hms(2020, 10, 18, 8, 0, 0).unwrap();
+        let cred = Credential::new_temporary("id", "key", "token", expiration);
+
+        let from_profile = parse_profile(&render_profile(&cred, "sso"), "sso", false)?.unwrap();
+        assert_eq!(from_profile.session_token, Some("token".to_string()));
+        assert_eq!(from_profile.expiration, Some(expiration));
+
+        let vars = env_vars(&cred);
+        let from_env = from_vars(|name| vars.iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, val)| val.clone()))?;
+        assert_eq!(from_env.access_key_id, "id");
+        assert_eq!(from_env.session_token, Some("token".to_string()));
+        assert_eq!(from_env.expiration, Some(expiration));
+        Ok(())
+    }
+
+    #[test]
+    fn update_existing_and_new_profiles() -> Result<(), Box<dyn Error>> {
+        let cred = Credential::new("new-id", "new-key");
+
+        let updated = update_profile(CREDENTIALS, "minecraft", &cred);
+        assert_eq!(parse_profile(&updated, "minecraft", false)?.unwrap().access_key_id, "new-id");
+        assert_eq!(parse_profile(&updated, "default", false)?.unwrap().access_key_id, "default-id");
+        assert!(updated.contains("# game server account"));
+        assert_eq!(updated.matches("aws_access_key_id").count(), 2);
+
+        let added = update_profile(CREDENTIALS, "backup", &cred);
+        assert_eq!(parse_profile(&added, "backup", false)?.unwrap().secret_access_key, "new-key");
+        assert_eq!(parse_profile(&added, "minecraft", false)?.unwrap().access_key_id, "mc-id");
+        Ok(())
+    }
+
+    #[test]
+    fn chain_skips_expired() -> Result<(), Box<dyn Error>> {
+        let expired = Credential::new_temporary("old-id", "old-key", "token", Utc::now());
+        let chain = CredentialChain::new(vec![
+            CredentialSource::Static(expired),
+            CredentialSource::Static(Credential::new("new-id", "new-key"))
+        ]);
+        assert_eq!(chain.load()?.access_key_id, "new-id");
+
+        let chain = CredentialChain::new(vec![chain.sources[0].clone()]);
+        assert!(chain.load().err().unwrap().to_string().contains("expired"));
+        Ok(())
+    }
+
+    #[test]
+    fn chain_uses_first_working_source() -> Result<(), Box<dyn Error>> {
+        let chain = CredentialChain::new(vec![
+            CredentialSource::ConsoleCsv(PathBuf::from("data/does_not_exist")),
+            CredentialSource::AccessKeyCsv(PathBuf::from("data/test_access_key_valid")),
+            CredentialSource::Static(Credential::new("static-id", "static-key"))
+        ]);
+        assert_eq!(chain.load()?.access_key_id, "id");
+
+        let creds = tokio_test::block_on(chain.credentials())?;
+        assert_eq!(creds.aws_access_key_id(), "id");
+        assert_eq!(creds.aws_secret_access_key(), "key");
+        Ok(())
+    }
+
+    #[test]
+    fn vault_opened_once() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("rust_ec2_provider_vault_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Vault::create(&path, "correct horse")?.add("minecraft", Credential::new("vault-id", "vault-key"))?;
+
+        let source = CredentialSource::vault(path.clone(), "minecraft", "correct horse");
+        let missing = CredentialSource::vault(path.clone(), "missing", "correct horse");
+        let wrong_passphrase = CredentialSource::vault(path.clone(), "minecraft", "battery staple");
+        //already decrypted, the file isn't needed any more
+        fs::remove_file(&path)?;
+        assert_eq!(source.load()?.access_key_id, "vault-id");
+        assert!(missing.load().err().unwrap().to_string().contains("<missing>"));
+        assert!(wrong_passphrase.load().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn chain_reports_every_failure() {
+        let chain = CredentialChain::new(vec![
+            CredentialSource::ConsoleCsv(PathBuf::from("data/does_not_exist")),
+            CredentialSource::AccessKeyCsv(PathBuf::from("data/also_missing"))
+        ]);
+        let error = chain.load().err().unwrap().to_string();
+        assert!(error.contains("data/does_not_exist"));
+        assert!(error.contains("data/also_missing"));
+    }
+}
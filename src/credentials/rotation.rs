@@ -0,0 +1,490 @@
+extern crate chrono;
+extern crate rusoto_iam;
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusoto_core::{HttpClient, Region};
+use rusoto_sts::StsClient;
+use self::chrono::{DateTime, Utc};
+use self::rusoto_iam::{Iam, IamClient};
+use self::rusoto_iam::{CreateAccessKeyRequest, DeleteAccessKeyRequest, ListAccessKeysRequest, UpdateAccessKeyRequest};
+
+use crate::credentials::credential::{update_csv, Credential};
+use crate::credentials::identity::{self, caller_identity};
+use crate::credentials::provider::update_profile;
+use crate::credentials::vault::Vault;
+
+/// Longest an access key should be used before it is rotated
+pub const MAX_KEY_AGE_DAYS: i64 = 90;
+/// Most access keys IAM allows a user to have
+const MAX_KEYS_PER_USER: usize = 2;
+
+/// Where the rotated credential is saved so it is used from then on
+#[derive(Clone)]
+pub enum CredentialStore {
+    /// the key in the row of the rotated user is replaced, keeping the csv's other columns and rows.
+    /// A new file is written as a 2 column access key csv, which credential csv loading reads too
+    Csv(PathBuf),
+    /// profile section of a credentials file, eg. ~/.aws/credentials
+    Profile {
+        path: PathBuf,
+        name: String
+    },
+    Vault {
+        path: PathBuf,
+        name: String,
+        passphrase: String
+    }
+}
+
+impl CredentialStore {
+    /// Saves cred over the credential currently in the store
+    pub fn save(&self, cred: &Credential) -> Result<(), Box<dyn Error>> {
+        match self {
+            CredentialStore::Csv(path) => {
+                let contents = match fs::read_to_string(path) {
+                    Ok(contents) => Some(update_csv(&contents, cred)?),
+                    Err(e) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => return Err(format!("couldn't read <{}>: {}", path.display(), e).into())
+                };
+                //replaced with a rename so a failed write keeps the old key
+                let tmp_path = path.with_extension("tmp");
+                match contents {
+                    Some(contents) => create_private(&tmp_path)?.write_all(contents.as_bytes())?,
+                    None => cred.write_access_key_csv(create_private(&tmp_path)?)?
+                }
+                fs::rename(&tmp_path, path)?;
+                Ok(())
+            },
+            CredentialStore::Profile { path, name } => {
+                let contents = match fs::read_to_string(path) {
+                    Ok(contents) => contents,
+                    //first profile in a new credentials file
+                    Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                    Err(e) => return Err(format!("couldn't read <{}>: {}", path.display(), e).into())
+                };
+                //same as the csv, the other profiles in the file are kept if the write fails
+                let tmp_path = path.with_extension("tmp");
+                create_private(&tmp_path)?.write_all(update_profile(&contents, name, cred).as_bytes())?;
+                fs::rename(&tmp_path, path)?;
+                Ok(())
+            },
+            CredentialStore::Vault { path, name, passphrase } => {
+                Vault::open(path, passphrase)?.rotate(name, cred.clone())?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// creates or truncates path, readable only by the current user
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// What a rotation did, or would do in a dry run
+pub struct RotationReport {
+    pub old_access_key_id: String,
+    /// None in a dry run
+    pub new_credential: Option<Credential>,
+    /// each step in order, eg. "create a new access key"
+    pub steps: Vec<String>
+}
+
+/// an sts client, or why one couldn't be built
+pub type StsClientResult = Result<StsClient, Box<dyn Error>>;
+
+/// Replaces the access key of an IAM user with a new one
+pub struct KeyRotation {
+    /// builds the iam client that calls iam as a credential
+    pub iam_for: Box<dyn Fn(&Credential) -> IamClient + Send + Sync>,
+    /// builds the sts client a credential is verified with
+    pub sts_for: Box<dyn Fn(&Credential) -> StsClientResult + Send + Sync>,
+    /// only check that a rotation is possible and report the steps it would take
+    pub dry_run: bool,
+    /// times to try a new key, iam takes a few seconds before new keys work
+    pub verify_attempts: u32,
+    pub verify_interval: Duration
+}
+
+impl KeyRotation {
+    /// Rotation that calls the real IAM and STS
+    pub fn new() -> KeyRotation {
+        KeyRotation {
+            //iam is global, us-east-1 is its endpoint
+            iam_for: Box::new(|cred: &Credential| {
+                IamClient::new_with(HttpClient::new().unwrap(), cred.to_provider(), Region::UsEast1)
+            }),
+            sts_for: Box::new(identity::sts_client),
+            dry_run: false,
+            verify_attempts: 6,
+            verify_interval: Duration::from_secs(5)
+        }
+    }
+
+    /// Age of current's access key, for checking it against MAX_KEY_AGE_DAYS
+    pub async fn key_age(&self, current: &Credential) -> Result<chrono::Duration, Box<dyn Error>> {
+        let keys = self.list_keys(current).await?;
+        let created = keys.iter()
+            .find(|key| key.access_key_id.as_deref() == Some(current.access_key_id.as_str()))
+            .and_then(|key| key.create_date.clone());
+        match created {
+            Some(created) => Ok(Utc::now() - DateTime::parse_from_rfc3339(&created)?.with_timezone(&Utc)),
+            None => Err(format!("iam has no access key <{}>", current.access_key_id).into())
+        }
+    }
+
+    /// true if current's access key is older than MAX_KEY_AGE_DAYS
+    pub async fn is_due(&self, current: &Credential) -> Result<bool, Box<dyn Error>> {
+        Ok(self.key_age(current).await? > chrono::Duration::days(MAX_KEY_AGE_DAYS))
+    }
+
+    /// Creates a new access key for the user of current, checks it works as the same user,
+    /// saves it to store then deactivates and deletes current's key.
+    /// If the new key doesn't work it is deleted and current's key is left active.
+    ///     Errors if the user already has the most access keys IAM allows
+    pub async fn rotate(&self, current: &Credential, store: &CredentialStore) -> Result<RotationReport, Box<dyn Error>> {
+        let keys = self.list_keys(current).await?;
+        if !keys.iter().any(|key| key.access_key_id.as_deref() == Some(current.access_key_id.as_str())) {
+            return Err(format!("iam has no access key <{}> for this user", current.access_key_id).into());
+        }
+        if keys.len() >= MAX_KEYS_PER_USER {
+            return Err(format!("user already has {} access keys, delete the unused one before rotating", keys.len()).into());
+        }
+        let identity = self.caller_arn(current).await?;
+
+        let mut report = RotationReport {
+            old_access_key_id: current.access_key_id.clone(),
+            new_credential: None,
+            steps: vec![]
+        };
+        if self.dry_run {
+            report.steps = vec![
+                format!("create a new access key for <{}>", identity),
+                "verify the new access key with sts".to_string(),
+                "save the new access key to the store".to_string(),
+                format!("deactivate access key <{}>", current.access_key_id),
+                format!("delete access key <{}>", current.access_key_id)
+            ];
+            return Ok(report);
+        }
+
+        let create_req = CreateAccessKeyRequest {
+            user_name: current.user_name.clone()
+        };
+        let access_key = (self.iam_for)(current).create_access_key(create_req).await?.access_key;
+        let mut new = Credential::new(&access_key.access_key_id, &access_key.secret_access_key);
+        new.user_name = Some(access_key.user_name);
+        report.steps.push(format!("created access key <{}>", new.access_key_id));
+
+        if let Err(e) = self.verify(&new, &identity).await {
+            self.delete_key(current, &new.access_key_id).await?;
+            return Err(format!("new access key didn't work and was deleted: {}", e).into());
+        }
+        report.steps.push("verified the new access key with sts".to_string());
+
+        if let Err(e) = store.save(&new) {
+            self.delete_key(current, &new.access_key_id).await?;
+            return Err(format!("couldn't save the new access key, it was deleted: {}", e).into());
+        }
+        report.steps.push("saved the new access key to the store".to_string());
+
+        //an inactive key can't delete itself so the old key is retired using the new one
+        let update_req = UpdateAccessKeyRequest {
+            access_key_id: current.access_key_id.clone(),
+            status: "Inactive".to_string(),
+            user_name: new.user_name.clone()
+        };
+        (self.iam_for)(&new).update_access_key(update_req).await?;
+        report.steps.push(format!("deactivated access key <{}>", current.access_key_id));
+        self.delete_key(&new, &current.access_key_id).await?;
+        report.steps.push(format!("deleted access key <{}>", current.access_key_id));
+
+        report.new_credential = Some(new);
+        Ok(report)
+    }
+
+    async fn list_keys(&self, current: &Credential) -> Result<Vec<rusoto_iam::AccessKeyMetadata>, Box<dyn Error>> {
+        let list_req = ListAccessKeysRequest {
+            user_name: current.user_name.clone(),
+            ..Default::default()
+        };
+        Ok((self.iam_for)(current).list_access_keys(list_req).await?.access_key_metadata)
+    }
+
+    async fn caller_arn(&self, cred: &Credential) -> Result<String, Box<dyn Error>> {
+        let sts = (self.sts_for)(cred)?;
+        Ok(caller_identity(&sts).await?.arn)
+    }
+
+    /// Checks new authenticates as expected_arn, retrying while iam propagates it
+    async fn verify(&self, new: &Credential, expected_arn: &str) -> Result<(), Box<dyn Error>> {
+        let mut last_error = String::new();
+        for attempt in 0..self.verify_attempts.max(1) {
+            if attempt > 0 {
+                tokio::time::delay_for(self.verify_interval).await;
+            }
+            match self.caller_arn(new).await {
+                Ok(arn) if arn == expected_arn => return Ok(()),
+                Ok(arn) => return Err(format!("new key belongs to <{}> not <{}>", arn, expected_arn).into()),
+                Err(e) => last_error = e.to_string()
+            }
+        }
+        Err(last_error.into())
+    }
+
+    /// Deletes access_key_id calling iam as as_cred
+    async fn delete_key(&self, as_cred: &Credential, access_key_id: &str) -> Result<(), Box<dyn Error>> {
+        let delete_req = DeleteAccessKeyRequest {
+            access_key_id: access_key_id.to_string(),
+            user_name: as_cred.user_name.clone()
+        };
+        (self.iam_for)(as_cred).delete_access_key(delete_req).await?;
+        Ok(())
+    }
+}
+
+impl Default for KeyRotation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+    use crate::virtual_machine::ec2::test_utils::request_params;
+
+    const OLD_ID: &str = "AKIAOLD";
+    const NEW_ID: &str = "AKIANEW";
+    const ARN: &str = "arn:aws:iam::123456789012:user/minecraft";
+    /// rusoto skips the element after a result expecting it to be this
+    const METADATA: &str = "<ResponseMetadata><RequestId>0123</RequestId></ResponseMetadata>";
+
+    /// Local IAM/STS that answers requests in order and records which key made each one
+    #[derive(Clone, Default)]
+    struct StandIn {
+        bodies: Arc<Mutex<VecDeque<String>>>,
+        calls: Arc<Mutex<Vec<(String, String)>>>
+    }
+
+    impl StandIn {
+        fn new(bodies: &[String]) -> StandIn {
+            let stand_in = StandIn::default();
+            stand_in.bodies.lock().unwrap().extend(bodies.iter().cloned());
+            stand_in
+        }
+
+        fn dispatcher(&self, cred: &Credential) -> MockRequestDispatcher {
+            let body = self.bodies.lock().unwrap().pop_front().expect("unexpected request");
+            let calls = self.calls.clone();
+            let key_id = cred.access_key_id.clone();
+            MockRequestDispatcher::default()
+                .with_body(&body)
+                .with_request_checker(move |request| {
+                    calls.lock().unwrap().push((key_id.clone(), request_params(request)));
+                })
+        }
+
+        fn rotation(&self) -> KeyRotation {
+            let iam = self.clone();
+            let sts = self.clone();
+            KeyRotation {
+                iam_for: Box::new(move |cred: &Credential| {
+                    IamClient::new_with(iam.dispatcher(cred), MockCredentialsProvider, Region::UsEast1)
+                }),
+                sts_for: Box::new(move |cred: &Credential| {
+                    Ok(StsClient::new_with(sts.dispatcher(cred), MockCredentialsProvider, Region::UsEast1))
+                }),
+                dry_run: false,
+                verify_attempts: 1,
+                verify_interval: Duration::from_secs(0)
+            }
+        }
+
+        /// (key id, action) of every request made
+        fn actions(&self) -> Vec<(String, String)> {
+            self.calls.lock().unwrap().iter()
+                .map(|(key_id, params)| {
+                    let action = params.split('&')
+                        .find_map(|param| param.strip_prefix("Action="))
+                        .unwrap_or_default();
+                    (key_id.clone(), action.to_string())
+                })
+                .collect()
+        }
+    }
+
+    fn list_keys(ids: &[&str]) -> String {
+        let members: String = ids.iter()
+            .map(|id| format!("<member><UserName>minecraft</UserName><AccessKeyId>{}</AccessKeyId>\
+                <Status>Active</Status><CreateDate>2020-07-01T00:00:00Z</CreateDate></member>", id))
+            .collect();
+        format!("<ListAccessKeysResponse><ListAccessKeysResult><AccessKeyMetadata>{}</AccessKeyMetadata>\
+            <IsTruncated>false</IsTruncated></ListAccessKeysResult>{}</ListAccessKeysResponse>", members, METADATA)
+    }
+
+    fn caller_identity() -> String {
+        format!("<GetCallerIdentityResponse><GetCallerIdentityResult><Arn>{}</Arn>\
+            <UserId>AIDA0123</UserId><Account>123456789012</Account></GetCallerIdentityResult>{}</GetCallerIdentityResponse>", ARN, METADATA)
+    }
+
+    fn create_key() -> String {
+        format!("<CreateAccessKeyResponse><CreateAccessKeyResult><AccessKey><UserName>minecraft</UserName>\
+            <AccessKeyId>{}</AccessKeyId><Status>Active</Status><SecretAccessKey>new-secret</SecretAccessKey>\
+            </AccessKey></CreateAccessKeyResult>{}</CreateAccessKeyResponse>", NEW_ID, METADATA)
+    }
+
+    fn empty(action: &str) -> String {
+        format!("<{0}Response>{1}</{0}Response>", action, METADATA)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_ec2_{}_{}", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn rotate_and_save_to_profile() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("rotate_profile");
+        fs::write(&path, "[minecraft]\naws_access_key_id = AKIAOLD\naws_secret_access_key = old-secret\nregion = us-east-2\n")?;
+        let store = CredentialStore::Profile { path: path.clone(), name: "minecraft".to_string() };
+
+        let stand_in = StandIn::new(&[
+            list_keys(&[OLD_ID]),
+            caller_identity(),
+            create_key(),
+            caller_identity(),
+            empty("UpdateAccessKey"),
+            empty("DeleteAccessKey")
+        ]);
+        let report = stand_in.rotation().rotate(&Credential::new(OLD_ID, "old-secret"), &store).await?;
+
+        assert_eq!(report.new_credential.unwrap().access_key_id, NEW_ID);
+        assert_eq!(stand_in.actions(), vec![
+            (OLD_ID.to_string(), "ListAccessKeys".to_string()),
+            (OLD_ID.to_string(), "GetCallerIdentity".to_string()),
+            (OLD_ID.to_string(), "CreateAccessKey".to_string()),
+            (NEW_ID.to_string(), "GetCallerIdentity".to_string()),
+            (NEW_ID.to_string(), "UpdateAccessKey".to_string()),
+            (NEW_ID.to_string(), "DeleteAccessKey".to_string())
+        ]);
+        let calls = stand_in.calls.lock().unwrap();
+        assert!(calls[4].1.contains("AccessKeyId=AKIAOLD") && calls[4].1.contains("Status=Inactive"));
+        assert!(calls[5].1.contains("AccessKeyId=AKIAOLD"));
+
+        let saved = fs::read_to_string(&path)?;
+        assert!(saved.contains("aws_access_key_id = AKIANEW") && saved.contains("new-secret"));
+        assert!(saved.contains("region = us-east-2") && !saved.contains("old-secret"));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn profile_save() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("save_profile");
+        let _ = fs::remove_file(&path);
+        let store = CredentialStore::Profile { path: path.clone(), name: "minecraft".to_string() };
+
+        store.save(&Credential::new(NEW_ID, "new-secret"))?;
+        assert!(fs::read_to_string(&path)?.contains("aws_access_key_id = AKIANEW"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path)?;
+
+        //unreadable isn't the same as missing, the profiles in it would be lost
+        let unreadable = CredentialStore::Profile { path: std::env::temp_dir(), name: "minecraft".to_string() };
+        assert!(unreadable.save(&Credential::new(NEW_ID, "new-secret")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_save_keeps_console_columns() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("save_console.csv");
+        fs::write(&path, "User name,Password,Access key ID,Secret access key,Console login link\n\
+            minecraft,,AKIAOLD,old-secret,https://123456789012.signin.aws.amazon.com/console\n")?;
+        let store = CredentialStore::Csv(path.clone());
+
+        let mut new = Credential::new(NEW_ID, "new-secret");
+        new.user_name = Some("minecraft".to_string());
+        store.save(&new)?;
+
+        let saved = fs::read_to_string(&path)?;
+        fs::remove_file(&path)?;
+        assert_eq!(saved, "User name,Password,Access key ID,Secret access key,Console login link\n\
+            minecraft,,AKIANEW,new-secret,https://123456789012.signin.aws.amazon.com/console\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_verification_deletes_new_key() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("rotate_failed.csv");
+        let store = CredentialStore::Csv(path.clone());
+        let other_user = caller_identity().replace("user/minecraft", "user/someone-else");
+
+        let stand_in = StandIn::new(&[
+            list_keys(&[OLD_ID]),
+            caller_identity(),
+            create_key(),
+            other_user,
+            empty("DeleteAccessKey")
+        ]);
+        let result = stand_in.rotation().rotate(&Credential::new(OLD_ID, "old-secret"), &store).await;
+
+        assert!(result.is_err());
+        let calls = stand_in.calls.lock().unwrap();
+        assert_eq!(calls.last().unwrap().0, OLD_ID);
+        assert!(calls.last().unwrap().1.contains("AccessKeyId=AKIANEW"));
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("rotate_dry_run.csv");
+        let store = CredentialStore::Csv(path.clone());
+
+        let stand_in = StandIn::new(&[list_keys(&[OLD_ID]), caller_identity()]);
+        let mut rotation = stand_in.rotation();
+        rotation.dry_run = true;
+        let report = rotation.rotate(&Credential::new(OLD_ID, "old-secret"), &store).await?;
+
+        assert!(report.new_credential.is_none());
+        assert_eq!(report.steps.len(), 5);
+        assert_eq!(stand_in.actions().len(), 2);
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn too_many_keys() {
+        let stand_in = StandIn::new(&[list_keys(&[OLD_ID, "AKIAOTHER"])]);
+        let store = CredentialStore::Csv(temp_path("rotate_too_many.csv"));
+        let result = stand_in.rotation().rotate(&Credential::new(OLD_ID, "old-secret"), &store).await;
+        assert!(result.is_err());
+        assert_eq!(stand_in.actions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn old_key_is_due() -> Result<(), Box<dyn Error>> {
+        let stand_in = StandIn::new(&[list_keys(&[OLD_ID])]);
+        assert!(stand_in.rotation().is_due(&Credential::new(OLD_ID, "old-secret")).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+extern crate argon2;
+extern crate base64;
+extern crate chacha20poly1305;
+extern crate chrono;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use self::argon2::Argon2;
+use self::chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use self::chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use self::chacha20poly1305::aead::rand_core::RngCore;
+use self::chrono::{DateTime, Utc};
+use self::serde_json::{json, Value};
+
+use crate::credentials::credential::Credential;
+
+/// Version of the vault file format, bumped if the kdf or cipher changes
+const VAULT_VERSION: u64 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Named credentials kept in a file encrypted with a key derived from a passphrase.
+/// The key is derived with argon2id and the file sealed with chacha20poly1305,
+/// so a wrong passphrase or a tampered file fails to open instead of giving garbage.
+/// Every change is written to disk straight away
+pub struct Vault {
+    path: PathBuf,
+    salt: [u8; SALT_LEN],
+    key: Key,
+    credentials: BTreeMap<String, Credential>
+}
+
+impl Vault {
+    /// Creates an empty vault at path, readable only by the current user.
+    ///     Errors if a file already exists at path
+    pub fn create(path: &Path, passphrase: &str) -> Result<Vault, Box<dyn Error>> {
+        if path.exists() {
+            return Err(format!("<{}> already exists", path.display()).into());
+        }
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let vault = Vault {
+            path: path.to_path_buf(),
+            salt,
+            key: derive_key(passphrase, &salt)?,
+            credentials: BTreeMap::new()
+        };
+        vault.save()?;
+        Ok(vault)
+    }
+
+    /// Opens the vault at path.
+    ///     Errors if the passphrase is wrong or the file was modified
+    pub fn open(path: &Path, passphrase: &str) -> Result<Vault, Box<dyn Error>> {
+        let file: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if file["version"].as_u64() != Some(VAULT_VERSION) {
+            return Err(format!("<{}> is not a version {} vault", path.display(), VAULT_VERSION).into());
+        }
+        let field = |name: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+            match file[name].as_str() {
+                Some(value) => Ok(base64::decode(value)?),
+                None => Err(format!("vault has no <{}>", name).into())
+            }
+        };
+        let salt = field("salt")?;
+        let nonce = field("nonce")?;
+        if salt.len() != SALT_LEN || nonce.len() != NONCE_LEN {
+            return Err("vault salt or nonce has the wrong length".into());
+        }
+
+        let mut vault = Vault {
+            path: path.to_path_buf(),
+            salt: [0; SALT_LEN],
+            key: derive_key(passphrase, &salt)?,
+            credentials: BTreeMap::new()
+        };
+        vault.salt.copy_from_slice(&salt);
+
+        let plaintext = match ChaCha20Poly1305::new(&vault.key).decrypt(Nonce::from_slice(&nonce), field("ciphertext")?.as_ref()) {
+            Ok(plaintext) => plaintext,
+            Err(_) => return Err(format!("couldn't decrypt <{}>, wrong passphrase or the file was modified", path.display()).into())
+        };
+        let entries: Value = serde_json::from_slice(&plaintext)?;
+        for (name, entry) in entries.as_object().into_iter().flatten() {
+            vault.credentials.insert(name.clone(), credential_from_json(entry)?);
+        }
+        Ok(vault)
+    }
+
+    /// Names of every stored credential, sorted
+    pub fn list(&self) -> Vec<&str> {
+        self.credentials.keys().map(String::as_str).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Credential> {
+        self.credentials.get(name)
+    }
+
+    /// Stores cred as name.
+    ///     Errors if name is already used, see rotate for replacing a credential
+    pub fn add(&mut self, name: &str, cred: Credential) -> Result<(), Box<dyn Error>> {
+        if self.credentials.contains_key(name) {
+            return Err(format!("vault already has a credential named <{}>", name).into());
+        }
+        self.credentials.insert(name.to_string(), cred);
+        self.save()
+    }
+
+    /// Removes and returns the credential stored as name
+    pub fn remove(&mut self, name: &str) -> Result<Credential, Box<dyn Error>> {
+        match self.credentials.remove(name) {
+            Some(cred) => {
+                self.save()?;
+                Ok(cred)
+            },
+            None => Err(format!("vault has no credential named <{}>", name).into())
+        }
+    }
+
+    /// Replaces the credential stored as name with cred, eg. after rotating its access key.
+    /// Returns the old credential so it can be deactivated
+    pub fn rotate(&mut self, name: &str, cred: Credential) -> Result<Credential, Box<dyn Error>> {
+        match self.credentials.insert(name.to_string(), cred) {
+            Some(old) => {
+                self.save()?;
+                Ok(old)
+            },
+            None => {
+                self.credentials.remove(name);
+                Err(format!("vault has no credential named <{}>", name).into())
+            }
+        }
+    }
+
+    /// Adds every credential in a csv downloaded from the IAM console, named by user name,
+    /// or by the file name if the csv has no user names. Deletes the csv afterwards if delete_csv.
+    /// Returns the names added.
+    ///     Errors without adding anything if a name is already used
+    pub fn import_csv(&mut self, csv_path: &Path, delete_csv: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        let creds = Credential::all_with_csv(&File::open(csv_path)?)?;
+        let file_name = csv_path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+
+        let mut named = vec![];
+        for (i, cred) in creds.into_iter().enumerate() {
+            let name = match &cred.user_name {
+                Some(user_name) => user_name.clone(),
+                None if i == 0 => file_name.clone(),
+                None => format!("{}-{}", file_name, i)
+            };
+            if self.credentials.contains_key(&name) || named.iter().any(|(used, _)| used == &name) {
+                return Err(format!("vault already has a credential named <{}>", name).into());
+            }
+            named.push((name, cred));
+        }
+
+        let names = named.iter().map(|(name, _)| name.clone()).collect();
+        self.credentials.extend(named);
+        self.save()?;
+        if delete_csv {
+            fs::remove_file(csv_path)?;
+        }
+        Ok(names)
+    }
+
+    /// Encrypts every credential with a new nonce and replaces the vault file
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let entries: serde_json::Map<String, Value> = self.credentials.iter()
+            .map(|(name, cred)| (name.clone(), credential_to_json(cred)))
+            .collect();
+        let plaintext = serde_json::to_vec(&entries)?;
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = match ChaCha20Poly1305::new(&self.key).encrypt(&nonce, plaintext.as_ref()) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err("couldn't encrypt vault".into())
+        };
+        let file = json!({
+            "version": VAULT_VERSION,
+            "salt": base64::encode(self.salt),
+            "nonce": base64::encode(nonce),
+            "ciphertext": base64::encode(ciphertext)
+        });
+
+        //written next to the vault then renamed over it so a failed write can't lose the vault
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut tmp = options.open(&tmp_path)?;
+        tmp.write_all(file.to_string().as_bytes())?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key, Box<dyn Error>> {
+    let mut key = Key::default();
+    match Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key) {
+        Ok(()) => Ok(key),
+        Err(e) => Err(format!("couldn't derive vault key: {}", e).into())
+    }
+}
+
+fn credential_to_json(cred: &Credential) -> Value {
+    json!({
+        "user_name": cred.user_name,
+        "access_key_id": cred.access_key_id,
+        "secret_access_key": cred.secret_access_key,
+        "session_token": cred.session_token,
+        "expiration": cred.expiration.map(|time| time.to_rfc3339())
+    })
+}
+
+fn credential_from_json(entry: &Value) -> Result<Credential, Box<dyn Error>> {
+    let (key_id, secret) = match (entry["access_key_id"].as_str(), entry["secret_access_key"].as_str()) {
+        (Some(key_id), Some(secret)) => (key_id, secret),
+        _ => return Err("vault entry is missing its access key".into())
+    };
+    let mut cred = Credential::new(key_id, secret);
+    cred.user_name = entry["user_name"].as_str().map(str::to_string);
+    cred.session_token = entry["session_token"].as_str().map(str::to_string);
+    if let Some(time) = entry["expiration"].as_str() {
+        cred.expiration = Some(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc));
+    }
+    Ok(cred)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_ec2_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn add_rotate_remove_persist() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("vault_persist");
+        let _ = fs::remove_file(&path);
+
+        let mut vault = Vault::create(&path, "correct horse")?;
+        vault.add("minecraft", Credential::new("id", "key"))?;
+        assert!(vault.add("minecraft", Credential::new("other", "other")).is_err());
+        assert!(!fs::read_to_string(&path)?.contains("key"));
+
+        let mut vault = Vault::open(&path, "correct horse")?;
+        assert_eq!(vault.list(), vec!["minecraft"]);
+        let old = vault.rotate("minecraft", Credential::new("new-id", "new-key"))?;
+        assert_eq!(old.access_key_id, "id");
+        assert!(vault.rotate("missing", Credential::new("id", "key")).is_err());
+        assert!(vault.get("missing").is_none());
+
+        let mut vault = Vault::open(&path, "correct horse")?;
+        assert_eq!(vault.get("minecraft").unwrap().access_key_id, "new-id");
+        vault.remove("minecraft")?;
+        assert!(Vault::open(&path, "correct horse")?.list().is_empty());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn wrong_passphrase_and_tampering() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("vault_tamper");
+        let _ = fs::remove_file(&path);
+
+        let mut vault = Vault::create(&path, "correct horse")?;
+        vault.add("minecraft", Credential::new("id", "key"))?;
+        assert!(Vault::open(&path, "battery staple").is_err());
+
+        let mut file: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        let mut ciphertext = base64::decode(file["ciphertext"].as_str().unwrap())?;
+        ciphertext[0] ^= 1;
+        file["ciphertext"] = Value::String(base64::encode(ciphertext));
+        fs::write(&path, file.to_string())?;
+        assert!(Vault::open(&path, "correct horse").is_err());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn import_and_delete_csv() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("vault_import");
+        let csv_path = temp_path("vault_import.csv");
+        let _ = fs::remove_file(&path);
+        fs::copy("data/test_user_credentials_valid", &csv_path)?;
+
+        let mut vault = Vault::create(&path, "correct horse")?;
+        assert_eq!(vault.import_csv(&csv_path, true)?, vec!["user"]);
+        assert!(!csv_path.exists());
+        assert_eq!(Vault::open(&path, "correct horse")?.get("user").unwrap().secret_access_key, "key");
+
+        //a second import of the same user changes nothing
+        fs::copy("data/test_user_credentials_valid", &csv_path)?;
+        assert!(vault.import_csv(&csv_path, true).is_err());
+        assert!(csv_path.exists());
+
+        fs::remove_file(&csv_path)?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
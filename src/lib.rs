@@ -1,9 +1,11 @@
 pub mod virtual_machine;
 pub mod credentials;
 pub mod ssh;
+pub mod config;
+pub mod minecraft;
+pub mod scheduler;
 
 #[cfg(test)]
 mod tests {
-    use crate::virtual_machine::vm::VMCore;
 
 }
@@ -0,0 +1,10 @@
+mod cli;
+
+#[tokio::main]
+async fn main() {
+    let matches = cli::app().get_matches();
+    if let Err(e) = cli::run(&matches).await {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,148 @@
+//! A local stand in for a minecraft server answering the server list ping and rcon
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::minecraft::MinecraftServer;
+use crate::minecraft::ping::{packet, read_varint, write_string};
+use crate::minecraft::rcon::{encode, read_packet, TYPE_AUTH_RESPONSE, TYPE_LOGIN, TYPE_RESPONSE};
+
+pub const PASSWORD: &str = "hunter2";
+/// what the fake answers pings with
+pub const STATUS: &str = r#"{
+    "version": {"name": "1.16.4", "protocol": 754},
+    "players": {"max": 20, "online": 1, "sample": [{"name": "Steve", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d20"}]},
+    "description": {"text": "§aSurvival", "extra": [{"text": " world"}]}
+}"#;
+/// what the fake answers pings with once empty is set
+pub const EMPTY_STATUS: &str = r#"{
+    "version": {"name": "1.16.4", "protocol": 754},
+    "players": {"max": 20, "online": 0},
+    "description": {"text": "§aSurvival"}
+}"#;
+/// longer than fits in one rcon packet, the fake sends it in two
+pub const LONG_COMMAND: &str = "help";
+
+pub struct FakeServer {
+    pub port: u16,
+    pub rcon_port: u16,
+    /// every rcon command run, in order
+    pub commands: Arc<Mutex<Vec<String>>>,
+    /// set by the stop command, after which pings go unanswered
+    pub stopped: Arc<AtomicBool>,
+    /// set to drop pings without answering them, like a server the network in between loses pings to
+    pub ping_blocked: Arc<AtomicBool>,
+    /// set to answer pings with nobody online
+    pub empty: Arc<AtomicBool>,
+    /// whether rcon stops listening once stopped, clear to act like a server stuck saving the world
+    pub exits: Arc<AtomicBool>
+}
+
+impl FakeServer {
+    pub async fn start() -> FakeServer {
+        let mut ping_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut rcon_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fake = FakeServer {
+            port: ping_listener.local_addr().unwrap().port(),
+            rcon_port: rcon_listener.local_addr().unwrap().port(),
+            commands: Arc::new(Mutex::new(vec![])),
+            stopped: Arc::new(AtomicBool::new(false)),
+            ping_blocked: Arc::new(AtomicBool::new(false)),
+            empty: Arc::new(AtomicBool::new(false)),
+            exits: Arc::new(AtomicBool::new(true))
+        };
+
+        let (stopped, blocked, empty, exits) = (fake.stopped.clone(), fake.ping_blocked.clone(), fake.empty.clone(), fake.exits.clone());
+        tokio::spawn(async move {
+            //dropping the listener refuses connections like an exited server does
+            while !(stopped.load(Ordering::SeqCst) && exits.load(Ordering::SeqCst)) {
+                let stream = match tokio::time::timeout(Duration::from_millis(10), ping_listener.accept()).await {
+                    Ok(Ok((stream, _))) => stream,
+                    _ => continue
+                };
+                if !stopped.load(Ordering::SeqCst) && !blocked.load(Ordering::SeqCst) {
+                    let status = if empty.load(Ordering::SeqCst) { EMPTY_STATUS } else { STATUS };
+                    tokio::spawn(answer_ping(stream, status));
+                }
+            }
+        });
+        let (commands, stopped, exits) = (fake.commands.clone(), fake.stopped.clone(), fake.exits.clone());
+        tokio::spawn(async move {
+            while !(stopped.load(Ordering::SeqCst) && exits.load(Ordering::SeqCst)) {
+                if let Ok(Ok((stream, _))) = tokio::time::timeout(Duration::from_millis(10), rcon_listener.accept()).await {
+                    tokio::spawn(answer_rcon(stream, commands.clone(), stopped.clone()));
+                }
+            }
+        });
+        fake
+    }
+
+    /// the fake as a server with its rcon password
+    pub fn server(&self) -> MinecraftServer {
+        MinecraftServer {
+            host: "127.0.0.1".to_string(),
+            port: self.port,
+            rcon_port: self.rcon_port,
+            rcon_password: Some(PASSWORD.to_string())
+        }
+    }
+
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+async fn answer_ping(mut stream: TcpStream, status: &'static str) {
+    //handshake then status request, both framed with their length
+    for _ in 0..2 {
+        let len = match read_varint(&mut stream).await {
+            Ok(len) => len,
+            Err(_) => return
+        };
+        let mut body = vec![0; len as usize];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+    }
+    let mut response = vec![];
+    write_string(&mut response, status);
+    let _ = stream.write_all(&packet(0x00, &response)).await;
+}
+
+async fn answer_rcon(mut stream: TcpStream, commands: Arc<Mutex<Vec<String>>>, stopped: Arc<AtomicBool>) {
+    loop {
+        //the error isn't Send so can't be held across the writes
+        let (id, kind, body) = match read_packet(&mut stream).await {
+            Ok(packet) => packet,
+            Err(_) => return
+        };
+        let replies = match kind {
+            TYPE_LOGIN if body == PASSWORD => vec![encode(id, TYPE_RESPONSE, ""), encode(id, TYPE_AUTH_RESPONSE, "")],
+            TYPE_LOGIN => vec![encode(-1, TYPE_AUTH_RESPONSE, "")],
+            //vanilla answers packets of an unknown type like this
+            TYPE_RESPONSE => vec![encode(id, TYPE_RESPONSE, &format!("Unknown request {:x}", kind))],
+            _ => {
+                commands.lock().unwrap().push(body.clone());
+                match body.as_str() {
+                    "list" => vec![encode(id, TYPE_RESPONSE, "There are 1 of a max of 20 players online: Steve")],
+                    "save-all" | "save-all flush" => vec![encode(id, TYPE_RESPONSE, "Saving the game (this may take a moment!)Saved the game")],
+                    "stop" => {
+                        stopped.store(true, Ordering::SeqCst);
+                        let _ = stream.write_all(&encode(id, TYPE_RESPONSE, "Stopping the server")).await;
+                        return;
+                    },
+                    LONG_COMMAND => vec![encode(id, TYPE_RESPONSE, &"a".repeat(4096)), encode(id, TYPE_RESPONSE, "b")],
+                    _ => vec![encode(id, TYPE_RESPONSE, "")]
+                }
+            }
+        };
+        for reply in replies {
+            if stream.write_all(&reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Stops a vm once nobody has been playing on it for a while, the server costs the same empty or full
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::minecraft::MinecraftServer;
+use crate::minecraft::shutdown::GracefulStop;
+use crate::virtual_machine::vm::{VMCore, VMSnapshot};
+
+/// A game server players connect to
+#[async_trait]
+pub trait GameServer: Sync {
+    /// number of players connected right now
+    async fn players_online(&self) -> Result<u32, Box<dyn Error>>;
+    /// shows message to every connected player
+    async fn broadcast(&self, message: &str) -> Result<(), Box<dyn Error>>;
+    /// the server as a minecraft server that can be shut down gracefully, None if it isn't one
+    fn minecraft(&self) -> Option<&MinecraftServer> {
+        None
+    }
+}
+
+#[async_trait]
+impl GameServer for MinecraftServer {
+    async fn players_online(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.status().await?.players_online)
+    }
+
+    async fn broadcast(&self, message: &str) -> Result<(), Box<dyn Error>> {
+        let mut rcon = self.rcon().await?;
+        rcon.say(message).await
+    }
+
+    fn minecraft(&self) -> Option<&MinecraftServer> {
+        Some(self)
+    }
+}
+
+/// How a watch ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchOutcome {
+    /// nobody played for idle_timeout so the vm was stopped
+    Stopped,
+    /// the vm stopped some other way, eg. from the console
+    StoppedElsewhere
+}
+
+/// Checks a server's players every check_interval and stops its vm once there have been none
+/// for idle_timeout. Players get a warning and grace_period to join before the stop.
+/// Defaults to checking every minute and stopping after 15 idle minutes with a 2 minute warning
+#[derive(Debug, Clone)]
+pub struct IdleWatcher {
+    pub check_interval: Duration,
+    pub idle_timeout: Duration,
+    pub grace_period: Duration,
+    /// whether a server that doesn't answer counts as having nobody on it, eg. after it crashed.
+    /// Off by default, a ping lost on the way doesn't mean nobody is playing
+    pub unreachable_is_idle: bool,
+    /// shuts the server down this way before stopping the vm, None stops the vm straight away
+    pub graceful: Option<GracefulStop>
+}
+
+impl Default for IdleWatcher {
+    fn default() -> Self {
+        IdleWatcher {
+            check_interval: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(15 * 60),
+            grace_period: Duration::from_secs(2 * 60),
+            unreachable_is_idle: false,
+            graceful: None
+        }
+    }
+}
+
+impl IdleWatcher {
+    /// Watches server until vm has been stopped, by this or anything else.
+    ///     Errors if stopping vm fails, or if graceful is set and the server can't be shut down
+    pub async fn watch<V, S>(&self, vm: &mut V, server: &S) -> Result<WatchOutcome, Box<dyn Error>>
+        where V: VMCore + VMSnapshot + Send + Sync, S: GameServer {
+        let mut idle_since: Option<Instant> = None;
+        loop {
+            if vm.status().await.as_deref() != Some("running") {
+                return Ok(WatchOutcome::StoppedElsewhere);
+            }
+            if !self.is_idle(server).await {
+                idle_since = None;
+            } else if idle_since.get_or_insert_with(Instant::now).elapsed() >= self.idle_timeout {
+                //nobody may be on to see it, but someone joining during the grace period will
+                let _ = server.broadcast(&self.warning()).await;
+                tokio::time::delay_for(self.grace_period).await;
+                if self.is_idle(server).await {
+                    self.stop(vm, server).await?;
+                    return Ok(WatchOutcome::Stopped);
+                }
+                idle_since = None;
+            }
+            tokio::time::delay_for(self.check_interval).await;
+        }
+    }
+
+    /// stops vm, shutting server down first if graceful is set
+    async fn stop<V, S>(&self, vm: &mut V, server: &S) -> Result<(), Box<dyn Error>>
+        where V: VMCore + VMSnapshot + Send + Sync, S: GameServer {
+        let graceful = match &self.graceful {
+            Some(graceful) => graceful,
+            None => {
+                vm.stop().await?;
+                return Ok(());
+            }
+        };
+        match server.minecraft() {
+            Some(minecraft) => {
+                graceful.stop_gracefully(vm, minecraft).await?;
+                Ok(())
+            },
+            None => Err("only a minecraft server can be shut down gracefully, not stopping the vm".into())
+        }
+    }
+
+    async fn is_idle<S: GameServer>(&self, server: &S) -> bool {
+        match server.players_online().await {
+            Ok(players) => players == 0,
+            Err(_) => self.unreachable_is_idle
+        }
+    }
+
+    /// message broadcast before stopping
+    pub fn warning(&self) -> String {
+        format!("Nobody is playing, the server stops in {} seconds unless someone joins", self.grace_period.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+    use crate::minecraft::fake_server::FakeServer;
+    use crate::virtual_machine::ec2::waiter::Waiter;
+    use crate::virtual_machine::mock::MockVM;
+
+    /// server whose player counts come from a script, None meaning unreachable.
+    /// Once the script runs out it has nobody on
+    struct Scripted {
+        players: Mutex<Vec<Option<u32>>>,
+        broadcasts: Mutex<Vec<String>>
+    }
+
+    impl Scripted {
+        fn new(players: &[Option<u32>]) -> Scripted {
+            Scripted {
+                players: Mutex::new(players.iter().rev().cloned().collect()),
+                broadcasts: Mutex::new(vec![])
+            }
+        }
+    }
+
+    #[async_trait]
+    impl GameServer for Scripted {
+        async fn players_online(&self) -> Result<u32, Box<dyn Error>> {
+            match self.players.lock().unwrap().pop().unwrap_or(Some(0)) {
+                Some(players) => Ok(players),
+                None => Err("unreachable".into())
+            }
+        }
+
+        async fn broadcast(&self, message: &str) -> Result<(), Box<dyn Error>> {
+            self.broadcasts.lock().unwrap().push(message.to_string());
+            Ok(())
+        }
+    }
+
+    fn watcher() -> IdleWatcher {
+        IdleWatcher {
+            check_interval: Duration::from_millis(1),
+            idle_timeout: Duration::from_millis(0),
+            grace_period: Duration::from_millis(1),
+            unreachable_is_idle: true,
+            graceful: None
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_when_idle() -> Result<(), Box<dyn Error>> {
+        let mut vm = MockVM::running("127.0.0.1");
+        let server = Scripted::new(&[Some(2), Some(1), Some(0)]);
+
+        assert_eq!(watcher().watch(&mut vm, &server).await?, WatchOutcome::Stopped);
+        assert_eq!(vm.calls, vec!["stop"]);
+        assert_eq!(*server.broadcasts.lock().unwrap(), vec![watcher().warning()]);
+        assert!(server.players.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn joining_in_grace_period_keeps_running() -> Result<(), Box<dyn Error>> {
+        let mut vm = MockVM::running("127.0.0.1");
+        //idle, someone joins after the warning, then idle for good
+        let server = Scripted::new(&[Some(0), Some(1), Some(0), Some(0)]);
+
+        assert_eq!(watcher().watch(&mut vm, &server).await?, WatchOutcome::Stopped);
+        assert_eq!(vm.calls, vec!["stop"]);
+        assert_eq!(server.broadcasts.lock().unwrap().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stops_gracefully_when_idle() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        fake.empty.store(true, Ordering::SeqCst);
+        let mut vm = MockVM::running("127.0.0.1");
+        let graceful = IdleWatcher {
+            graceful: Some(GracefulStop {
+                waiter: Waiter::new(Duration::from_millis(10), Duration::from_secs(5)),
+                ..Default::default()
+            }),
+            ..watcher()
+        };
+
+        assert_eq!(graceful.watch(&mut vm, &fake.server()).await?, WatchOutcome::Stopped);
+        assert_eq!(fake.commands(), vec![format!("say {}", graceful.warning()), "save-all flush".to_string(), "stop".to_string()]);
+        assert_eq!(vm.calls, vec!["stop"]);
+
+        //a server that can't be shut down gracefully keeps its vm running
+        let mut vm = MockVM::running("127.0.0.1");
+        assert!(graceful.watch(&mut vm, &Scripted::new(&[])).await.is_err());
+        assert!(vm.calls.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unreachable_server() -> Result<(), Box<dyn Error>> {
+        let server = Scripted::new(&[None, None]);
+        let mut keep_unreachable = watcher();
+        keep_unreachable.unreachable_is_idle = false;
+        assert!(!keep_unreachable.is_idle(&server).await);
+        assert!(watcher().is_idle(&server).await);
+        assert!(!IdleWatcher::default().unreachable_is_idle);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn already_stopped() -> Result<(), Box<dyn Error>> {
+        let mut vm = MockVM::stopped();
+        let server = Scripted::new(&[]);
+
+        assert_eq!(watcher().watch(&mut vm, &server).await?, WatchOutcome::StoppedElsewhere);
+        assert!(vm.calls.is_empty());
+        assert!(server.broadcasts.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+//! Talking to the minecraft server running on a vm, to see who is on and to run commands
+pub mod idle;
+pub mod ping;
+pub mod rcon;
+pub mod shutdown;
+
+#[cfg(test)]
+pub(crate) mod fake_server;
+
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::minecraft::ping::ServerStatus;
+use crate::minecraft::rcon::Rcon;
+use crate::virtual_machine::vm::VMNetwork;
+
+/// Port the server list ping is answered on, see virtual_machine::ec2::security_group::MINECRAFT_PORT
+pub const SERVER_PORT: u16 = 25565;
+/// Port rcon listens on once enable-rcon=true is in server.properties
+pub const RCON_PORT: u16 = 25575;
+/// rcon.password from server.properties, read from the environment so it isn't in a file
+pub const RCON_PASSWORD_VAR: &str = "RUST_EC2_RCON_PASSWORD";
+/// Longest probe waits for a connection, a firewalled port never refuses one
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What connecting to a port says about whatever should be listening on it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortState {
+    Listening,
+    /// the host is up with nothing listening
+    Refused,
+    /// timed out or unreachable, eg. firewalled, which says nothing about the server
+    NoAnswer
+}
+
+/// Connects to host:port without speaking any protocol
+pub async fn probe(host: &str, port: u16) -> PortState {
+    match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => PortState::Listening,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => PortState::Refused,
+        _ => PortState::NoAnswer
+    }
+}
+
+/// A minecraft server at host. Commands can only be sent if rcon_password is set
+#[derive(Clone)]
+pub struct MinecraftServer {
+    pub host: String,
+    pub port: u16,
+    pub rcon_port: u16,
+    pub rcon_password: Option<String>
+}
+
+impl MinecraftServer {
+    /// Server on the default ports of host, without rcon
+    pub fn new(host: &str) -> MinecraftServer {
+        MinecraftServer {
+            host: host.to_string(),
+            port: SERVER_PORT,
+            rcon_port: RCON_PORT,
+            rcon_password: None
+        }
+    }
+
+    /// Server on the default ports of vm's public ip.
+    ///     Errors if vm has no public ip, eg. it isn't running
+    pub async fn on_vm(vm: &impl VMNetwork) -> Result<MinecraftServer, Box<dyn Error>> {
+        match vm.get_public_ip().await {
+            Some(ip) => Ok(Self::new(&ip)),
+            None => Err("vm has no public ip, is it running?".into())
+        }
+    }
+
+    /// Version, motd and players the server shows in the multiplayer screen
+    pub async fn status(&self) -> Result<ServerStatus, Box<dyn Error>> {
+        ping::status(&self.host, self.port).await
+    }
+
+    /// Whether the rcon port accepts connections. The server closes it only once it has saved
+    /// the world, so it is still open while the server is exiting after the ping stops answering
+    pub async fn rcon_listening(&self) -> bool {
+        probe(&self.host, self.rcon_port).await == PortState::Listening
+    }
+
+    /// Whether the server is known to have exited, with both its ports refusing connections.
+    /// A server that doesn't answer at all may still be running
+    pub async fn is_down(&self) -> bool {
+        probe(&self.host, self.port).await == PortState::Refused
+            && probe(&self.host, self.rcon_port).await == PortState::Refused
+    }
+
+    /// Connects and authenticates with rcon.
+    ///     Errors if there's no rcon_password
+    pub async fn rcon(&self) -> Result<Rcon, Box<dyn Error>> {
+        match &self.rcon_password {
+            Some(password) => Rcon::connect(&self.host, self.rcon_port, password).await,
+            None => Err(format!("no rcon password for <{}>, set {}", self.host, RCON_PASSWORD_VAR).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_machine::mock::MockVM;
+
+    #[test]
+    fn server_on_vm() {
+        let server = tokio_test::block_on(MinecraftServer::on_vm(&MockVM::running("1.2.3.4"))).unwrap();
+        assert_eq!(server.host, "1.2.3.4");
+        assert_eq!(server.port, SERVER_PORT);
+        assert!(tokio_test::block_on(server.rcon()).is_err());
+        assert!(tokio_test::block_on(MinecraftServer::on_vm(&MockVM::stopped())).is_err());
+    }
+}
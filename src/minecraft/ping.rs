@@ -0,0 +1,218 @@
+//! Server List Ping, what the multiplayer screen uses to show a server's version, motd and players.
+//! See https://wiki.vg/Server_List_Ping
+use std::error::Error;
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest a ping waits for the server before giving up
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Protocol version sent in the handshake, servers answer a status request whatever it is
+const PROTOCOL_VERSION: i32 = -1;
+/// Largest response accepted, real ones with a favicon are well under this
+const MAX_PACKET_LEN: i32 = 1 << 21;
+
+/// What a server reports about itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStatus {
+    /// eg. 1.16.4
+    pub version: String,
+    /// protocol number of version, eg. 754
+    pub protocol: i64,
+    /// message of the day with formatting codes removed
+    pub motd: String,
+    pub players_online: u32,
+    pub players_max: u32,
+    /// names of some of the players online, servers with many players only send a few
+    pub player_sample: Vec<String>
+}
+
+/// Pings the server at host:port.
+///     Errors if it can't be reached within PING_TIMEOUT or its answer isn't a status
+pub async fn status(host: &str, port: u16) -> Result<ServerStatus, Box<dyn Error>> {
+    let json = match tokio::time::timeout(PING_TIMEOUT, request_status(host, port)).await {
+        Ok(json) => json?,
+        Err(_) => return Err(format!("<{}:{}> didn't answer the ping within {:?}", host, port, PING_TIMEOUT).into())
+    };
+    parse_status(&json)
+}
+
+/// json of the status response
+async fn request_status(host: &str, port: u16) -> Result<String, Box<dyn Error>> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let mut handshake = vec![];
+    write_varint(&mut handshake, PROTOCOL_VERSION);
+    write_string(&mut handshake, host);
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, 1); //next state: status
+    stream.write_all(&packet(0x00, &handshake)).await?;
+    stream.write_all(&packet(0x00, &[])).await?;
+
+    let len = read_varint(&mut stream).await?;
+    if !(0..=MAX_PACKET_LEN).contains(&len) {
+        return Err(format!("status response claims to be {} bytes", len).into());
+    }
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).await?;
+
+    let mut body = body.as_slice();
+    let id = read_varint(&mut body).await?;
+    if id != 0x00 {
+        return Err(format!("expected a status response, got packet {:#04x}", id).into());
+    }
+    let json_len = read_varint(&mut body).await?;
+    match body.get(..json_len.max(0) as usize) {
+        Some(json) if json_len >= 0 => Ok(String::from_utf8(json.to_vec())?),
+        _ => Err("status response is shorter than its json".into())
+    }
+}
+
+fn parse_status(json: &str) -> Result<ServerStatus, Box<dyn Error>> {
+    let status: Value = serde_json::from_str(json)?;
+    let count = |field: &str| -> Result<u32, Box<dyn Error>> {
+        match status["players"][field].as_u64() {
+            Some(count) => Ok(count as u32),
+            None => Err(format!("status has no players.{}", field).into())
+        }
+    };
+    Ok(ServerStatus {
+        version: status["version"]["name"].as_str().unwrap_or_default().to_string(),
+        protocol: status["version"]["protocol"].as_i64().unwrap_or_default(),
+        motd: strip_formatting(&flatten_text(&status["description"])),
+        players_online: count("online")?,
+        players_max: count("max")?,
+        player_sample: status["players"]["sample"].as_array()
+            .iter()
+            .flat_map(|sample| sample.iter())
+            .filter_map(|player| player["name"].as_str())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+/// text of a chat component, which is a string or an object with text and extra components
+fn flatten_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(components) => components.iter().map(flatten_text).collect(),
+        Value::Object(fields) => {
+            let mut text = fields.get("text").map(flatten_text).unwrap_or_default();
+            if let Some(extra) = fields.get("extra") {
+                text.push_str(&flatten_text(extra));
+            }
+            text
+        },
+        _ => String::new()
+    }
+}
+
+/// text without § colour and style codes
+fn strip_formatting(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '§' {
+            chars.next();
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// id and data framed with their length
+pub(crate) fn packet(id: i32, data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    write_varint(&mut body, id);
+    body.extend_from_slice(data);
+    let mut framed = vec![];
+    write_varint(&mut framed, body.len() as i32);
+    framed.extend(body);
+    framed
+}
+
+/// value in 7 bit groups, least significant first, with the top bit set on all but the last
+pub(crate) fn write_varint(buf: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7f == 0 {
+            buf.push(value as u8);
+            return;
+        }
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+pub(crate) fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_varint(buf, value.len() as i32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+pub(crate) async fn read_varint<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<i32, Box<dyn Error>> {
+    let mut value: u32 = 0;
+    for i in 0..5 {
+        let byte = reader.read_u8().await?;
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value as i32);
+        }
+    }
+    Err("varint is longer than 5 bytes".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minecraft::fake_server::FakeServer;
+
+    #[test]
+    fn varints() {
+        for (value, bytes) in [(0, vec![0x00]), (127, vec![0x7f]), (300, vec![0xac, 0x02]), (-1, vec![0xff, 0xff, 0xff, 0xff, 0x0f])] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            assert_eq!(buf, bytes);
+            assert_eq!(tokio_test::block_on(read_varint(&mut buf.as_slice())).unwrap(), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_local_server() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+
+        let status = fake.server().status().await?;
+        assert_eq!(status, ServerStatus {
+            version: "1.16.4".to_string(),
+            protocol: 754,
+            motd: "Survival world".to_string(),
+            players_online: 1,
+            players_max: 20,
+            player_sample: vec!["Steve".to_string()]
+        });
+
+        fake.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(fake.server().status().await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn motds() {
+        assert_eq!(flatten_text(&Value::String("§lA §cMinecraft§r Server".to_string())), "§lA §cMinecraft§r Server");
+        assert_eq!(strip_formatting("§lA §cMinecraft§r Server"), "A Minecraft Server");
+        let component: Value = serde_json::from_str(r#"{"text": "", "extra": [{"text": "Sur"}, {"text": "vival", "extra": ["!"]}]}"#).unwrap();
+        assert_eq!(flatten_text(&component), "Survival!");
+    }
+
+    #[test]
+    fn status_without_players() {
+        assert!(parse_status(r#"{"version":{"name":"1.16.4","protocol":754}}"#).is_err());
+        assert!(parse_status("not json").is_err());
+        //a bare server sends a plain string motd and no sample
+        let status = parse_status(r#"{"description":"A Minecraft Server","players":{"max":20,"online":0}}"#).unwrap();
+        assert_eq!(status.motd, "A Minecraft Server");
+        assert!(status.player_sample.is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+//! Remote console, runs server commands like the server's own terminal does.
+//! See https://wiki.vg/RCON
+use std::error::Error;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Longest rcon waits for the server to answer before giving up
+const RCON_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest command the server accepts
+const MAX_COMMAND_LEN: usize = 1446;
+/// Largest packet the protocol allows
+const MAX_PACKET_LEN: i32 = 4096 + 10;
+
+pub(crate) const TYPE_RESPONSE: i32 = 0;
+pub(crate) const TYPE_COMMAND: i32 = 2;
+pub(crate) const TYPE_AUTH_RESPONSE: i32 = 2;
+pub(crate) const TYPE_LOGIN: i32 = 3;
+
+/// An authenticated rcon connection
+pub struct Rcon {
+    stream: TcpStream,
+    next_id: i32
+}
+
+impl Rcon {
+    /// Connects to host:port and logs in with password.
+    ///     Errors if the server can't be reached or rejects password
+    pub async fn connect(host: &str, port: u16, password: &str) -> Result<Rcon, Box<dyn Error>> {
+        let stream = match tokio::time::timeout(RCON_TIMEOUT, TcpStream::connect((host, port))).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(format!("couldn't connect to rcon at <{}:{}> within {:?}", host, port, RCON_TIMEOUT).into())
+        };
+        let mut rcon = Rcon {
+            stream,
+            next_id: 1
+        };
+        let id = rcon.send(TYPE_LOGIN, password).await?;
+        //some servers send an empty response before the auth response
+        loop {
+            let (response_id, kind, _) = rcon.receive().await?;
+            if kind != TYPE_AUTH_RESPONSE {
+                continue;
+            }
+            if response_id == -1 {
+                return Err("rcon rejected the password".into());
+            }
+            if response_id != id {
+                return Err(format!("rcon answered login {} with {}", id, response_id).into());
+            }
+            return Ok(rcon);
+        }
+    }
+
+    /// Runs command, eg. "list", returning what the server printed
+    pub async fn command(&mut self, command: &str) -> Result<String, Box<dyn Error>> {
+        if command.len() > MAX_COMMAND_LEN {
+            return Err(format!("rcon commands can be at most {} bytes", MAX_COMMAND_LEN).into());
+        }
+        let id = self.send(TYPE_COMMAND, command).await?;
+        //long output is split over several packets with nothing marking the last one, but the
+        //server answers in order so everything before the answer to this marker belongs to command
+        let marker = self.send(TYPE_RESPONSE, "").await?;
+        let mut output = String::new();
+        loop {
+            let (response_id, _, body) = self.receive().await?;
+            if response_id == marker {
+                return Ok(output);
+            }
+            if response_id == id {
+                output.push_str(&body);
+            }
+        }
+    }
+
+    /// Shows message in every player's chat
+    pub async fn say(&mut self, message: &str) -> Result<(), Box<dyn Error>> {
+        self.command(&format!("say {}", message)).await?;
+        Ok(())
+    }
+
+    /// Writes every loaded chunk to disk, so a copy of the world taken afterwards is complete
+    pub async fn save_all(&mut self) -> Result<String, Box<dyn Error>> {
+        self.command("save-all").await
+    }
+
+    /// Names of the players online
+    pub async fn players(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let output = self.command("list").await?;
+        //There are 2 of a max of 20 players online: Steve, Alex
+        match output.split_once(':') {
+            Some((_, names)) => Ok(names.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()),
+            None => Err(format!("couldn't read players from <{}>", output).into())
+        }
+    }
+
+    /// Saves the world and shuts the server down. The server closes the connection as it stops,
+    /// so this doesn't wait for an answer
+    pub async fn stop(mut self) -> Result<(), Box<dyn Error>> {
+        self.send(TYPE_COMMAND, "stop").await?;
+        Ok(())
+    }
+
+    /// sends a packet, returning its id
+    async fn send(&mut self, kind: i32, body: &str) -> Result<i32, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.stream.write_all(&encode(id, kind, body)).await?;
+        Ok(id)
+    }
+
+    /// (id, type, body) of the next packet
+    async fn receive(&mut self) -> Result<(i32, i32, String), Box<dyn Error>> {
+        match tokio::time::timeout(RCON_TIMEOUT, read_packet(&mut self.stream)).await {
+            Ok(packet) => packet,
+            Err(_) => Err(format!("rcon didn't answer within {:?}", RCON_TIMEOUT).into())
+        }
+    }
+}
+
+/// little endian length, id and type then the body and two nul bytes
+pub(crate) fn encode(id: i32, kind: i32, body: &str) -> Vec<u8> {
+    let len = (4 + 4 + body.len() + 2) as i32;
+    let mut packet = Vec::with_capacity(len as usize + 4);
+    packet.extend_from_slice(&len.to_le_bytes());
+    packet.extend_from_slice(&id.to_le_bytes());
+    packet.extend_from_slice(&kind.to_le_bytes());
+    packet.extend_from_slice(body.as_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet
+}
+
+pub(crate) async fn read_packet<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<(i32, i32, String), Box<dyn Error>> {
+    let len = reader.read_i32_le().await?;
+    if !(10..=MAX_PACKET_LEN).contains(&len) {
+        return Err(format!("rcon packet claims to be {} bytes", len).into());
+    }
+    let id = reader.read_i32_le().await?;
+    let kind = reader.read_i32_le().await?;
+    let mut body = vec![0; len as usize - 8];
+    reader.read_exact(&mut body).await?;
+    //drop the two trailing nul bytes
+    body.truncate(body.len() - 2);
+    Ok((id, kind, String::from_utf8_lossy(&body).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::minecraft::fake_server::{FakeServer, PASSWORD, LONG_COMMAND};
+
+    #[tokio::test]
+    async fn commands() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        let mut rcon = Rcon::connect("127.0.0.1", fake.rcon_port, PASSWORD).await?;
+
+        assert_eq!(rcon.players().await?, vec!["Steve"]);
+        rcon.say("saving").await?;
+        assert!(rcon.save_all().await?.contains("Saved the game"));
+        assert_eq!(fake.commands(), vec!["list", "say saving", "save-all"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_over_several_packets() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        let mut rcon = Rcon::connect("127.0.0.1", fake.rcon_port, PASSWORD).await?;
+
+        let output = rcon.command(LONG_COMMAND).await?;
+        assert_eq!(output, format!("{}b", "a".repeat(4096)));
+        //the connection is still in step afterwards
+        assert_eq!(rcon.players().await?, vec!["Steve"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_server() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        let rcon = Rcon::connect("127.0.0.1", fake.rcon_port, PASSWORD).await?;
+
+        rcon.stop().await?;
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(fake.stopped.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrong_password() {
+        let fake = FakeServer::start().await;
+        assert!(Rcon::connect("127.0.0.1", fake.rcon_port, "hunter3").await.is_err());
+    }
+
+    #[test]
+    fn packets_round_trip() -> Result<(), Box<dyn Error>> {
+        let packet = encode(7, TYPE_COMMAND, "say hi");
+        assert_eq!(&packet[..4], &16i32.to_le_bytes());
+        assert_eq!(tokio_test::block_on(read_packet(&mut packet.as_slice()))?, (7, TYPE_COMMAND, "say hi".to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,208 @@
+//! Stops a vm only once the server on it has saved the world and exited, stopping the vm under
+//! a running server can leave the world half written
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::minecraft::MinecraftServer;
+use crate::ssh::ssh_agent::SSHAgent;
+use crate::virtual_machine::ec2::waiter::Waiter;
+use crate::virtual_machine::vm::{VMCore, VMSnapshot};
+
+/// What is run to shut the server down
+#[derive(Debug, Clone)]
+pub enum PreStopHook {
+    /// save-all flush then stop over rcon
+    Rcon,
+    /// a command run over ssh, eg. sudo systemctl stop minecraft. Rcon is usually firewalled,
+    /// so the command should only return once the server has exited like systemctl stop does
+    Ssh {
+        command: String,
+        user: String,
+        key_path: PathBuf
+    }
+}
+
+/// How to shut a server down before stopping its vm.
+/// Defaults to rcon, waiting up to 2 minutes for the server to exit and no snapshot
+#[derive(Debug, Clone)]
+pub struct GracefulStop {
+    pub hook: PreStopHook,
+    /// how often and how long to check whether the server has exited
+    pub waiter: Waiter,
+    /// whether to snapshot the world once the server has exited
+    pub snapshot: bool,
+    /// volume the world is on, None snapshots every volume
+    pub world_volume_id: Option<String>
+}
+
+impl Default for GracefulStop {
+    fn default() -> Self {
+        GracefulStop {
+            hook: PreStopHook::Rcon,
+            waiter: Waiter::new(Duration::from_secs(2), Duration::from_secs(2 * 60)),
+            snapshot: false,
+            world_volume_id: None
+        }
+    }
+}
+
+impl GracefulStop {
+    /// Shuts server down with the hook, waits for it to exit, snapshots the world if asked to
+    /// and then stops vm, returning the ids of any snapshots.
+    /// The ssh hook always runs. Rcon is skipped only for a server known to have exited, see exit_state.
+    ///     Errors without stopping vm if the hook fails, the server is still up after waiter.timeout
+    ///     or it can't be told whether a server that rcon can't reach is running
+    pub async fn stop_gracefully<V>(&self, vm: &mut V, server: &MinecraftServer) -> Result<Vec<String>, Box<dyn Error>>
+        where V: VMCore + VMSnapshot + Send + Sync {
+        match self.exit_state(server).await {
+            //nothing to shut down if it has already exited or crashed
+            "exited" if matches!(self.hook, PreStopHook::Rcon) => (),
+            "unknown" if matches!(self.hook, PreStopHook::Rcon) =>
+                return Err(format!("can't tell whether the server on <{}> is running, not stopping the vm", server.host).into()),
+            _ => self.run_hook(server).await?
+        }
+        let exited = self.waiter.wait_for("exited", &["running", "saving", "unknown"], || async {
+            Ok(self.exit_state(server).await.to_string())
+        }).await;
+        if let Err(e) = exited {
+            return Err(format!("server on <{}> didn't exit, not stopping the vm: {}", server.host, e).into());
+        }
+
+        let snapshot_ids = if self.snapshot {
+            let description = format!("world of the server on {}", server.host);
+            vm.snapshot(self.world_volume_id.as_deref(), &description).await?
+        } else {
+            vec![]
+        };
+        vm.stop().await?;
+        Ok(snapshot_ids)
+    }
+
+    /// Running while the ping answers, saving while only rcon is open, as the ping stops as soon
+    /// as the server starts shutting down and rcon closes once the world is saved. Exited once
+    /// both refuse connections, or unknown if they can't be reached, eg. firewalled. The ssh
+    /// hook's command only returns once the server has exited so it counts unknown as exited
+    async fn exit_state(&self, server: &MinecraftServer) -> &'static str {
+        if server.status().await.is_ok() {
+            "running"
+        } else if server.rcon_listening().await {
+            "saving"
+        } else if matches!(self.hook, PreStopHook::Ssh { .. }) || server.is_down().await {
+            "exited"
+        } else {
+            "unknown"
+        }
+    }
+
+    async fn run_hook(&self, server: &MinecraftServer) -> Result<(), Box<dyn Error>> {
+        match &self.hook {
+            PreStopHook::Rcon => {
+                let mut rcon = server.rcon().await?;
+                //flush waits for the world to be written, plain save-all only queues it
+                rcon.command("save-all flush").await?;
+                rcon.stop().await
+            },
+            PreStopHook::Ssh { command, user, key_path } => {
+                let (host, remote_command, user, key_path) = (server.host.clone(), command.clone(), user.clone(), key_path.clone());
+                //ssh2 blocks
+                let run = tokio::task::spawn_blocking(move || {
+                    SSHAgent::connect(&host, &user, &key_path)
+                        .and_then(|agent| agent.exec(&remote_command))
+                        .map_err(|e| e.to_string())
+                });
+                match run.await? {
+                    Ok(output) if output.success() => Ok(()),
+                    Ok(output) => Err(format!("<{}> exited with {}: {}", command, output.exit_status, output.stdout.trim()).into()),
+                    Err(e) => Err(e.into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minecraft::fake_server::FakeServer;
+    use crate::virtual_machine::mock::MockVM;
+
+    fn graceful() -> GracefulStop {
+        GracefulStop {
+            waiter: Waiter::new(Duration::from_millis(10), Duration::from_secs(5)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn saves_and_stops_server_first() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        let mut vm = MockVM::running("127.0.0.1");
+        let snapshotting = GracefulStop {
+            snapshot: true,
+            world_volume_id: Some("vol-0123".to_string()),
+            ..graceful()
+        };
+
+        assert_eq!(snapshotting.stop_gracefully(&mut vm, &fake.server()).await?, vec!["snap-vol-0123"]);
+        assert_eq!(fake.commands(), vec!["save-all flush", "stop"]);
+        assert_eq!(vm.calls, vec!["stop"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_already_exited() -> Result<(), Box<dyn Error>> {
+        let fake = FakeServer::start().await;
+        fake.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut vm = MockVM::running("127.0.0.1");
+
+        assert!(graceful().stop_gracefully(&mut vm, &fake.server()).await?.is_empty());
+        assert!(fake.commands().is_empty());
+        assert_eq!(vm.calls, vec!["stop"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_world_to_be_saved() {
+        let fake = FakeServer::start().await;
+        //stops answering the ping but never finishes exiting
+        fake.exits.store(false, std::sync::atomic::Ordering::SeqCst);
+        let mut vm = MockVM::running("127.0.0.1");
+        let impatient = GracefulStop {
+            waiter: Waiter::new(Duration::from_millis(10), Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let e = impatient.stop_gracefully(&mut vm, &fake.server()).await.err().unwrap();
+        assert!(e.to_string().contains("<saving>"), "{}", e);
+        assert_eq!(fake.commands(), vec!["save-all flush", "stop"]);
+        assert!(vm.calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_isnt_exited() {
+        let fake = FakeServer::start().await;
+        //up but the ping is lost on the way and rcon is firewalled off, here closed
+        fake.ping_blocked.store(true, std::sync::atomic::Ordering::SeqCst);
+        let mut server = fake.server();
+        server.rcon_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut vm = MockVM::running("127.0.0.1");
+
+        let e = graceful().stop_gracefully(&mut vm, &server).await.err().unwrap();
+        assert!(e.to_string().contains("can't tell whether the server"), "{}", e);
+        assert!(fake.commands().is_empty());
+        assert!(vm.calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_running_if_the_server_cant_be_shut_down() {
+        let fake = FakeServer::start().await;
+        let mut vm = MockVM::running("127.0.0.1");
+        let mut server = fake.server();
+        server.rcon_password = Some("hunter3".to_string());
+
+        assert!(graceful().stop_gracefully(&mut vm, &server).await.is_err());
+        assert!(vm.calls.is_empty());
+        assert_eq!(vm.state, "running");
+    }
+}
@@ -0,0 +1,227 @@
+//! Cron expressions: minute hour day-of-month month day-of-week, eg. `0 18 * * mon-fri` for 6pm on weekdays.
+//! Fields take `*`, numbers, names for months and weekdays, ranges `a-b`, steps `*/n` or `a-b/n` and lists `a,b`
+extern crate chrono;
+extern crate chrono_tz;
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use self::chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use self::chrono_tz::Tz;
+
+const MONTHS: &[&str] = &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+/// Furthest matches are searched for, so an expression that never matches (eg. 30 Feb) gives up
+const SEARCH_DAYS: i64 = 366;
+
+/// A parsed cron expression. Like cron, when both day of month and day of week are restricted
+/// a day matching either matches
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expression: String,
+    /// bit n set if n matches
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// sunday is 0
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool
+}
+
+impl FromStr for Cron {
+    type Err = Box<dyn Error>;
+
+    fn from_str(expression: &str) -> Result<Cron, Box<dyn Error>> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("expected minute hour day month weekday in <{}>", expression).into());
+        }
+        let weekdays = parse_field(fields[4], 0, 7, WEEKDAYS)?;
+        Ok(Cron {
+            expression: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, MONTHS)?,
+            //7 is sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            days_restricted: !fields[2].starts_with('*'),
+            weekdays_restricted: !fields[4].starts_with('*')
+        })
+    }
+}
+
+impl Cron {
+    /// whether the minute time is in matches, in time's own time zone
+    pub fn matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        self.hour_matches(time) && bit(self.minutes, time.minute())
+    }
+
+    /// whether the day and hour of time match, ignoring the minute
+    fn hour_matches<T: Datelike + Timelike>(&self, time: &T) -> bool {
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday
+        };
+        day_matches && bit(self.months, time.month()) && bit(self.hours, time.hour())
+    }
+
+    /// The latest minute after after and no later than until that matches in timezone
+    pub fn last_between(&self, after: DateTime<Utc>, until: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        let after = after.max(until - Duration::days(SEARCH_DAYS));
+        let mut time = truncate_to_minute(until);
+        while time > after {
+            let local = time.with_timezone(&timezone);
+            if !self.hour_matches(&local) {
+                //back to the last minute of the hour before
+                time -= Duration::minutes(local.minute() as i64 + 1);
+                continue;
+            }
+            if bit(self.minutes, local.minute()) {
+                return Some(time);
+            }
+            time -= Duration::minutes(1);
+        }
+        None
+    }
+
+    /// The first minute after after that matches in timezone
+    pub fn next_after(&self, after: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        let until = after + Duration::days(SEARCH_DAYS);
+        let mut time = truncate_to_minute(after) + Duration::minutes(1);
+        while time <= until {
+            let local = time.with_timezone(&timezone);
+            if !self.hour_matches(&local) {
+                //on to the start of the next hour
+                time += Duration::minutes(60 - local.minute() as i64);
+                continue;
+            }
+            if bit(self.minutes, local.minute()) {
+                return Some(time);
+            }
+            time += Duration::minutes(1);
+        }
+        None
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+fn bit(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn truncate_to_minute(time: DateTime<Utc>) -> DateTime<Utc> {
+    time - Duration::seconds(time.second() as i64) - Duration::nanoseconds(time.nanosecond() as i64)
+}
+
+/// bitset of the values field matches, names being numbered from min
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, Box<dyn Error>> {
+    let value = |value: &str| -> Result<u32, Box<dyn Error>> {
+        let lower = value.to_lowercase();
+        let parsed = match names.iter().position(|name| *name == lower) {
+            Some(index) => index as u32 + min,
+            None => match value.parse() {
+                Ok(parsed) => parsed,
+                Err(_) => return Err(format!("<{}> isn't a number or name in <{}>", value, field).into())
+            }
+        };
+        if !(min..=max).contains(&parsed) {
+            return Err(format!("<{}> is outside {}-{} in <{}>", value, min, max, field).into());
+        }
+        Ok(parsed)
+    };
+
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("step <{}> isn't a positive number in <{}>", step, field).into())
+            },
+            None => (part, 1)
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            //a single value with a step runs to the end, like 5/15
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?)
+        };
+        if start > end {
+            return Err(format!("range <{}> runs backwards in <{}>", range, field).into());
+        }
+        for matched in (start..=end).step_by(step as usize) {
+            set |= 1 << matched;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn fields() -> Result<(), Box<dyn Error>> {
+        let cron: Cron = "*/15 18-23 * * MON-fri".parse()?;
+        assert_eq!(cron.minutes, 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(cron.hours, 0b1111_1100_0000_0000_0000_0000);
+        assert_eq!(cron.weekdays, 0b011_1110);
+        assert_eq!(cron.to_string(), "*/15 18-23 * * MON-fri");
+
+        let cron: Cron = "0 9 1,15 jan-mar/2 7".parse()?;
+        assert_eq!(cron.days, 1 << 1 | 1 << 15);
+        assert_eq!(cron.months, 1 << 1 | 1 << 3);
+        assert_eq!(cron.weekdays, 1);
+
+        for invalid in ["0 18 * *", "60 * * * *", "* * 0 * *", "* * * * funday", "5-1 * * * *", "*/0 * * * *"] {
+            assert!(invalid.parse::<Cron>().is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn matching_days() -> Result<(), Box<dyn Error>> {
+        //2020-11-13 was a friday
+        let friday_13th = Utc.with_ymd_and_hms(2020, 11, 13, 18, 0, 0).unwrap();
+        assert!("0 18 * * fri".parse::<Cron>()?.matches(&friday_13th));
+        assert!(!"0 18 * * sat,sun".parse::<Cron>()?.matches(&friday_13th));
+        //restricting both matches either
+        assert!("0 18 1 * fri".parse::<Cron>()?.matches(&friday_13th));
+        assert!(!"0 18 1 * *".parse::<Cron>()?.matches(&friday_13th));
+        Ok(())
+    }
+
+    #[test]
+    fn searching_in_a_time_zone() -> Result<(), Box<dyn Error>> {
+        let evenings: Cron = "0 18 * * *".parse()?;
+        let london: Tz = "Europe/London".parse()?;
+        //british summer time, 6pm in london is 5pm utc
+        let next = evenings.next_after(utc("2020-06-01T12:00:00Z"), london);
+        assert_eq!(next, Some(utc("2020-06-01T17:00:00Z")));
+        let last = evenings.last_between(utc("2020-05-01T00:00:00Z"), utc("2020-06-01T12:00:00Z"), london);
+        assert_eq!(last, Some(utc("2020-05-31T17:00:00Z")));
+        //after is excluded, until isn't
+        assert_eq!(evenings.last_between(utc("2020-05-31T17:00:00Z"), utc("2020-06-01T12:00:00Z"), london), None);
+        assert_eq!(evenings.last_between(utc("2020-05-31T16:00:00Z"), utc("2020-05-31T17:00:30Z"), london), last);
+
+        let never: Cron = "0 0 30 feb *".parse()?;
+        assert_eq!(never.next_after(utc("2020-06-01T12:00:00Z"), Tz::UTC), None);
+        Ok(())
+    }
+}
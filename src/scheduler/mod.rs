@@ -0,0 +1,400 @@
+//! Starts and stops vms on cron schedules, eg. only running a server on evenings and weekends.
+//! Schedules come from the config's [schedules], see config::ScheduleConfig
+extern crate chrono;
+extern crate chrono_tz;
+
+pub mod cron;
+pub mod state;
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use self::chrono::{DateTime, Utc};
+use self::chrono_tz::Tz;
+
+use crate::scheduler::cron::Cron;
+use crate::scheduler::state::ScheduleState;
+use crate::virtual_machine::vm::VMCore;
+
+/// What a schedule does to its vms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Start,
+    Stop
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Start => write!(f, "start"),
+            Event::Stop => write!(f, "stop")
+        }
+    }
+}
+
+/// When to start and stop the vms target matches
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub name: String,
+    /// instance id, key=value tag or instance in the config, as on the command line
+    pub target: String,
+    pub start: Option<Cron>,
+    pub stop: Option<Cron>,
+    /// time zone start and stop are in
+    pub timezone: Tz
+}
+
+impl Schedule {
+    /// The latest event after after and no later than until, a stop winning if both happen at once
+    pub fn last_event(&self, after: DateTime<Utc>, until: DateTime<Utc>) -> Option<(Event, DateTime<Utc>)> {
+        let start = self.start.as_ref().and_then(|cron| cron.last_between(after, until, self.timezone));
+        let stop = self.stop.as_ref().and_then(|cron| cron.last_between(after, until, self.timezone));
+        match (start, stop) {
+            (Some(start), Some(stop)) if start > stop => Some((Event::Start, start)),
+            (_, Some(stop)) => Some((Event::Stop, stop)),
+            (Some(start), None) => Some((Event::Start, start)),
+            (None, None) => None
+        }
+    }
+
+    /// The first event after after
+    pub fn next_event(&self, after: DateTime<Utc>) -> Option<(Event, DateTime<Utc>)> {
+        let start = self.start.as_ref().and_then(|cron| cron.next_after(after, self.timezone));
+        let stop = self.stop.as_ref().and_then(|cron| cron.next_after(after, self.timezone));
+        match (start, stop) {
+            (Some(start), Some(stop)) if start < stop => Some((Event::Start, start)),
+            (_, Some(stop)) => Some((Event::Stop, stop)),
+            (Some(start), None) => Some((Event::Start, start)),
+            (None, None) => None
+        }
+    }
+}
+
+/// (schedule index, event, when it was due) of an event that hasn't fired yet
+pub type Due = (usize, Event, DateTime<Utc>);
+
+/// Where the scheduler gets the time from, so tests don't have to wait for it
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system's clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// An event that was due and what happened to each vm its target matched
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fired {
+    pub schedule: String,
+    pub target: String,
+    pub event: Event,
+    /// when the event was due, which is earlier than it fired if the scheduler wasn't running
+    pub at: DateTime<Utc>,
+    /// the new state of each vm, or why it or target couldn't be started or stopped
+    pub results: Vec<Result<String, String>>
+}
+
+/// Fires the events of schedules as they come due. Only the latest event since a schedule was last
+/// checked fires, so a scheduler that was down over a start and a stop leaves vms stopped.
+/// Events from before a schedule is first checked never fire, events that fail are retried next tick
+pub struct Scheduler<C: Clock> {
+    pub schedules: Vec<Schedule>,
+    pub clock: C,
+    state: ScheduleState
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(schedules: Vec<Schedule>, clock: C, state: ScheduleState) -> Scheduler<C> {
+        Scheduler {
+            schedules,
+            clock,
+            state
+        }
+    }
+
+    /// Events due between each schedule being last checked and now, none for a schedule that has
+    /// never been checked
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<Due> {
+        self.schedules.iter()
+            .enumerate()
+            .filter_map(|(index, schedule)| {
+                let checked = self.state.checked(&schedule.name)?;
+                let (event, at) = schedule.last_event(checked, now)?;
+                Some((index, event, at))
+            })
+            .collect()
+    }
+
+    /// Starts or stops the vms of every schedule with an event due, finding them with resolve.
+    /// vms already in the state an event wants are left alone, so an event that failed is retried
+    /// next tick by leaving its schedule checked up to before it. Everything else is checked up to now.
+    ///     Errors if the state can't be saved, failing to start or stop vms is in the results
+    pub async fn tick<V, F, Fut>(&mut self, resolve: F) -> Result<Vec<Fired>, Box<dyn Error>>
+        where V: VMCore, F: Fn(String) -> Fut, Fut: Future<Output = Result<Vec<V>, Box<dyn Error>>> {
+        let now = self.clock.now();
+        let mut fired = vec![];
+        for (index, event, at) in self.due(now) {
+            let schedule = &self.schedules[index];
+            let results = match resolve(schedule.target.clone()).await {
+                Ok(mut vms) => {
+                    let mut results = vec![];
+                    for vm in vms.iter_mut() {
+                        results.push(fire(vm, event).await.map_err(|e| e.to_string()));
+                    }
+                    results
+                },
+                Err(e) => vec![Err(e.to_string())]
+            };
+            fired.push(Fired {
+                schedule: schedule.name.clone(),
+                target: schedule.target.clone(),
+                event,
+                at,
+                results
+            });
+        }
+        for schedule in &self.schedules {
+            let failed = fired.iter()
+                .any(|fired| fired.schedule == schedule.name && fired.results.iter().any(Result::is_err));
+            if !failed {
+                self.state.set_checked(&schedule.name, now);
+            }
+        }
+        self.state.save()?;
+        Ok(fired)
+    }
+
+    /// Ticks every interval until the state can't be saved, passing everything fired to on_fired
+    pub async fn run<V, F, Fut>(&mut self, resolve: F, interval: Duration, mut on_fired: impl FnMut(&Fired)) -> Result<(), Box<dyn Error>>
+        where V: VMCore, F: Fn(String) -> Fut, Fut: Future<Output = Result<Vec<V>, Box<dyn Error>>> {
+        loop {
+            for fired in self.tick(&resolve).await? {
+                on_fired(&fired);
+            }
+            tokio::time::delay_for(interval).await;
+        }
+    }
+}
+
+/// starts or stops vm, returning the state it's in afterwards
+async fn fire<V: VMCore>(vm: &mut V, event: Event) -> Result<String, Box<dyn Error>> {
+    let state = match vm.status().await {
+        Some(state) => state,
+        None => return Err("couldn't find vm".into())
+    };
+    match (event, state.as_str()) {
+        (Event::Start, "stopped") => vm.start().await?,
+        (Event::Stop, "running") => vm.stop().await?,
+        (Event::Start, "running") | (Event::Stop, "stopped") => return Ok(state),
+        _ => return Err(format!("can't {} a vm that is {}", event, state).into())
+    };
+    match vm.status().await {
+        Some(state) => Ok(state),
+        None => Err(format!("couldn't find vm after {}", event).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use async_trait::async_trait;
+    use crate::virtual_machine::mock::MockVM;
+
+    /// a clock that only moves when told to
+    struct MockClock(Mutex<DateTime<Utc>>);
+
+    impl MockClock {
+        fn at(time: &str) -> MockClock {
+            MockClock(Mutex::new(utc(time)))
+        }
+
+        fn set(&self, time: &str) {
+            *self.0.lock().unwrap() = utc(time);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// a MockVM the test keeps hold of while the scheduler starts and stops it, with stops
+    /// failing while the flag is set
+    #[derive(Clone)]
+    struct SharedVM(Arc<tokio::sync::Mutex<MockVM>>, Arc<AtomicBool>);
+
+    #[async_trait]
+    impl VMCore for SharedVM {
+        async fn retrieve(_instance_id: &str, _role_arn: &str) -> Option<Self> {
+            None
+        }
+
+        async fn status(&self) -> Option<String> {
+            self.0.lock().await.status().await
+        }
+
+        async fn stop(&mut self) -> Result<String, Box<dyn Error>> {
+            if self.1.load(Ordering::SeqCst) {
+                return Err("the api is down".into());
+            }
+            self.0.lock().await.stop().await
+        }
+
+        async fn start(&mut self) -> Result<String, Box<dyn Error>> {
+            self.0.lock().await.start().await
+        }
+    }
+
+    impl SharedVM {
+        fn new(vm: MockVM) -> SharedVM {
+            SharedVM(Arc::new(tokio::sync::Mutex::new(vm)), Arc::new(AtomicBool::new(false)))
+        }
+
+        async fn calls(&self) -> Vec<String> {
+            self.0.lock().await.calls.clone()
+        }
+
+        /// ticks scheduler with every target resolving to this vm
+        async fn tick<C: Clock>(&self, scheduler: &mut Scheduler<C>) -> Vec<Fired> {
+            scheduler.tick(|_| {
+                let vm = self.clone();
+                async move { Ok(vec![vm]) }
+            }).await.unwrap()
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc)
+    }
+
+    /// on from 6pm on weekdays until 11pm, london time
+    fn evenings() -> Schedule {
+        Schedule {
+            name: "evenings".to_string(),
+            target: "minecraft=survival".to_string(),
+            start: Some("0 18 * * mon-fri".parse().unwrap()),
+            stop: Some("0 23 * * *".parse().unwrap()),
+            timezone: "Europe/London".parse().unwrap()
+        }
+    }
+
+    fn state_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rust_ec2_{}_{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn latest_event_wins() {
+        let schedule = evenings();
+        //friday 13th november 2020, london is on utc in winter
+        let (event, at) = schedule.last_event(utc("2020-11-13T12:00:00Z"), utc("2020-11-13T19:00:00Z")).unwrap();
+        assert_eq!((event, at), (Event::Start, utc("2020-11-13T18:00:00Z")));
+        let (event, _) = schedule.last_event(utc("2020-11-13T12:00:00Z"), utc("2020-11-14T12:00:00Z")).unwrap();
+        assert_eq!(event, Event::Stop);
+        assert_eq!(schedule.last_event(utc("2020-11-14T12:00:00Z"), utc("2020-11-14T19:00:00Z")), None);
+        //nothing starts on the weekend but it still stops every night
+        assert_eq!(schedule.next_event(utc("2020-11-14T00:00:00Z")), Some((Event::Stop, utc("2020-11-14T23:00:00Z"))));
+        assert_eq!(schedule.next_event(utc("2020-11-15T23:00:00Z")), Some((Event::Start, utc("2020-11-16T18:00:00Z"))));
+    }
+
+    #[tokio::test]
+    async fn fires_when_due() -> Result<(), Box<dyn Error>> {
+        let vm = SharedVM::new(MockVM::stopped());
+        let mut scheduler = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T17:59:00Z"), ScheduleState::default());
+
+        //the first check only records the time
+        assert!(vm.tick(&mut scheduler).await.is_empty());
+        scheduler.clock.set("2020-11-13T18:00:30Z");
+        assert_eq!(vm.tick(&mut scheduler).await, vec![Fired {
+            schedule: "evenings".to_string(),
+            target: "minecraft=survival".to_string(),
+            event: Event::Start,
+            at: utc("2020-11-13T18:00:00Z"),
+            results: vec![Ok("running".to_string())]
+        }]);
+        scheduler.clock.set("2020-11-13T18:05:00Z");
+        assert!(vm.tick(&mut scheduler).await.is_empty());
+        scheduler.clock.set("2020-11-13T23:00:00Z");
+        assert_eq!(vm.tick(&mut scheduler).await[0].event, Event::Stop);
+        assert_eq!(vm.calls().await, vec!["start", "stop"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn restarting_doesnt_fire_again() -> Result<(), Box<dyn Error>> {
+        let path = state_path("schedule_restart");
+        let vm = SharedVM::new(MockVM::stopped());
+        let mut scheduler = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T17:59:00Z"), ScheduleState::load(&path)?);
+        vm.tick(&mut scheduler).await;
+        scheduler.clock.set("2020-11-13T18:01:00Z");
+        assert_eq!(vm.tick(&mut scheduler).await.len(), 1);
+
+        let mut restarted = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T18:02:00Z"), ScheduleState::load(&path)?);
+        assert!(vm.tick(&mut restarted).await.is_empty());
+        //events while it was down still fire once it's back
+        restarted.clock.set("2020-11-13T23:30:00Z");
+        assert_eq!(vm.tick(&mut restarted).await[0].event, Event::Stop);
+        assert_eq!(vm.calls().await, vec!["start", "stop"]);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_missed_event_fires() -> Result<(), Box<dyn Error>> {
+        let vm = SharedVM::new(MockVM::stopped());
+        let mut scheduler = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T17:00:00Z"), ScheduleState::default());
+        vm.tick(&mut scheduler).await;
+
+        //down over friday's start and stop, the vm is already where the stop leaves it
+        scheduler.clock.set("2020-11-14T12:00:00Z");
+        let fired = vm.tick(&mut scheduler).await;
+        assert_eq!((fired[0].event, fired[0].results.clone()), (Event::Stop, vec![Ok("stopped".to_string())]));
+        assert!(vm.calls().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_events_are_retried() -> Result<(), Box<dyn Error>> {
+        let path = state_path("schedule_retry");
+        let vm = SharedVM::new(MockVM::running("1.2.3.4"));
+        let mut scheduler = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T22:59:00Z"), ScheduleState::load(&path)?);
+        vm.tick(&mut scheduler).await;
+
+        vm.1.store(true, Ordering::SeqCst);
+        scheduler.clock.set("2020-11-13T23:00:30Z");
+        assert_eq!(vm.tick(&mut scheduler).await[0].results, vec![Err("the api is down".to_string())]);
+
+        //tried again, even after a restart
+        vm.1.store(false, Ordering::SeqCst);
+        let mut restarted = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T23:01:00Z"), ScheduleState::load(&path)?);
+        let fired = vm.tick(&mut restarted).await;
+        assert_eq!((fired[0].event, fired[0].results.clone()), (Event::Stop, vec![Ok("stopped".to_string())]));
+        assert_eq!(vm.calls().await, vec!["stop"]);
+        restarted.clock.set("2020-11-13T23:02:00Z");
+        assert!(vm.tick(&mut restarted).await.is_empty());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported() -> Result<(), Box<dyn Error>> {
+        let mut scheduler = Scheduler::new(vec![evenings()], MockClock::at("2020-11-13T17:59:00Z"), ScheduleState::default());
+        let unresolvable = |target: String| async move {
+            Err::<Vec<MockVM>, Box<dyn Error>>(format!("no instances match <{}>", target).into())
+        };
+        scheduler.tick(unresolvable).await?;
+        scheduler.clock.set("2020-11-13T18:00:00Z");
+        let fired = scheduler.tick(unresolvable).await?;
+        assert_eq!(fired[0].results, vec![Err("no instances match <minecraft=survival>".to_string())]);
+        Ok(())
+    }
+}
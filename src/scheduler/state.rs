@@ -0,0 +1,101 @@
+//! How far each schedule has been checked, kept in a json file so a restarted scheduler carries on
+//! from where it stopped instead of firing the same event again
+extern crate chrono;
+extern crate dirs;
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use self::chrono::{DateTime, Utc};
+use self::serde::{Deserialize, Serialize};
+
+/// Time each schedule was last checked up to, by schedule name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScheduleState {
+    /// None keeps the state in memory only
+    path: Option<PathBuf>,
+    checked: BTreeMap<String, DateTime<Utc>>
+}
+
+/// what is kept in the file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    checked: BTreeMap<String, DateTime<Utc>>
+}
+
+impl ScheduleState {
+    /// State saved at path, empty if nothing has been saved there yet.
+    ///     Errors if the file can't be read or isn't a schedule state
+    pub fn load(path: &Path) -> Result<ScheduleState, Box<dyn Error>> {
+        let file = if path.exists() {
+            match serde_json::from_str(&fs::read_to_string(path)?) {
+                Ok(file) => file,
+                Err(e) => return Err(format!("invalid schedule state <{}>: {}", path.display(), e).into())
+            }
+        } else {
+            StateFile::default()
+        };
+        Ok(ScheduleState {
+            path: Some(path.to_path_buf()),
+            checked: file.checked
+        })
+    }
+
+    /// ~/.local/share/rust_ec2/schedule.json on linux, see dirs::data_dir for other platforms
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("rust_ec2").join("schedule.json"))
+    }
+
+    /// when the schedule called name was last checked, None if it never has been
+    pub fn checked(&self, name: &str) -> Option<DateTime<Utc>> {
+        self.checked.get(name).cloned()
+    }
+
+    /// records that the schedule called name has been checked up to time, see save
+    pub fn set_checked(&mut self, name: &str, time: DateTime<Utc>) {
+        self.checked.insert(name.to_string(), time);
+    }
+
+    /// Writes the state to its file, if it has one
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let file = StateFile { checked: self.checked.clone() };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        //written next to the state then renamed over it so a failed write can't lose it
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&file)?)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_and_loaded() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("rust_ec2_schedule_state_{}.json", std::process::id()));
+        let time = DateTime::parse_from_rfc3339("2020-11-13T18:00:00Z")?.with_timezone(&Utc);
+
+        let mut state = ScheduleState::load(&path)?;
+        assert_eq!(state.checked("evenings"), None);
+        state.set_checked("evenings", time);
+        state.save()?;
+        assert_eq!(ScheduleState::load(&path)?.checked("evenings"), Some(time));
+
+        fs::write(&path, r#"{"checked": {"evenings": "6pm"}}"#)?;
+        assert!(ScheduleState::load(&path).is_err());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1 +1,2 @@
-pub mod ssh_agent;
\ No newline at end of file
+pub mod ssh_agent;
+pub mod parallel;
\ No newline at end of file
@@ -0,0 +1,149 @@
+extern crate futures;
+
+use std::path::{Path, PathBuf};
+
+use self::futures::future::join_all;
+use tokio::sync::Semaphore;
+
+use crate::ssh::ssh_agent::{SSHAgent, CommandOutput, SSH_USER};
+use crate::virtual_machine::vm::VMNetwork;
+
+/// Result of running a command on one host
+#[derive(Debug)]
+pub struct HostOutput {
+    pub host: String,
+    /// Output of the command, or why it couldn't be run
+    pub result: Result<CommandOutput, String>
+}
+
+impl HostOutput {
+    /// true if the command couldn't be run or exited with a non zero status
+    pub fn failed(&self) -> bool {
+        match &self.result {
+            Ok(output) => !output.success(),
+            Err(_) => true
+        }
+    }
+}
+
+/// Outputs of a command run on several hosts, in the order the hosts were given
+#[derive(Debug)]
+pub struct ParallelSummary {
+    pub outputs: Vec<HostOutput>
+}
+
+impl ParallelSummary {
+    /// names of hosts where the command ran and exited with status 0
+    pub fn succeeded(&self) -> Vec<&str> {
+        self.outputs.iter()
+            .filter(|output| !output.failed())
+            .map(|output| output.host.as_str())
+            .collect()
+    }
+    /// names of hosts where the command couldn't be run or exited with a non zero status
+    pub fn failed(&self) -> Vec<&str> {
+        self.outputs.iter()
+            .filter(|output| output.failed())
+            .map(|output| output.host.as_str())
+            .collect()
+    }
+    pub fn all_succeeded(&self) -> bool {
+        self.outputs.iter().all(|output| !output.failed())
+    }
+}
+
+/// Runs the same ssh command on many VMs at once, with at most max_parallel connections open
+pub struct ParallelSSH {
+    pub key_path: PathBuf,
+    pub user: String,
+    pub max_parallel: usize
+}
+
+impl ParallelSSH {
+    pub fn new(key_path: &Path, max_parallel: usize) -> ParallelSSH {
+        ParallelSSH {
+            key_path: key_path.to_path_buf(),
+            user: SSH_USER.to_string(),
+            max_parallel
+        }
+    }
+
+    /// Runs command on every (host name, vm) pair in hosts.
+    /// A host failing doesn't stop the command from running on the others
+    pub async fn execute<V: VMNetwork + Sync>(&self, hosts: &[(String, V)], command: &str) -> ParallelSummary {
+        let semaphore = Semaphore::new(self.max_parallel.max(1));
+
+        let runs = hosts.iter().map(|(host, vm)| {
+            let semaphore = &semaphore;
+            async move {
+                let _permit = semaphore.acquire().await;
+                let result = match vm.get_public_ip().await {
+                    Some(ip) => self.run_on(ip, command.to_string()).await,
+                    None => Err("tried to get ip but got None, is vm on?".to_string())
+                };
+                HostOutput {
+                    host: host.clone(),
+                    result
+                }
+            }
+        });
+
+        ParallelSummary {
+            outputs: join_all(runs).await
+        }
+    }
+
+    /// ssh2 blocks so each connection gets its own blocking thread
+    async fn run_on(&self, ip: String, command: String) -> Result<CommandOutput, String> {
+        let user = self.user.clone();
+        let key_path = self.key_path.clone();
+        let run = tokio::task::spawn_blocking(move || {
+            SSHAgent::connect(&ip, &user, &key_path)
+                .and_then(|agent| agent.exec(&command))
+                .map_err(|e| e.to_string())
+        });
+        match run.await {
+            Ok(result) => result,
+            Err(e) => Err(e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    struct Offline;
+
+    #[async_trait]
+    impl VMNetwork for Offline {
+        async fn get_public_ip(&self) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn offline_hosts_fail() {
+        let hosts = vec![("a".to_string(), Offline), ("b".to_string(), Offline)];
+        let pssh = ParallelSSH::new(Path::new("key.pem"), 1);
+
+        let summary = tokio_test::block_on(pssh.execute(&hosts, "uptime"));
+
+        assert_eq!(summary.failed(), vec!["a", "b"]);
+        assert!(summary.succeeded().is_empty());
+        assert!(!summary.all_succeeded());
+    }
+
+    #[test]
+    fn non_zero_exit_fails() {
+        let summary = ParallelSummary {
+            outputs: vec![
+                HostOutput { host: "ok".to_string(), result: Ok(CommandOutput { stdout: String::new(), exit_status: 0 }) },
+                HostOutput { host: "bad".to_string(), result: Ok(CommandOutput { stdout: String::new(), exit_status: 1 }) }
+            ]
+        };
+        assert_eq!(summary.succeeded(), vec!["ok"]);
+        assert_eq!(summary.failed(), vec!["bad"]);
+    }
+}
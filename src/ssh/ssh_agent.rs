@@ -1,50 +1,166 @@
 extern crate ssh2;
 
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
-use self::ssh2::{Session, Channel};
+use self::ssh2::Session;
 use std::error::Error;
 use crate::virtual_machine::vm::VMNetwork;
+use std::fs::{self, File};
 use std::path::Path;
-use std::io::Read;
+use std::io::{self, Read, Write};
+
+/// Default user for ubuntu images
+pub const SSH_USER: &str = "ubuntu";
+/// Default ssh port
+pub const SSH_PORT: u16 = 22;
+/// Longest connecting, the handshake and authenticating each wait for the host to answer
+pub const SSH_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct SSHAgent {
     session: Session
 }
 
+/// Output of a single command run over ssh
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub exit_status: i32
+}
+
+impl CommandOutput {
+    /// true if the command exited with status 0
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
 impl SSHAgent {
 
+    /// Connects to vm's public ip as SSH_USER.
+    ///     Errors if vm has no public ip, with its state if the vm reports network info
     pub async fn new(vm: &impl VMNetwork, key_path: &Path) -> Result<Self, Box<dyn Error>> {
-        let mut ssh_address = match vm.get_public_ip().await {
+        let ssh_address = match vm.get_public_ip().await {
             Some(ip) => ip,
-            None => panic!("tried to get ip but got None, is vm on?") //TODO proper error
+            None => {
+                let state = vm.network_info().await.ok().and_then(|info| info.state);
+                return Err(match state {
+                    Some(state) => format!("vm has no public ip, its state is <{}>", state),
+                    None => "vm has no public ip".to_string()
+                }.into());
+            }
         };
 
-        ssh_address.push_str(":22");
-
-        println!("{:?}", ssh_address);
+        Self::connect(&ssh_address, SSH_USER, key_path)
+    }
 
-        let tcp = TcpStream::connect(ssh_address).unwrap();
-        let mut sess = Session::new().unwrap();
+    /// Connects and authenticates to address:22 as user using the private key at key_path.
+    /// Blocks the current thread while connecting, for up to SSH_CONNECT_TIMEOUT at each step
+    pub fn connect(address: &str, user: &str, key_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let socket_address = match (address, SSH_PORT).to_socket_addrs()?.next() {
+            Some(socket_address) => socket_address,
+            None => return Err(format!("<{}> didn't resolve to an address", address).into())
+        };
+        let tcp = TcpStream::connect_timeout(&socket_address, SSH_CONNECT_TIMEOUT)?;
+        let mut sess = Session::new()?;
         sess.set_tcp_stream(tcp);
-        sess.handshake().unwrap();
-        sess.userauth_pubkey_file("ubuntu", None, &key_path, None).unwrap();
+        sess.set_timeout(SSH_CONNECT_TIMEOUT.as_millis() as u32);
+        sess.handshake()?;
+        sess.userauth_pubkey_file(user, None, key_path, None)?;
+        //commands can rightly go quiet for longer, eg. systemctl stop waiting for a server to exit
+        sess.set_timeout(0);
 
-        assert!(sess.authenticated());
+        if !sess.authenticated() {
+            return Err(format!("could not authenticate as <{}> to <{}>", user, address).into());
+        }
 
         Ok(SSHAgent{
             session: sess
         })
     }
 
-    pub async fn execute(&self, command: &str) -> String {
-        let mut channel = self.session.channel_session().unwrap();
-        channel.exec(command).unwrap();
-        let mut result_string = String::new();
-        channel.read_to_string(&mut result_string);
-        channel.wait_close();
+    /// Runs command, returning its output with the exit status appended.
+    ///     Errors if the command couldn't be run
+    pub async fn execute(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        let output = self.exec(command)?;
+        let mut result_string = output.stdout;
+        result_string.push_str(output.exit_status.to_string().as_ref());
+        Ok(result_string)
+    }
+
+    /// Runs command, returning its output and exit status separately.
+    /// Blocks the current thread until the command finishes
+    pub fn exec(&self, command: &str) -> Result<CommandOutput, Box<dyn Error>> {
+        let mut channel = self.session.channel_session()?;
+        channel.exec(command)?;
+        let mut stdout = String::new();
+        channel.read_to_string(&mut stdout)?;
+        channel.wait_close()?;
+
+        Ok(CommandOutput {
+            stdout,
+            exit_status: channel.exit_status()?
+        })
+    }
+
+    /// Copies the local file at local_path to remote_path with scp, keeping its permissions.
+    /// Returns the bytes copied. Blocks the current thread until the copy finishes
+    pub fn upload(&self, local_path: &Path, remote_path: &str) -> Result<u64, Box<dyn Error>> {
+        let metadata = fs::metadata(local_path)?;
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            (metadata.permissions().mode() & 0o777) as i32
+        };
+        #[cfg(not(unix))]
+        let mode = 0o644;
 
-        result_string.push_str(channel.exit_status().unwrap().to_string().as_ref());
-        result_string
+        let mut channel = self.session.scp_send(Path::new(remote_path), mode, metadata.len(), None)?;
+        let copied = io::copy(&mut File::open(local_path)?, &mut channel)?;
+        channel.send_eof()?;
+        channel.wait_eof()?;
+        channel.close()?;
+        channel.wait_close()?;
+        Ok(copied)
     }
-}
\ No newline at end of file
+
+    /// Copies remote_path to a file at local_path with scp, replacing it if it exists.
+    /// Returns the bytes copied. Blocks the current thread until the copy finishes
+    pub fn download(&self, remote_path: &str, local_path: &Path) -> Result<u64, Box<dyn Error>> {
+        let (mut channel, _stat) = self.session.scp_recv(Path::new(remote_path))?;
+        let mut local = File::create(local_path)?;
+        let copied = io::copy(&mut channel, &mut local)?;
+        local.flush()?;
+        channel.send_eof()?;
+        channel.wait_eof()?;
+        channel.close()?;
+        channel.wait_close()?;
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::virtual_machine::mock::MockVM;
+
+    /// only knows its ip, like most VMNetwork implementations
+    struct IpOnly;
+
+    #[async_trait]
+    impl VMNetwork for IpOnly {
+        async fn get_public_ip(&self) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn no_public_ip() {
+        let key_path = Path::new("key.pem");
+        let e = tokio_test::block_on(SSHAgent::new(&IpOnly, key_path)).err().unwrap();
+        assert_eq!(e.to_string(), "vm has no public ip");
+        let e = tokio_test::block_on(SSHAgent::new(&MockVM::stopped(), key_path)).err().unwrap();
+        assert_eq!(e.to_string(), "vm has no public ip, its state is <stopped>");
+    }
+}
@@ -0,0 +1,190 @@
+use std::error::Error;
+
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{AllocateAddressRequest, ReleaseAddressRequest, DescribeAddressesRequest, Address};
+use rusoto_ec2::{AssociateAddressRequest, DisassociateAddressRequest, Filter};
+
+use crate::virtual_machine::ec2::instance::Ec2Object;
+
+/// A static public ip that stays the same across instance stops and starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElasticIp {
+    pub allocation_id: String,
+    pub public_ip: String,
+    /// Some while associated with an instance
+    pub association_id: Option<String>,
+    pub instance_id: Option<String>
+}
+
+impl ElasticIp {
+    /// Allocates a new elastic ip for use in a vpc. AWS bills elastic ips that aren't associated
+    pub async fn allocate(client: &Ec2Client) -> Result<ElasticIp, Box<dyn Error>> {
+        let allocate_req = AllocateAddressRequest {
+            domain: Some("vpc".to_string()),
+            ..Default::default()
+        };
+        let allocate_res = client.allocate_address(allocate_req).await?;
+
+        match (allocate_res.allocation_id, allocate_res.public_ip) {
+            (Some(allocation_id), Some(public_ip)) => Ok(ElasticIp {
+                allocation_id,
+                public_ip,
+                association_id: None,
+                instance_id: None
+            }),
+            _ => Err("allocated elastic ip but got no allocation id or ip".into())
+        }
+    }
+
+    /// Lists every elastic ip allocated in the client's region
+    pub async fn list(client: &Ec2Client) -> Result<Vec<ElasticIp>, Box<dyn Error>> {
+        Self::describe(client, DescribeAddressesRequest::default()).await
+    }
+
+    /// Gets the elastic ip associated with ec2.
+    /// returns None if ec2 doesn't have one
+    pub async fn for_instance(ec2: &Ec2Object) -> Result<Option<ElasticIp>, Box<dyn Error>> {
+        let desc_req = DescribeAddressesRequest {
+            filters: Some(vec![Filter {
+                name: Some("instance-id".to_string()),
+                values: Some(vec![ec2.instance_id.clone()])
+            }]),
+            ..Default::default()
+        };
+        Ok(Self::describe(&ec2.client, desc_req).await?.into_iter().next())
+    }
+
+    /// Associates this ip with ec2, replacing its current public ip.
+    /// Moves the ip if it is already associated with another instance
+    pub async fn associate(&mut self, ec2: &Ec2Object) -> Result<(), Box<dyn Error>> {
+        let associate_req = AssociateAddressRequest {
+            allocation_id: Some(self.allocation_id.clone()),
+            instance_id: Some(ec2.instance_id.clone()),
+            allow_reassociation: Some(true),
+            ..Default::default()
+        };
+        let associate_res = ec2.client.associate_address(associate_req).await?;
+
+        self.association_id = associate_res.association_id;
+        self.instance_id = Some(ec2.instance_id.clone());
+        Ok(())
+    }
+
+    /// Disassociates this ip from its instance, which gets a new non elastic public ip when next started.
+    /// Does nothing if not associated
+    pub async fn disassociate(&mut self, client: &Ec2Client) -> Result<(), Box<dyn Error>> {
+        let association_id = match &self.association_id {
+            Some(id) => id.clone(),
+            None => return Ok(())
+        };
+        let disassociate_req = DisassociateAddressRequest {
+            association_id: Some(association_id),
+            ..Default::default()
+        };
+        client.disassociate_address(disassociate_req).await?;
+
+        self.association_id = None;
+        self.instance_id = None;
+        Ok(())
+    }
+
+    /// Gives this ip back to AWS. It must be disassociated first
+    pub async fn release(self, client: &Ec2Client) -> Result<(), Box<dyn Error>> {
+        if self.association_id.is_some() {
+            return Err(format!("<{}> is still associated, disassociate it before releasing", self.public_ip).into());
+        }
+        let release_req = ReleaseAddressRequest {
+            allocation_id: Some(self.allocation_id),
+            ..Default::default()
+        };
+        client.release_address(release_req).await?;
+        Ok(())
+    }
+
+    async fn describe(client: &Ec2Client, desc_req: DescribeAddressesRequest) -> Result<Vec<ElasticIp>, Box<dyn Error>> {
+        let desc_res = client.describe_addresses(desc_req).await?;
+        Ok(desc_res.addresses.unwrap_or_default()
+            .into_iter()
+            .filter_map(Self::from_info)
+            .collect())
+    }
+
+    fn from_info(address: Address) -> Option<ElasticIp> {
+        Some(ElasticIp {
+            allocation_id: address.allocation_id?,
+            public_ip: address.public_ip?,
+            association_id: address.association_id,
+            instance_id: address.instance_id
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_machine::ec2::test_utils::{mock_client, mock_client_checked, request_params};
+
+    fn ec2(client: Ec2Client) -> Ec2Object {
+        Ec2Object {
+            client,
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        }
+    }
+
+    #[test]
+    fn associate_with_instance() -> Result<(), Box<dyn Error>> {
+        let body = "<AssociateAddressResponse><associationId>eipassoc-0123</associationId></AssociateAddressResponse>";
+        let client = mock_client_checked(body, |req| {
+            let params = request_params(req);
+            assert!(params.contains("AllocationId=eipalloc-0123"));
+            assert!(params.contains("InstanceId=i-0123"));
+        });
+        let mut elastic_ip = ElasticIp {
+            allocation_id: "eipalloc-0123".to_string(),
+            public_ip: "1.2.3.4".to_string(),
+            association_id: None,
+            instance_id: None
+        };
+
+        tokio_test::block_on(elastic_ip.associate(&ec2(client)))?;
+        assert_eq!(elastic_ip.association_id, Some("eipassoc-0123".to_string()));
+        assert_eq!(elastic_ip.instance_id, Some("i-0123".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn for_instance() -> Result<(), Box<dyn Error>> {
+        let body = r#"<DescribeAddressesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <addressesSet>
+                <item>
+                    <publicIp>1.2.3.4</publicIp>
+                    <allocationId>eipalloc-0123</allocationId>
+                    <domain>vpc</domain>
+                    <instanceId>i-0123</instanceId>
+                    <associationId>eipassoc-0123</associationId>
+                </item>
+            </addressesSet>
+        </DescribeAddressesResponse>"#;
+
+        let elastic_ip = tokio_test::block_on(ElasticIp::for_instance(&ec2(mock_client(body))))?.unwrap();
+        assert_eq!(elastic_ip.public_ip, "1.2.3.4");
+        assert_eq!(elastic_ip.association_id, Some("eipassoc-0123".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn release_refuses_associated() {
+        let client = mock_client_checked("", |_req| panic!("should not release"));
+        let elastic_ip = ElasticIp {
+            allocation_id: "eipalloc-0123".to_string(),
+            public_ip: "1.2.3.4".to_string(),
+            association_id: Some("eipassoc-0123".to_string()),
+            instance_id: Some("i-0123".to_string())
+        };
+
+        assert!(tokio_test::block_on(elastic_ip.release(&client)).is_err());
+    }
+}
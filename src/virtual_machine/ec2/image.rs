@@ -0,0 +1,302 @@
+use std::error::Error;
+
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{CreateImageRequest, DescribeImagesRequest, DeregisterImageRequest};
+use rusoto_ec2::{DeleteSnapshotRequest, Filter};
+
+use crate::virtual_machine::ec2::instance::Ec2Object;
+use crate::virtual_machine::ec2::waiter::Waiter;
+
+/// AWS account id canonical publishes its official ubuntu images from
+pub const CANONICAL_OWNER_ID: &str = "099720109477";
+
+/// An Amazon Machine Image instances can be launched from
+pub struct Image {
+    pub client: Ec2Client,
+    pub image_id: String,
+    pub name: String,
+    /// ISO 8601, eg. 2020-10-01T04:00:00.000Z
+    pub creation_date: String,
+    /// snapshots holding the image's volumes, deleted by deregister
+    pub snapshot_ids: Vec<String>
+}
+
+impl Image {
+    /// Starts creating an image of ec2's volumes named name.
+    /// Unless no_reboot is set ec2 is rebooted first so its file systems are consistent.
+    /// Does not wait for the image to become available, see wait_until_available
+    pub async fn create_from(ec2: &Ec2Object, name: &str, description: &str, no_reboot: bool) -> Result<Image, Box<dyn Error>> {
+        let create_req = CreateImageRequest {
+            instance_id: ec2.instance_id.clone(),
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            no_reboot: Some(no_reboot),
+            ..Default::default()
+        };
+        let create_res = ec2.client.create_image(create_req).await?;
+
+        match create_res.image_id {
+            Some(image_id) => Ok(Image {
+                client: ec2.client.clone(),
+                image_id,
+                name: name.to_string(),
+                creation_date: String::new(),
+                snapshot_ids: vec![]
+            }),
+            None => Err(format!("created image <{}> but got no id", name).into())
+        }
+    }
+
+    /// Retrieves image by id.
+    /// returns None if there is no image with image_id
+    pub async fn retrieve(client: Ec2Client, image_id: &str) -> Result<Option<Image>, Box<dyn Error>> {
+        match Self::describe(&client, image_id).await? {
+            Some(image) => Ok(Self::from_info(client, image)),
+            None => Ok(None)
+        }
+    }
+
+    /// Gets current state of this image: pending, available, failed or deregistered
+    pub async fn state(&self) -> Result<String, Box<dyn Error>> {
+        match Self::describe(&self.client, &self.image_id).await? {
+            Some(image) => match image.state {
+                Some(state) => Ok(state),
+                None => Err(format!("image <{}> has no state", self.image_id).into())
+            },
+            None => Err(format!("couldn't find image <{}>", self.image_id).into())
+        }
+    }
+
+    /// Blocks until this image can be launched. Refreshes snapshot_ids once it can
+    pub async fn wait_until_available(&mut self, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        waiter.wait_for("available", &["pending"], || self.state()).await?;
+
+        if let Some(image) = Self::describe(&self.client, &self.image_id).await? {
+            if let Some(available) = Self::from_info(self.client.clone(), image) {
+                *self = available;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deregisters this image so it can no longer be launched.
+    /// If delete_snapshots is set the snapshots backing it are deleted too, they are otherwise kept (and billed).
+    ///     Errors without deregistering if delete_snapshots is set and the snapshots can't be found,
+    ///     eg. while the image is still pending
+    pub async fn deregister(self, delete_snapshots: bool) -> Result<(), Box<dyn Error>> {
+        //looked up again as snapshot_ids is empty until wait_until_available
+        let snapshot_ids = if delete_snapshots {
+            match Self::describe(&self.client, &self.image_id).await? {
+                Some(image) if image.state.as_deref() == Some("pending") =>
+                    return Err(format!("image <{}> is pending so its snapshots aren't known yet, not deregistering", self.image_id).into()),
+                Some(image) => snapshot_ids(&image),
+                None => return Err(format!("couldn't find image <{}> to find its snapshots", self.image_id).into())
+            }
+        } else {
+            vec![]
+        };
+        let deregister_req = DeregisterImageRequest {
+            image_id: self.image_id.clone(),
+            ..Default::default()
+        };
+        self.client.deregister_image(deregister_req).await?;
+
+        for snapshot_id in snapshot_ids {
+            let delete_req = DeleteSnapshotRequest {
+                snapshot_id,
+                ..Default::default()
+            };
+            self.client.delete_snapshot(delete_req).await?;
+        }
+        Ok(())
+    }
+
+    async fn describe(client: &Ec2Client, image_id: &str) -> Result<Option<rusoto_ec2::Image>, Box<dyn Error>> {
+        let desc_req = DescribeImagesRequest {
+            image_ids: Some(vec![image_id.to_string()]),
+            ..Default::default()
+        };
+        let desc_res = client.describe_images(desc_req).await?;
+        Ok(desc_res.images.unwrap_or_default()
+            .into_iter()
+            .find(|image| image.image_id.as_deref() == Some(image_id)))
+    }
+
+    fn from_info(client: Ec2Client, image: rusoto_ec2::Image) -> Option<Image> {
+        Some(Image {
+            client,
+            snapshot_ids: snapshot_ids(&image),
+            image_id: image.image_id?,
+            name: image.name.unwrap_or_default(),
+            creation_date: image.creation_date.unwrap_or_default()
+        })
+    }
+}
+
+/// snapshots holding image's ebs volumes
+fn snapshot_ids(image: &rusoto_ec2::Image) -> Vec<String> {
+    image.block_device_mappings.iter()
+        .flatten()
+        .filter_map(|mapping| mapping.ebs.as_ref()?.snapshot_id.clone())
+        .collect()
+}
+
+/// Search for available images, eg. the latest official ubuntu release
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageQuery {
+    /// account ids, or self/amazon/aws-marketplace
+    pub owners: Vec<String>,
+    /// image name, * matches anything
+    pub name: String,
+    /// x86_64 or arm64
+    pub architecture: String
+}
+
+impl ImageQuery {
+    /// Official canonical ubuntu server image, eg. ubuntu("22.04", "amd64")
+    pub fn ubuntu(version: &str, arch: &str) -> ImageQuery {
+        let architecture = match arch {
+            "amd64" => "x86_64",
+            other => other
+        };
+        ImageQuery {
+            owners: vec![CANONICAL_OWNER_ID.to_string()],
+            //the volume type (hvm-ssd, hvm-ssd-gp3) and release codename vary between releases
+            name: format!("ubuntu/images/hvm-ssd*/ubuntu-*-{}-{}-server-*", version, arch),
+            architecture: architecture.to_string()
+        }
+    }
+
+    /// Gets the most recently created available image matching this query.
+    /// returns None if nothing matches
+    pub async fn latest(&self, client: &Ec2Client) -> Result<Option<Image>, Box<dyn Error>> {
+        let filter = |name: &str, value: &str| Filter {
+            name: Some(name.to_string()),
+            values: Some(vec![value.to_string()])
+        };
+        let desc_req = DescribeImagesRequest {
+            owners: Some(self.owners.clone()),
+            filters: Some(vec![
+                filter("name", &self.name),
+                filter("architecture", &self.architecture),
+                filter("state", "available")
+            ]),
+            ..Default::default()
+        };
+        let desc_res = client.describe_images(desc_req).await?;
+
+        //creation dates are ISO 8601 so sort correctly as strings
+        Ok(desc_res.images.unwrap_or_default()
+            .into_iter()
+            .filter_map(|image| Image::from_info(client.clone(), image))
+            .max_by(|a, b| a.creation_date.cmp(&b.creation_date)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_machine::ec2::test_utils::{mock_client_checked, request_params};
+    use rusoto_core::Region;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher};
+
+    const DESCRIBE_BODY: &str = r#"<DescribeImagesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <imagesSet>
+            <item>
+                <imageId>ami-old</imageId>
+                <name>ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-amd64-server-20230101</name>
+                <creationDate>2023-01-01T00:00:00.000Z</creationDate>
+                <imageState>available</imageState>
+            </item>
+            <item>
+                <imageId>ami-new</imageId>
+                <name>ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-amd64-server-20230601</name>
+                <creationDate>2023-06-01T00:00:00.000Z</creationDate>
+                <imageState>available</imageState>
+                <blockDeviceMapping>
+                    <item>
+                        <deviceName>/dev/sda1</deviceName>
+                        <ebs><snapshotId>snap-0123</snapshotId></ebs>
+                    </item>
+                </blockDeviceMapping>
+            </item>
+            <item>
+                <imageId>ami-mid</imageId>
+                <name>ubuntu/images/hvm-ssd/ubuntu-jammy-22.04-amd64-server-20230301</name>
+                <creationDate>2023-03-01T00:00:00.000Z</creationDate>
+                <imageState>available</imageState>
+            </item>
+        </imagesSet>
+    </DescribeImagesResponse>"#;
+
+    #[test]
+    fn latest_ubuntu() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked(DESCRIBE_BODY, |req| {
+            let params = request_params(req);
+            assert!(params.contains(&format!("Owner.1={}", CANONICAL_OWNER_ID)));
+            assert!(params.contains("Value.1=ubuntu%2Fimages%2Fhvm-ssd*%2Fubuntu-*-22.04-amd64-server-*"));
+            assert!(params.contains("Value.1=x86_64"));
+        });
+        let query = ImageQuery::ubuntu("22.04", "amd64");
+
+        let image = tokio_test::block_on(query.latest(&client))?.unwrap();
+        assert_eq!(image.image_id, "ami-new");
+        assert_eq!(image.snapshot_ids, vec!["snap-0123"]);
+        Ok(())
+    }
+
+    #[test]
+    fn create_without_reboot() -> Result<(), Box<dyn Error>> {
+        let body = "<CreateImageResponse><imageId>ami-0123</imageId></CreateImageResponse>";
+        let client = mock_client_checked(body, |req| {
+            let params = request_params(req);
+            assert!(params.contains("InstanceId=i-0123"));
+            assert!(params.contains("NoReboot=true"));
+        });
+        let ec2 = Ec2Object {
+            client,
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        let image = tokio_test::block_on(Image::create_from(&ec2, "world", "minecraft world", true))?;
+        assert_eq!(image.image_id, "ami-0123");
+        Ok(())
+    }
+
+    fn created(client: Ec2Client) -> Image {
+        Image {
+            client,
+            image_id: "ami-new".to_string(),
+            name: "world".to_string(),
+            creation_date: String::new(),
+            snapshot_ids: vec![]
+        }
+    }
+
+    #[test]
+    fn deregister_finds_snapshots() -> Result<(), Box<dyn Error>> {
+        let dispatchers = vec![
+            MockRequestDispatcher::default().with_body(DESCRIBE_BODY),
+            MockRequestDispatcher::default().with_body("<DeregisterImageResponse><return>true</return></DeregisterImageResponse>"),
+            MockRequestDispatcher::default().with_body("<DeleteSnapshotResponse><return>true</return></DeleteSnapshotResponse>")
+                .with_request_checker(|req| assert!(request_params(req).contains("SnapshotId=snap-0123"))),
+        ];
+        let client = Ec2Client::new_with(MultipleMockRequestDispatcher::new(dispatchers), MockCredentialsProvider, Region::UsEast2);
+
+        //not waited for, so snapshot_ids is still empty
+        tokio_test::block_on(created(client).deregister(true))
+    }
+
+    #[test]
+    fn pending_image_isnt_deregistered() {
+        let pending = DESCRIBE_BODY.replace("<imageState>available</imageState>", "<imageState>pending</imageState>");
+        let client = mock_client_checked(&pending, |req| {
+            assert!(!request_params(req).contains("Action=DeregisterImage"));
+        });
+
+        assert!(tokio_test::block_on(created(client).deregister(true)).is_err());
+    }
+}
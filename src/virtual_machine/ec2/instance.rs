@@ -1,20 +1,31 @@
 use std::default::Default;
 use rusoto_core::{Region, HttpClient};
 use rusoto_ec2::{Ec2Client, Ec2};
-use rusoto_ec2::{DescribeInstancesResult, DescribeInstancesRequest, Reservation};
+use rusoto_ec2::{DescribeInstancesResult, DescribeInstancesRequest};
 use rusoto_ec2::{RunInstancesRequest, Instance};
 use rusoto_ec2::{StartInstancesRequest, InstanceStateChange};
-use rusoto_ec2::{StopInstancesRequest, StopInstancesResult};
+use rusoto_ec2::StopInstancesRequest;
+use rusoto_ec2::{RebootInstancesRequest, TerminateInstancesRequest};
 use rusoto_ec2::{TagSpecification, Tag};
+use rusoto_ec2::{GroupIdentifier, InstanceIpv6Address, InstancePrivateIpAddress};
+use rusoto_ec2::{ModifyInstanceAttributeRequest, AttributeValue};
+use rusoto_ec2::{DescribeInstanceTypeOfferingsRequest, Filter};
 use rusoto_sts::{StsClient, StsAssumeRoleSessionCredentialsProvider};
+use rusoto_credential::ProvideAwsCredentials;
 use std::error::Error;
 
 use async_trait::async_trait;
-use crate::virtual_machine::vm::VMNetwork;
+use crate::credentials::identity::{self, CallerIdentity};
+use crate::virtual_machine::vm::{VMCore, VMNetwork, VMSnapshot, NetworkInfo, NetworkInterfaceInfo};
+use crate::virtual_machine::ec2::elastic_ip::ElasticIp;
+use crate::virtual_machine::ec2::snapshot::Snapshot;
+use crate::virtual_machine::ec2::spot::SpotOptions;
+use crate::virtual_machine::ec2::user_data::UserData;
+use crate::virtual_machine::ec2::waiter::Waiter;
 
 const AMI_TYPE:&str = "t2.micro";
-const AMI_ID:&str = "ami-07efac79022b86107"; //ubuntu
-const ROLE_ARN:&str = "";
+const AMI_ID:&str = "ami-07efac79022b86107"; //ubuntu, see image::ImageQuery::ubuntu for finding current images
+/// session name roles are assumed with when none is given, shows in the role's cloudtrail events
 const PROVIDER_SESSION_NAME:&str = "minecraft-session";
 
 const TAG_KEY:&str = "minecraft";
@@ -29,6 +40,36 @@ pub struct Ec2Object {
     pub instance_id: String,
     //Anything with &mut self needs to update this
 }
+/// Settings for launching a new instance.
+/// Defaults to a tagged ubuntu t2.micro with no key pair
+#[derive(Debug, Clone)]
+pub struct LaunchOptions {
+    pub image_id: String,
+    pub instance_type: String,
+    /// name of an existing key pair to allow ssh with, see key_pair::KeyPair
+    pub key_name: Option<String>,
+    /// launch as a spot instance instead of on demand
+    pub spot: Option<SpotOptions>,
+    /// script or cloud-config run on first boot
+    pub user_data: Option<UserData>,
+    /// security groups to launch in, the vpc's default group if empty
+    pub security_group_ids: Vec<String>,
+    pub tags: Vec<Tag>
+}
+impl Default for LaunchOptions {
+    fn default() -> Self {
+        LaunchOptions {
+            image_id: AMI_ID.to_string(),
+            instance_type: AMI_TYPE.to_string(),
+            key_name: None,
+            spot: None,
+            user_data: None,
+            security_group_ids: vec![],
+            tags: vec![Ec2Object::default_tag()]
+        }
+    }
+}
+
 impl Ec2Object {
     /// returns default tag as defined by const default values in instance.rs
     pub fn default_tag() -> rusoto_ec2::Tag {
@@ -36,7 +77,7 @@ impl Ec2Object {
     }
     /// returns default region: UsEast2
     pub fn default_region() -> Region {
-        return REGION.clone();
+        REGION.clone()
     }
     /// returns default provider using environment credentials and const default values defined
     ///     in instance.rs
@@ -56,60 +97,391 @@ impl Ec2Object {
     fn default_ec2_client(role_arn:&str) -> rusoto_ec2::Ec2Client {
         Ec2Client::new_with(HttpClient::new().unwrap(), Self::default_provider(role_arn), Self::default_region())
     }
+    /// Checks the default credentials are permitted to assume role_arn the way default_provider does,
+    /// as session_name or the default session name, returning the assumed role's identity.
+    /// Call before anything else to fail with a clear error
+    pub async fn validate(role_arn:&str, session_name: Option<&str>) -> Result<CallerIdentity, Box<dyn Error>> {
+        let sts = StsClient::new(Self::default_region());
+        let assumed = identity::assume_role(&sts, role_arn, session_name.unwrap_or(PROVIDER_SESSION_NAME)).await?;
+        assumed.validate().await
+    }
+    /// returns ec2_client using default region that gets its credentials from provider,
+    ///     eg. a credentials::provider::CredentialChain. Assumes role_arn with them if given
+    pub fn ec2_client_with<P>(provider: P, role_arn: Option<&str>) -> Ec2Client
+        where P: ProvideAwsCredentials + Send + Sync + 'static {
+        Self::ec2_client_in(provider, role_arn, None, Self::default_region())
+    }
+    /// ec2_client_with for region instead of the default region, assuming role_arn as session_name
+    ///     or the default session name
+    pub fn ec2_client_in<P>(provider: P, role_arn: Option<&str>, session_name: Option<&str>, region: Region) -> Ec2Client
+        where P: ProvideAwsCredentials + Send + Sync + 'static {
+        match role_arn {
+            Some(role_arn) => {
+                let sts = StsClient::new_with(HttpClient::new().unwrap(), provider, region.clone());
+                let assumed = StsAssumeRoleSessionCredentialsProvider::new(
+                    sts,
+                    role_arn.to_string(),
+                    session_name.unwrap_or(PROVIDER_SESSION_NAME).to_string(),
+                    None,
+                    None,
+                    None,
+                    None
+                );
+                Ec2Client::new_with(HttpClient::new().unwrap(), assumed, region)
+            },
+            None => Ec2Client::new_with(HttpClient::new().unwrap(), provider, region)
+        }
+    }
     /// returns DescribeInstanceResult from creating default DescribeInstanceRequest
-    async fn describe_instances(client: &Ec2Client) -> DescribeInstancesResult {
+    async fn describe_instances(client: &Ec2Client) -> Result<DescribeInstancesResult, Box<dyn Error>> {
         let desc_instances_req = DescribeInstancesRequest::default();
-        return match client.describe_instances(desc_instances_req).await {
-            Ok(val) => val,
-            Err(e) => panic!(e)
-        };
+        Ok(client.describe_instances(desc_instances_req).await?)
     }
 
-    /// gets instance by instance_id
-    async fn get_instance(ec2:&Ec2Client, instance_id: &String) -> Option<Instance> {
+    /// gets instance by instance_id, None if there is no such instance
+    pub(crate) async fn get_instance(ec2:&Ec2Client, instance_id: &String) -> Result<Option<Instance>, Box<dyn Error>> {
         let filter = |instance: &Instance| {
             match &instance.instance_id {
-                Some(id) => id.to_string() == instance_id.to_string(),
+                Some(id) => id == instance_id,
                 None => false
             }
         };
-        return match Self::filter_instances(ec2, &filter).await {
-            Some(mut vec) => {
-                assert_eq!(vec.len(), 1);
+        let mut matches = Self::filter_instances(ec2, &filter).await?;
+        match matches.len() {
+            0 => Ok(None),
+            1 => Ok(Some(matches.remove(0))),
+            count => Err(format!("{} instances have the id <{}>", count, instance_id).into())
+        }
+    }
 
-                Some(vec.remove(0))
-            }
+    /// Launches a new instance as described by options using the default ec2 client.
+    /// Does not wait for the instance to be running
+    pub async fn launch(options: &LaunchOptions, role_arn:&str) -> Result<Self, Box<dyn Error>> {
+        Self::launch_with(Self::default_ec2_client(role_arn), options).await
+    }
+
+    /// Launches a new instance as described by options using client
+    pub async fn launch_with(client: Ec2Client, options: &LaunchOptions) -> Result<Self, Box<dyn Error>> {
+        // https://github.com/rusoto/rusoto/issues/1102
+        //Thank god for Jonhoo
+        let tag_specifications = if options.tags.is_empty() {
+            None
+        } else {
+            Some(vec![TagSpecification{
+                resource_type: Some("instance".to_string()),
+                tags: Some(options.tags.clone())
+            }])
+        };
+        let instance_market_options = match &options.spot {
+            Some(spot) => Some(spot.to_market_options()?),
             None => None
+        };
+        let user_data = match &options.user_data {
+            Some(user_data) => Some(user_data.encode()?),
+            None => None
+        };
+        let security_group_ids = if options.security_group_ids.is_empty() {
+            None
+        } else {
+            Some(options.security_group_ids.clone())
+        };
+        let run_req = RunInstancesRequest {
+            instance_type: Some(options.instance_type.clone()),
+            image_id: Some(options.image_id.clone()),
+            key_name: options.key_name.clone(),
+            instance_market_options,
+            user_data,
+            security_group_ids,
+            min_count: 1,
+            max_count: 1,
+            tag_specifications,
+            ..Default::default()
+        };
+
+        let run_res = client.run_instances(run_req).await?;
+        let instance = match run_res.instances {
+            Some(mut instances) if !instances.is_empty() => instances.remove(0),
+            _ => return Err("Tried to launch instance, none launched!".into())
+        };
+        match Self::from_instance(client, instance) {
+            Some(ec2) => Ok(ec2),
+            None => Err("launched instance is missing its id, image or type".into())
+        }
+    }
+
+    /// Gets the availability zone this instance was placed in
+    pub async fn availability_zone(&self) -> Result<String, Box<dyn Error>> {
+        match Self::get_instance(&self.client, &self.instance_id).await? {
+            Some(instance) => match instance.placement.and_then(|placement| placement.availability_zone) {
+                Some(zone) => Ok(zone),
+                None => Err(format!("instance <{}> has no availability zone", self.instance_id).into())
+            },
+            None => Err(format!("couldn't find instance <{}>", self.instance_id).into())
+        }
+    }
+
+    /// Gets the ids of the EBS volumes attached to this instance, root volume included
+    pub async fn volume_ids(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        match Self::get_instance(&self.client, &self.instance_id).await? {
+            Some(instance) => Ok(instance.block_device_mappings.unwrap_or_default()
+                .into_iter()
+                .filter_map(|mapping| mapping.ebs?.volume_id)
+                .collect()),
+            None => Err(format!("couldn't find instance <{}>", self.instance_id).into())
+        }
+    }
+
+    /// Changes this instance to new_type, eg. t2.micro -> t3.large, see resize_with
+    pub async fn resize(&mut self, new_type: &str) -> Result<(), Box<dyn Error>> {
+        self.resize_with(new_type, &Waiter::default()).await
+    }
+
+    /// Changes this instance to new_type once it has finished starting or stopping.
+    /// A running instance is stopped first and started again afterwards, even if changing its
+    /// type fails, a stopped one stays stopped.
+    ///     Errors without touching the instance if new_type isn't offered in its availability zone
+    pub async fn resize_with(&mut self, new_type: &str, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        if new_type == self.instance_type {
+            return Ok(());
+        }
+        let availability_zone = self.availability_zone().await?;
+        if !Self::is_type_offered(&self.client, new_type, &availability_zone).await? {
+            return Err(format!("<{}> is not offered in <{}>", new_type, availability_zone).into());
+        }
+
+        let was_running = match self.wait_until_steady(waiter).await?.as_str() {
+            "running" => true,
+            "stopped" => false,
+            state => return Err(format!("can't resize <{}> while it is {}", self.instance_id, state).into())
+        };
+        if was_running {
+            self.stop_with(waiter).await?;
+        }
+
+        let modify_req = ModifyInstanceAttributeRequest {
+            instance_id: self.instance_id.clone(),
+            instance_type: Some(AttributeValue {
+                value: Some(new_type.to_string())
+            }),
+            ..Default::default()
+        };
+        if let Err(e) = self.client.modify_instance_attribute(modify_req).await {
+            //a failed resize shouldn't leave the instance down
+            if was_running {
+                if let Err(start_e) = self.start_with(waiter).await {
+                    return Err(format!("couldn't change the type of <{}>: {}, nor start it again: {}", self.instance_id, e, start_e).into());
+                }
+            }
+            return Err(e.into());
+        }
+        self.instance_type = new_type.to_string();
+
+        if was_running {
+            self.start_with(waiter).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits while this instance is pending or stopping, returning the state it settles in
+    pub async fn wait_until_steady(&self, waiter: &Waiter) -> Result<String, Box<dyn Error>> {
+        let state = self.state().await?;
+        let (target, transitional) = match state.as_str() {
+            "pending" => ("running", "pending"),
+            "stopping" => ("stopped", "stopping"),
+            _ => return Ok(state)
+        };
+        waiter.wait_for(target, &[transitional], || self.state()).await?;
+        Ok(target.to_string())
+    }
+
+    /// whether instances of instance_type can be launched in availability_zone
+    async fn is_type_offered(client: &Ec2Client, instance_type: &str, availability_zone: &str) -> Result<bool, Box<dyn Error>> {
+        let filter = |name: &str, value: &str| Filter {
+            name: Some(name.to_string()),
+            values: Some(vec![value.to_string()])
+        };
+        let offerings_req = DescribeInstanceTypeOfferingsRequest {
+            location_type: Some("availability-zone".to_string()),
+            filters: Some(vec![
+                filter("location", availability_zone),
+                filter("instance-type", instance_type)
+            ]),
+            ..Default::default()
+        };
+        let offerings_res = client.describe_instance_type_offerings(offerings_req).await?;
+        Ok(offerings_res.instance_type_offerings.unwrap_or_default()
+            .iter()
+            .any(|offering| offering.instance_type.as_deref() == Some(instance_type)))
+    }
+
+    /// builds an Ec2Object for instance using client
+    fn from_instance(client: Ec2Client, instance: Instance) -> Option<Self> {
+        Some(Ec2Object {
+            client,
+            image_id: instance.image_id?,
+            instance_type: instance.instance_type?,
+            instance_id: instance.instance_id?
+        })
+    }
+
+    /// Retrieves instance_id using client, eg. one from ec2_client_with
+    pub async fn retrieve_with(client: Ec2Client, instance_id: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let instance = Self::get_instance(&client, &instance_id.to_string()).await?;
+        Ok(instance.and_then(|instance| Self::from_instance(client, instance)))
+    }
+
+    /// Retrieves every instance tagged with key=val.
+    /// Returns an empty vec if no instance has a matching tag
+    pub async fn retrieve_by_tag(key: &str, val: &str, role_arn:&str) -> Result<Vec<Self>, Box<dyn Error>> {
+        Self::retrieve_by_tag_with(Self::default_ec2_client(role_arn), key, val).await
+    }
+
+    /// Retrieves every instance tagged with key=val using client
+    pub async fn retrieve_by_tag_with(ec2_client: Ec2Client, key: &str, val: &str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let tag = rusoto_ec2::Tag{key:Some(key.to_string()), value:Some(val.to_string())};
+        Self::retrieve_by_tags_with(ec2_client, &[tag]).await
+    }
+
+    /// Retrieves every instance that has all of tags using client
+    pub async fn retrieve_by_tags_with(ec2_client: Ec2Client, tags: &[Tag]) -> Result<Vec<Self>, Box<dyn Error>> {
+        let filter = |instance: &Instance| {
+            match &instance.tags {
+                Some(instance_tags) => tags.iter().all(|tag| instance_tags.contains(tag)),
+                None => false
+            }
+        };
+        Ok(Self::filter_instances(&ec2_client, &filter).await?
+            .into_iter()
+            .filter_map(|instance| Self::from_instance(ec2_client.clone(), instance))
+            .collect())
+    }
+
+    /// Retrieves every instance whose id is in instance_ids.
+    /// Ids that don't match any instance are skipped
+    pub async fn retrieve_all(instance_ids: &[&str], role_arn:&str) -> Result<Vec<Self>, Box<dyn Error>> {
+        let ec2_client = Self::default_ec2_client(role_arn);
+        let filter = |instance: &Instance| {
+            match &instance.instance_id {
+                Some(id) => instance_ids.contains(&id.as_str()),
+                None => false
+            }
+        };
+        Ok(Self::filter_instances(&ec2_client, &filter).await?
+            .into_iter()
+            .filter_map(|instance| Self::from_instance(ec2_client.clone(), instance))
+            .collect())
+    }
+
+    /// Retrieves every instance client can see, including stopped and terminated ones
+    pub async fn list_with(ec2_client: Ec2Client) -> Result<Vec<Self>, Box<dyn Error>> {
+        Ok(Self::filter_instances(&ec2_client, &|_: &Instance| true).await?
+            .into_iter()
+            .filter_map(|instance| Self::from_instance(ec2_client.clone(), instance))
+            .collect())
+    }
+
+    /// Reboots this instance, which keeps its public ip and instance store unlike stop then start.
+    /// Does not wait for the reboot to finish
+    pub async fn reboot(&self) -> Result<(), Box<dyn Error>> {
+        let reboot_req = RebootInstancesRequest {
+            instance_ids: vec![self.instance_id.clone()],
+            ..Default::default()
+        };
+        self.client.reboot_instances(reboot_req).await?;
+        Ok(())
+    }
+
+    /// Terminates this instance, deleting it and any volumes set to delete on termination.
+    /// Returns the state it changed to, usually shutting-down
+    pub async fn terminate(&mut self) -> Result<String, Box<dyn Error>> {
+        let terminate_req = TerminateInstancesRequest {
+            instance_ids: vec![self.instance_id.clone()],
+            ..Default::default()
+        };
+        let terminate_res = self.client.terminate_instances(terminate_req).await?;
+        let state = terminate_res.terminating_instances.unwrap_or_default()
+            .into_iter()
+            .find(|change| change.instance_id.as_deref() == Some(self.instance_id.as_str()))
+            .and_then(|change| change.current_state)
+            .and_then(|state| state.name);
+        match state {
+            Some(state) => Ok(state),
+            None => Err(format!("terminate didn't change the state of <{}>", self.instance_id).into())
+        }
+    }
+
+    /// current state of this instance, eg. running or stopped
+    pub async fn state(&self) -> Result<String, Box<dyn Error>> {
+        match Self::get_instance(&self.client, &self.instance_id).await? {
+            Some(instance) => match instance.state.and_then(|state| state.name) {
+                Some(state) => Ok(state),
+                None => Err(format!("instance <{}> has no state", self.instance_id).into())
+            },
+            None => Err(format!("couldn't find instance <{}>", self.instance_id).into())
+        }
+    }
+
+    /// Stops this instance and waits until it has stopped, returning its state.
+    ///     Errors if it goes into any state but stopping or stopped, or waiter times out
+    pub async fn stop_with(&mut self, waiter: &Waiter) -> Result<String, Box<dyn Error>> {
+        let stop_req = StopInstancesRequest {
+            instance_ids: vec![self.instance_id.clone()],
+            ..Default::default()
+        };
+        let stop_res = self.client.stop_instances(stop_req).await?;
+        self.check_changed(stop_res.stopping_instances)?;
+        waiter.wait_for("stopped", &["stopping"], || self.state()).await?;
+        Ok("stopped".to_string())
+    }
+
+    /// Starts this instance and waits until it is running, so a crash on boot isn't reported as started.
+    ///     Errors if it goes into any state but pending or running, or waiter times out
+    pub async fn start_with(&mut self, waiter: &Waiter) -> Result<String, Box<dyn Error>> {
+        let start_req = StartInstancesRequest {
+            instance_ids: vec![self.instance_id.clone()],
+            ..Default::default()
+        };
+        let start_res = self.client.start_instances(start_req).await?;
+        self.check_changed(start_res.starting_instances)?;
+        waiter.wait_for("running", &["pending"], || self.state()).await?;
+        Ok("running".to_string())
+    }
+
+    /// errors unless changes is exactly this instance
+    fn check_changed(&self, changes: Option<Vec<InstanceStateChange>>) -> Result<(), Box<dyn Error>> {
+        let ids: Vec<String> = changes.unwrap_or_default()
+            .into_iter()
+            .filter_map(|change| change.instance_id)
+            .collect();
+        if ids == [self.instance_id.clone()] {
+            Ok(())
+        } else {
+            Err(format!("expected only <{}> to change state but got {:?}", self.instance_id, ids).into())
         }
     }
 
     /// filters all instances by given filter
-    async fn filter_instances<F: Fn(&Instance, ) -> bool>(ec2:&Ec2Client, filter:&F) -> Option<Vec<Instance>> {
-        let desc_res = Self::describe_instances(ec2).await;
+    async fn filter_instances<F: Fn(&Instance, ) -> bool>(ec2:&Ec2Client, filter:&F) -> Result<Vec<Instance>, Box<dyn Error>> {
+        let desc_res = Self::describe_instances(ec2).await?;
 
         let mut matches:Vec<Instance> = vec![];
         //I don't really know what a reservation is but apparently you can get more than one?
-        for reservation in &desc_res.reservations? {
-            let mut res_matches: Vec<Instance> = reservation.instances.clone()?
+        for reservation in desc_res.reservations.unwrap_or_default() {
+            let res_matches: Vec<Instance> = reservation.instances.unwrap_or_default()
                 .into_iter()
-                .filter(|instance|filter(&instance))
+                .filter(|instance|filter(instance))
                 .collect::<Vec<Instance>>();
-            matches.extend(res_matches.into_iter());
+            matches.extend(res_matches);
         }
-        return if !matches.is_empty() {
-            Some(matches)
-        }
-        else{
-            None
-        };
+        Ok(matches)
     }
 }
 #[async_trait]
-impl crate::virtual_machine::vm::VMCore for Ec2Object {
+impl VMCore for Ec2Object {
     async fn retrieve(instance_id: &str, role_arn:&str) -> Option<Self> {
         let ec2_client = Self::default_ec2_client(role_arn);
 
-        return match Self::get_instance(&ec2_client, &instance_id.to_string()).await {
+        return match Self::get_instance(&ec2_client, &instance_id.to_string()).await.ok().flatten() {
             Some(instance) =>
                 Some(Ec2Object {
                     client: ec2_client,
@@ -123,129 +495,436 @@ impl crate::virtual_machine::vm::VMCore for Ec2Object {
     }
 
     async fn status(&self) -> Option<String> {
-        match Self::get_instance(&self.client, &self.instance_id).await {
-            Some(instance) => {
-                //TODO return actual status strings
-                println!("{:?}", instance);
-                Some("pass".to_string())
-            },
+        match Self::get_instance(&self.client, &self.instance_id).await.ok().flatten() {
+            Some(instance) => instance.state?.name,
             None => None
         }
     }
 
-    //TODO this is almost the same as start() so should be able to merge
     async fn stop(&mut self) -> Result<String, Box<dyn Error>> {
-        //aws start instance request --------------------------------------------------------------
-        let stop_req = StopInstancesRequest {
-            instance_ids: vec![self.instance_id.clone()],
-            ..Default::default()
-        };
+        self.stop_with(&Waiter::default()).await
+    }
 
-        let stop_res = self.client.stop_instances(stop_req).await?;
-        //-----------------------------------------------------------------------------------------
-        //check to make sure correct number of instances modified ---------------------------------
-        let mut stopping_instances: Vec<InstanceStateChange> = match stop_res.stopping_instances {
-            Some(vec) => vec,
-            None => panic!("Tried to stop instance, none stopped!")
+    async fn start(&mut self) -> Result<String, Box<dyn Error>> {
+        self.start_with(&Waiter::default()).await
+    }
+}
+#[async_trait]
+impl VMNetwork for Ec2Object {
+    async fn get_public_ip(&self) -> Option<String>{
+        return Self::get_instance(&self.client, &self.instance_id).await.ok()??.public_ip_address;
+    }
+    async fn network_info(&self) -> Result<NetworkInfo, Box<dyn Error>> {
+        let instance = match Self::get_instance(&self.client, &self.instance_id).await? {
+            Some(instance) => instance,
+            None => return Err(format!("couldn't find instance <{}>", self.instance_id).into())
+        };
+        let group_ids = |groups: Option<Vec<GroupIdentifier>>| -> Vec<String> {
+            groups.unwrap_or_default()
+                .into_iter()
+                .filter_map(|group| group.group_id)
+                .collect()
+        };
+        let ipv6_addresses = |addresses: Option<Vec<InstanceIpv6Address>>| -> Vec<String> {
+            addresses.unwrap_or_default()
+                .into_iter()
+                .filter_map(|address| address.ipv_6_address)
+                .collect()
         };
 
-        if stopping_instances.len() > 1 {
-            panic!("Started more than 1 instance! Are your tags correct? Trying to shut down all instances");
-        }
-        //-----------------------------------------------------------------------------------------
-        //get status from instance. Can't do async within closure so have to get instance after----
-        let get_status = |instance: Instance|{
-            let state: rusoto_ec2::InstanceState = match instance.state {
-                Some(s) => s,
-                None => panic!("expected state of instance but there was none!")
-            };
-            return match &state.name {
-                Some(n) => n.clone(),
-                None => panic!("expected state name but there was none!")
-            };
-        };
-        let mut instance = match Self::get_instance(&self.client, &self.instance_id).await {
-            Some(inst) => inst,
-            None => panic!("couldn't find this instance but there was none!")
-        };
-        let mut status = get_status(instance);
-        //-----------------------------------------------------------------------------------------
-        //block thread until instance on-----------------------------------------------------------
-        //  this is done to ensure that we don't just say we've started the instance if
-        //  the instance crashes on boot
-        while status != "stopped" {
-            let mut instance = match Self::get_instance(&self.client, &self.instance_id).await {
-                Some(inst) => inst,
-                None => panic!("couldn't find this instance but there was none!")
-            };
-            status = get_status(instance);
-            if status != "stopping" && status != "stopped" {
-                println!("status {:?}", status);
-                panic!("tried to stop but instead got another status!")//TODO do an error
-            }
+        let network_interfaces: Vec<NetworkInterfaceInfo> = instance.network_interfaces.unwrap_or_default()
+            .into_iter()
+            .filter_map(|interface| {
+                let mut private_ips: Vec<InstancePrivateIpAddress> = interface.private_ip_addresses.unwrap_or_default();
+                private_ips.sort_by_key(|ip| !ip.primary.unwrap_or(false));
+                Some(NetworkInterfaceInfo {
+                    network_interface_id: interface.network_interface_id?,
+                    mac_address: interface.mac_address,
+                    subnet_id: interface.subnet_id,
+                    private_ips: private_ips.into_iter().filter_map(|ip| ip.private_ip_address).collect(),
+                    public_ip: interface.association.and_then(|association| association.public_ip),
+                    ipv6_addresses: ipv6_addresses(interface.ipv_6_addresses),
+                    security_group_ids: group_ids(interface.groups)
+                })
+            })
+            .collect();
+
+        Ok(NetworkInfo {
+            state: instance.state.and_then(|state| state.name),
+            public_ip: instance.public_ip_address,
+            private_ip: instance.private_ip_address,
+            //ec2 sends empty dns names rather than none while stopped
+            public_dns_name: instance.public_dns_name.filter(|name| !name.is_empty()),
+            private_dns_name: instance.private_dns_name.filter(|name| !name.is_empty()),
+            ipv6_addresses: network_interfaces.iter()
+                .flat_map(|interface| interface.ipv6_addresses.clone())
+                .collect(),
+            vpc_id: instance.vpc_id,
+            subnet_id: instance.subnet_id,
+            security_group_ids: group_ids(instance.security_groups),
+            network_interfaces
+        })
+    }
+    async fn is_public_ip_elastic(&self) -> Option<bool> {
+        let public_ip = self.get_public_ip().await?;
+        match ElasticIp::for_instance(self).await {
+            Ok(elastic_ip) => Some(elastic_ip.is_some_and(|elastic_ip| elastic_ip.public_ip == public_ip)),
+            Err(_) => None
         }
-        return Ok("running".to_string());//TODO actual status
-        //-----------------------------------------------------------------------------------------
     }
+}
+#[async_trait]
+impl VMSnapshot for Ec2Object {
+    async fn snapshot(&self, volume_id: Option<&str>, description: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let tags = [Self::default_tag()];
+        let snapshots = match volume_id {
+            Some(volume_id) => vec![Snapshot::create(self.client.clone(), volume_id, description, &tags).await?],
+            None => Snapshot::create_for(self, description, &tags).await?
+        };
+        Ok(snapshots.into_iter().map(|snapshot| snapshot.snapshot_id).collect())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_machine::ec2::spot::{SpotRequestType, InterruptionBehavior};
+    use crate::virtual_machine::ec2::test_utils::{mock_client, mock_client_checked, mock_client_sequence, request_params};
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
 
-    async fn start(&mut self) -> Result<String, Box<dyn Error>> {
-        //aws start instance request --------------------------------------------------------------
-        let start_req = StartInstancesRequest {
-            instance_ids: vec![self.instance_id.clone()],
+    const RUN_BODY: &str = r#"<RunInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <reservationId>r-0123</reservationId>
+        <instancesSet>
+            <item>
+                <instanceId>i-0123</instanceId>
+                <imageId>ami-07efac79022b86107</imageId>
+                <instanceType>t2.micro</instanceType>
+                <keyName>game-key</keyName>
+            </item>
+        </instancesSet>
+    </RunInstancesResponse>"#;
+
+    const DESCRIBE_BODY: &str = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <reservationSet>
+            <item>
+                <reservationId>r-0123</reservationId>
+                <instancesSet>
+                    <item>
+                        <instanceId>i-0123</instanceId>
+                        <imageId>ami-07efac79022b86107</imageId>
+                        <instanceState><code>16</code><name>running</name></instanceState>
+                        <privateDnsName>ip-10-0-0-5.us-east-2.compute.internal</privateDnsName>
+                        <dnsName>ec2-1-2-3-4.us-east-2.compute.amazonaws.com</dnsName>
+                        <instanceType>t2.micro</instanceType>
+                        <subnetId>subnet-0123</subnetId>
+                        <vpcId>vpc-0123</vpcId>
+                        <privateIpAddress>10.0.0.5</privateIpAddress>
+                        <ipAddress>1.2.3.4</ipAddress>
+                        <groupSet>
+                            <item><groupId>sg-0123</groupId><groupName>minecraft</groupName></item>
+                        </groupSet>
+                        <networkInterfaceSet>
+                            <item>
+                                <networkInterfaceId>eni-0123</networkInterfaceId>
+                                <subnetId>subnet-0123</subnetId>
+                                <macAddress>02:00:00:00:00:01</macAddress>
+                                <groupSet>
+                                    <item><groupId>sg-0123</groupId><groupName>minecraft</groupName></item>
+                                </groupSet>
+                                <association><publicIp>1.2.3.4</publicIp><ipOwnerId>amazon</ipOwnerId></association>
+                                <privateIpAddressesSet>
+                                    <item><privateIpAddress>10.0.0.6</privateIpAddress><primary>false</primary></item>
+                                    <item><privateIpAddress>10.0.0.5</privateIpAddress><primary>true</primary></item>
+                                </privateIpAddressesSet>
+                                <ipv6AddressesSet>
+                                    <item><ipv6Address>2600:1f16::1</ipv6Address></item>
+                                </ipv6AddressesSet>
+                            </item>
+                        </networkInterfaceSet>
+                    </item>
+                </instancesSet>
+            </item>
+        </reservationSet>
+    </DescribeInstancesResponse>"#;
+
+    #[test]
+    fn network_info() -> Result<(), Box<dyn Error>> {
+        let ec2 = Ec2Object {
+            client: mock_client(DESCRIBE_BODY),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        let info = tokio_test::block_on(ec2.network_info())?;
+        assert_eq!(info.state, Some("running".to_string()));
+        assert_eq!(info.public_ip, Some("1.2.3.4".to_string()));
+        assert_eq!(info.private_ip, Some("10.0.0.5".to_string()));
+        assert_eq!(info.public_dns_name, Some("ec2-1-2-3-4.us-east-2.compute.amazonaws.com".to_string()));
+        assert_eq!(info.ipv6_addresses, vec!["2600:1f16::1"]);
+        assert_eq!(info.security_group_ids, vec!["sg-0123"]);
+        assert_eq!(info.network_interfaces.len(), 1);
+        assert_eq!(info.network_interfaces[0].private_ips, vec!["10.0.0.5", "10.0.0.6"]);
+        assert_eq!(info.network_interfaces[0].public_ip, Some("1.2.3.4".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn describe_errors_are_returned() {
+        let dispatcher = MockRequestDispatcher::with_status(403).with_body(
+            "<Response><Errors><Error><Code>UnauthorizedOperation</Code><Message>no</Message></Error></Errors></Response>");
+        let client = Ec2Client::new_with(dispatcher, MockCredentialsProvider, Region::UsEast2);
+
+        assert!(tokio_test::block_on(Ec2Object::get_instance(&client, &"i-0123".to_string())).is_err());
+        assert!(tokio_test::block_on(Ec2Object::list_with(client.clone())).is_err());
+
+        let ec2 = Ec2Object {
+            client,
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+        assert!(tokio_test::block_on(ec2.network_info()).is_err());
+    }
+
+    #[test]
+    fn missing_instances_arent_errors() -> Result<(), Box<dyn Error>> {
+        let empty = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <reservationSet/>
+        </DescribeInstancesResponse>"#;
+        assert!(tokio_test::block_on(Ec2Object::retrieve_with(mock_client(empty), "i-0123"))?.is_none());
+        assert!(tokio_test::block_on(Ec2Object::list_with(mock_client(empty)))?.is_empty());
+        Ok(())
+    }
+
+    fn stopped_describe_body() -> String {
+        DESCRIBE_BODY
+            .replace("<code>16</code><name>running</name>", "<code>80</code><name>stopped</name>")
+            .replace("<subnetId>subnet-0123</subnetId>\n                        <vpcId>",
+                     "<placement><availabilityZone>us-east-2a</availabilityZone></placement>\n                        <subnetId>subnet-0123</subnetId>\n                        <vpcId>")
+    }
+
+    const OFFERED_BODY: &str = r#"<DescribeInstanceTypeOfferingsResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <instanceTypeOfferingSet>
+            <item>
+                <instanceType>t3.large</instanceType>
+                <locationType>availability-zone</locationType>
+                <location>us-east-2a</location>
+            </item>
+        </instanceTypeOfferingSet>
+    </DescribeInstanceTypeOfferingsResponse>"#;
+
+    const STOP_BODY: &str = r#"<StopInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <instancesSet>
+            <item>
+                <instanceId>i-0123</instanceId>
+                <currentState><code>64</code><name>stopping</name></currentState>
+                <previousState><code>16</code><name>running</name></previousState>
+            </item>
+        </instancesSet>
+    </StopInstancesResponse>"#;
+
+    #[tokio::test]
+    async fn stop_waits_until_stopped() -> Result<(), Box<dyn Error>> {
+        let stopping = DESCRIBE_BODY.replace("<code>16</code><name>running</name>", "<code>64</code><name>stopping</name>");
+        let stopped = stopped_describe_body();
+        let bodies = [STOP_BODY, stopping.as_str(), stopping.as_str(), stopped.as_str()];
+        let mut ec2 = Ec2Object {
+            client: mock_client_sequence(&bodies),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_secs(1));
+        assert_eq!(ec2.stop_with(&waiter).await?, "stopped");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn start_errors_instead_of_panicking() {
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_secs(1));
+        let nothing_started = "<StartInstancesResponse><instancesSet/></StartInstancesResponse>";
+        let mut ec2 = Ec2Object {
+            client: mock_client(nothing_started),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+        assert!(ec2.start_with(&waiter).await.is_err());
+
+        //stopped again straight away instead of booting
+        let started = STOP_BODY.replace("StopInstancesResponse", "StartInstancesResponse")
+            .replace("<code>64</code><name>stopping</name>", "<code>0</code><name>pending</name>");
+        ec2.client = mock_client_sequence(&[started, stopped_describe_body()]);
+        assert!(ec2.start_with(&waiter).await.is_err());
+    }
+
+    #[test]
+    fn resize_stopped_instance() -> Result<(), Box<dyn Error>> {
+        let describe = stopped_describe_body();
+        let bodies = [describe.as_str(), OFFERED_BODY, describe.as_str(), "<ModifyInstanceAttributeResponse><return>true</return></ModifyInstanceAttributeResponse>"];
+        let mut ec2 = Ec2Object {
+            client: mock_client_sequence(&bodies),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        tokio_test::block_on(ec2.resize("t3.large"))?;
+        assert_eq!(ec2.instance_type, "t3.large");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_resize_starts_instance_again() {
+        let running = DESCRIBE_BODY.replace("<subnetId>subnet-0123</subnetId>\n                        <vpcId>",
+            "<placement><availabilityZone>us-east-2a</availabilityZone></placement>\n                        <subnetId>subnet-0123</subnetId>\n                        <vpcId>");
+        let stopping = running.replace("<code>16</code><name>running</name>", "<code>64</code><name>stopping</name>");
+        let stopped = stopped_describe_body();
+        let started = STOP_BODY.replace("StopInstancesResponse", "StartInstancesResponse");
+        let unsupported = "<Response><Errors><Error><Code>Unsupported</Code><Message>no</Message></Error></Errors></Response>";
+        let dispatchers = vec![
+            MockRequestDispatcher::default().with_body(&running),
+            MockRequestDispatcher::default().with_body(OFFERED_BODY),
+            //still stopping from an earlier stop
+            MockRequestDispatcher::default().with_body(&stopping),
+            MockRequestDispatcher::default().with_body(&stopped),
+            MockRequestDispatcher::with_status(400).with_body(unsupported),
+        ];
+        let mut ec2 = Ec2Object {
+            client: Ec2Client::new_with(MultipleMockRequestDispatcher::new(dispatchers), MockCredentialsProvider, Region::UsEast2),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_secs(1));
+        assert!(ec2.resize_with("t3.large", &waiter).await.is_err());
+        assert_eq!(ec2.instance_type, "t2.micro");
+
+        //stopped for the resize so started again
+        let was_restarted = Arc::new(AtomicBool::new(false));
+        let restarted = was_restarted.clone();
+        let dispatchers = vec![
+            MockRequestDispatcher::default().with_body(&running),
+            MockRequestDispatcher::default().with_body(OFFERED_BODY),
+            MockRequestDispatcher::default().with_body(&running),
+            MockRequestDispatcher::default().with_body(STOP_BODY),
+            MockRequestDispatcher::default().with_body(&stopped),
+            MockRequestDispatcher::with_status(400).with_body(unsupported),
+            MockRequestDispatcher::default().with_body(&started).with_request_checker(move |req| {
+                assert!(request_params(req).contains("Action=StartInstances"));
+                restarted.store(true, Ordering::SeqCst);
+            }),
+            MockRequestDispatcher::default().with_body(&running),
+        ];
+        ec2.client = Ec2Client::new_with(MultipleMockRequestDispatcher::new(dispatchers), MockCredentialsProvider, Region::UsEast2);
+        let e = ec2.resize_with("t3.large", &waiter).await.err().unwrap();
+        assert!(!e.to_string().contains("nor start"), "{}", e);
+        assert!(was_restarted.load(Ordering::SeqCst));
+        assert_eq!(ec2.instance_type, "t2.micro");
+    }
+
+    #[test]
+    fn resize_to_type_not_offered() {
+        let describe = stopped_describe_body();
+        let not_offered = "<DescribeInstanceTypeOfferingsResponse><instanceTypeOfferingSet/></DescribeInstanceTypeOfferingsResponse>";
+        let mut ec2 = Ec2Object {
+            client: mock_client_sequence(&[describe.as_str(), not_offered]),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        assert!(tokio_test::block_on(ec2.resize("p4d.24xlarge")).is_err());
+        assert_eq!(ec2.instance_type, "t2.micro");
+    }
+
+    #[test]
+    fn launch_with_key_pair() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked(RUN_BODY, |req| {
+            let params = request_params(req);
+            assert!(params.contains("KeyName=game-key"));
+            assert!(params.contains("InstanceType=t2.micro"));
+            assert!(params.contains("SecurityGroupId.1=sg-0123"));
+        });
+        let options = LaunchOptions {
+            key_name: Some("game-key".to_string()),
+            security_group_ids: vec!["sg-0123".to_string()],
             ..Default::default()
         };
 
-        let start_res = self.client.start_instances(start_req).await?;
-        //-----------------------------------------------------------------------------------------
-        //check to make sure correct number of instances modified ---------------------------------
-        let mut starting_instances: Vec<InstanceStateChange> = match start_res.starting_instances {
-            Some(vec) => vec,
-            None => panic!("Tried to start instance, none started!")
+        let ec2 = tokio_test::block_on(Ec2Object::launch_with(client, &options))?;
+        assert_eq!(ec2.instance_id, "i-0123");
+        assert_eq!(ec2.instance_type, "t2.micro");
+        Ok(())
+    }
+
+    #[test]
+    fn launch_as_spot() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked(RUN_BODY, |req| {
+            let params = request_params(req);
+            assert!(params.contains("InstanceMarketOptions.MarketType=spot"));
+            assert!(params.contains("InstanceMarketOptions.SpotOptions.MaxPrice=0.01"));
+            assert!(params.contains("InstanceMarketOptions.SpotOptions.SpotInstanceType=persistent"));
+            assert!(params.contains("InstanceMarketOptions.SpotOptions.InstanceInterruptionBehavior=stop"));
+        });
+        let options = LaunchOptions {
+            spot: Some(SpotOptions {
+                max_price: Some("0.01".to_string()),
+                request_type: SpotRequestType::Persistent,
+                interruption_behavior: InterruptionBehavior::Stop
+            }),
+            ..Default::default()
         };
 
-        if starting_instances.len() > 1 {
-            panic!("Started more than 1 instance! Are your tags correct? Trying to shut down all instances");
-        }
-        //-----------------------------------------------------------------------------------------
-        //get status from instance. Can't do async within closure so have to get instance after----
-        let get_status = |instance: Instance|{
-            let state: rusoto_ec2::InstanceState = match instance.state {
-                Some(s) => s,
-                None => panic!("expected state of instance but there was none!")
-            };
-            return match &state.name {
-                Some(n) => n.clone(),
-                None => panic!("expected state name but there was none!")
-            };
-        };
-        let mut instance = match Self::get_instance(&self.client, &self.instance_id).await {
-            Some(inst) => inst,
-            None => panic!("couldn't find this instance but there was none!")
-        };
-        let mut status = get_status(instance);
-        //-----------------------------------------------------------------------------------------
-        //block thread until instance on-----------------------------------------------------------
-        //  this is done to ensure that we don't just say we've started the instance if
-        //  the instance crashes on boot
-        while status != "running" {
-            let mut instance = match Self::get_instance(&self.client, &self.instance_id).await {
-                Some(inst) => inst,
-                None => panic!("couldn't find this instance but there was none!")
-            };
-            status = get_status(instance);
-            if status != "pending" && status != "running" {
-                println!("status {:?}", status);
-                panic!("tried to run but instead got another status!")//TODO do an error
-            }
-        }
-        return Ok("running".to_string());//TODO actual status
-        //-----------------------------------------------------------------------------------------
+        tokio_test::block_on(Ec2Object::launch_with(client, &options))?;
+        Ok(())
     }
-}
-#[async_trait]
-impl VMNetwork for Ec2Object {
-    async fn get_public_ip(&self) -> Option<String>{
-        return Self::get_instance(&self.client, &self.instance_id).await?.public_ip_address;
+
+    #[test]
+    fn launch_with_user_data() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked(RUN_BODY, |req| {
+            //base64 of "#!/bin/bash\necho hi\n", url encoded
+            assert!(request_params(req).contains("UserData=IyEvYmluL2Jhc2gKZWNobyBoaQo%3D"));
+        });
+        let options = LaunchOptions {
+            user_data: Some(UserData::Script("#!/bin/bash\necho hi\n".to_string())),
+            ..Default::default()
+        };
+
+        tokio_test::block_on(Ec2Object::launch_with(client, &options))?;
+        Ok(())
+    }
+
+    #[test]
+    fn terminate() -> Result<(), Box<dyn Error>> {
+        let body = r#"<TerminateInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <currentState><code>32</code><name>shutting-down</name></currentState>
+                <previousState><code>16</code><name>running</name></previousState>
+            </item></instancesSet>
+        </TerminateInstancesResponse>"#;
+        let mut ec2 = Ec2Object {
+            client: mock_client_checked(body, |req| {
+                assert!(request_params(req).contains("Action=TerminateInstances"));
+                assert!(request_params(req).contains("InstanceId.1=i-0123"));
+            }),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        assert_eq!(tokio_test::block_on(ec2.terminate())?, "shutting-down");
+        Ok(())
     }
 }
 // impl Ec2Object {
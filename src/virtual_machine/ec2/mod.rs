@@ -3,3 +3,16 @@ extern crate rusoto_core;
 extern crate tokio;
 
 pub mod instance;
+pub mod elastic_ip;
+pub mod image;
+pub mod key_pair;
+pub mod plan;
+pub mod security_group;
+pub mod snapshot;
+pub mod spot;
+pub mod user_data;
+pub mod volume;
+pub mod waiter;
+
+#[cfg(test)]
+pub(crate) mod test_utils;
\ No newline at end of file
@@ -0,0 +1,890 @@
+//! Desired state for instances, compared against what ec2 reports to plan the changes that bring
+//! them in line, then applied. Planning again once everything has been applied gives no changes
+extern crate serde;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{CreateTagsRequest, DescribeInstancesRequest, DescribeVolumesRequest, Instance, Tag};
+use self::serde::Deserialize;
+
+use crate::virtual_machine::ec2::elastic_ip::ElasticIp;
+use crate::virtual_machine::ec2::instance::{Ec2Object, LaunchOptions};
+use crate::virtual_machine::ec2::security_group::set_instance_groups;
+use crate::virtual_machine::ec2::volume::{Volume, VolumeOptions};
+use crate::virtual_machine::ec2::waiter::Waiter;
+use crate::virtual_machine::vm::VMCore;
+
+/// Tag every instance a plan launches carries, naming it so later plans find it again
+pub const NAME_TAG: &str = "rust_ec2:name";
+
+/// State an instance should be left in
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DesiredState {
+    #[default]
+    Running,
+    Stopped,
+    Terminated
+}
+
+/// A volume an instance should have attached as device
+#[derive(Debug, Clone)]
+pub struct DesiredVolume {
+    /// eg. volume::DEFAULT_DEVICE
+    pub device: String,
+    pub options: VolumeOptions
+}
+
+/// How an instance should be. Settings that are None aren't managed and are left as they are
+#[derive(Debug, Clone)]
+pub struct DesiredInstance {
+    pub name: String,
+    /// adopts an instance that wasn't launched by a plan, otherwise it is found by NAME_TAG or
+    /// failing that by launch's tags
+    pub instance_id: Option<String>,
+    pub state: DesiredState,
+    pub instance_type: Option<String>,
+    /// a different image means replacing the instance, so its disks are lost
+    pub image_id: Option<String>,
+    pub security_group_ids: Option<Vec<String>>,
+    /// set if missing or different, tags that aren't here are left alone
+    pub tags: Option<Vec<Tag>>,
+    /// created if there is nothing attached as their device, grown if smaller
+    pub volumes: Vec<DesiredVolume>,
+    /// allocation id of an elastic ip to associate
+    pub elastic_ip: Option<String>,
+    /// how to launch the instance if it doesn't exist, NAME_TAG is added to its tags
+    pub launch: LaunchOptions
+}
+
+impl DesiredInstance {
+    /// launch with NAME_TAG added, security groups and image and type settings applied
+    fn launch_options(&self) -> LaunchOptions {
+        let mut options = self.launch.clone();
+        options.tags.retain(|tag| tag.key.as_deref() != Some(NAME_TAG));
+        options.tags.push(Tag {
+            key: Some(NAME_TAG.to_string()),
+            value: Some(self.name.clone())
+        });
+        if let Some(image_id) = &self.image_id {
+            options.image_id = image_id.clone();
+        }
+        if let Some(instance_type) = &self.instance_type {
+            options.instance_type = instance_type.clone();
+        }
+        if let Some(security_group_ids) = &self.security_group_ids {
+            options.security_group_ids = security_group_ids.clone();
+        }
+        options
+    }
+
+    /// whether actual was launched with launch's tags but not by a plan, eg. by `launch minecraft`
+    fn launched_like(&self, actual: &ActualInstance) -> bool {
+        let tags: Vec<&Tag> = self.launch.tags.iter()
+            .filter(|tag| tag.key.as_deref() != Some(NAME_TAG))
+            .collect();
+        actual.name.is_none() && !tags.is_empty() && tags.iter().all(|tag| actual.tags.contains(tag))
+    }
+}
+
+/// A volume attached to an instance as ec2 reports it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActualVolume {
+    pub device: String,
+    pub volume_id: String,
+    /// size in GiB
+    pub size: Option<i64>
+}
+
+/// An instance as ec2 reports it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActualInstance {
+    pub instance_id: String,
+    /// value of NAME_TAG
+    pub name: Option<String>,
+    pub instance_type: String,
+    pub image_id: String,
+    pub state: String,
+    pub security_group_ids: Vec<String>,
+    /// every tag, NAME_TAG included
+    pub tags: Vec<Tag>,
+    pub volumes: Vec<ActualVolume>,
+    /// allocation id of the associated elastic ip
+    pub elastic_ip: Option<String>
+}
+
+impl ActualInstance {
+    fn from_instance(instance: Instance) -> Option<ActualInstance> {
+        let tags = instance.tags.unwrap_or_default();
+        let name = tags.iter()
+            .find(|tag| tag.key.as_deref() == Some(NAME_TAG))
+            .and_then(|tag| tag.value.clone());
+        Some(ActualInstance {
+            instance_id: instance.instance_id?,
+            name,
+            tags,
+            instance_type: instance.instance_type?,
+            image_id: instance.image_id?,
+            state: instance.state?.name?,
+            security_group_ids: instance.security_groups.unwrap_or_default()
+                .into_iter()
+                .filter_map(|group| group.group_id)
+                .collect(),
+            volumes: instance.block_device_mappings.unwrap_or_default()
+                .into_iter()
+                .filter_map(|mapping| Some(ActualVolume {
+                    device: mapping.device_name?,
+                    volume_id: mapping.ebs?.volume_id?,
+                    size: None
+                }))
+                .collect(),
+            elastic_ip: None
+        })
+    }
+
+    /// running or on its way there, apply waits for transitions to finish before starting or stopping
+    fn is_running(&self) -> bool {
+        self.state == "running" || self.state == "pending"
+    }
+
+    /// stopped or on its way there
+    fn is_stopped(&self) -> bool {
+        self.state == "stopped" || self.state == "stopping"
+    }
+}
+
+/// Every instance client can see with the sizes of their volumes and their elastic ips.
+/// Terminated instances are left out
+pub async fn snapshot(client: &Ec2Client) -> Result<Vec<ActualInstance>, Box<dyn Error>> {
+    let desc_res = client.describe_instances(DescribeInstancesRequest::default()).await?;
+    let mut instances: Vec<ActualInstance> = desc_res.reservations.unwrap_or_default()
+        .into_iter()
+        .flat_map(|reservation| reservation.instances.unwrap_or_default())
+        .filter_map(ActualInstance::from_instance)
+        .filter(|instance| instance.state != "terminated" && instance.state != "shutting-down")
+        .collect();
+
+    let volume_ids: Vec<String> = instances.iter()
+        .flat_map(|instance| instance.volumes.iter().map(|volume| volume.volume_id.clone()))
+        .collect();
+    if !volume_ids.is_empty() {
+        let desc_req = DescribeVolumesRequest {
+            volume_ids: Some(volume_ids),
+            ..Default::default()
+        };
+        let sizes: BTreeMap<String, i64> = client.describe_volumes(desc_req).await?.volumes.unwrap_or_default()
+            .into_iter()
+            .filter_map(|volume| Some((volume.volume_id?, volume.size?)))
+            .collect();
+        for volume in instances.iter_mut().flat_map(|instance| instance.volumes.iter_mut()) {
+            volume.size = sizes.get(&volume.volume_id).copied();
+        }
+    }
+
+    let elastic_ips = ElasticIp::list(client).await?;
+    for instance in instances.iter_mut() {
+        instance.elastic_ip = elastic_ips.iter()
+            .find(|elastic_ip| elastic_ip.instance_id.as_deref() == Some(instance.instance_id.as_str()))
+            .map(|elastic_ip| elastic_ip.allocation_id.clone());
+    }
+    Ok(instances)
+}
+
+/// One thing to do to an instance
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// launches the instance and waits for it to run
+    Create(LaunchOptions),
+    /// terminates the instance and creates it again, for changes ec2 can't make in place
+    Replace {
+        reason: String,
+        launch: LaunchOptions
+    },
+    Resize {
+        from: String,
+        to: String
+    },
+    SetSecurityGroups {
+        from: Vec<String>,
+        to: Vec<String>
+    },
+    /// tags to add or change the value of
+    SetTags(Vec<Tag>),
+    CreateVolume(DesiredVolume),
+    GrowVolume {
+        device: String,
+        volume_id: String,
+        from: i64,
+        to: i64
+    },
+    /// allocation id to associate
+    AssociateElasticIp(String),
+    Start,
+    Stop,
+    Terminate
+}
+
+impl Action {
+    /// whether the action deletes an instance and so everything on its disks
+    pub fn is_destructive(&self) -> bool {
+        matches!(self, Action::Replace { .. } | Action::Terminate)
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Create(launch) => write!(f, "create {} from {}", launch.instance_type, launch.image_id),
+            Action::Replace { reason, launch } => write!(f, "replace ({}) with {} from {}", reason, launch.instance_type, launch.image_id),
+            Action::Resize { from, to } => write!(f, "resize {} -> {}", from, to),
+            Action::SetSecurityGroups { from, to } => write!(f, "security groups [{}] -> [{}]", from.join(", "), to.join(", ")),
+            Action::SetTags(tags) => {
+                let tags: Vec<String> = tags.iter()
+                    .map(|tag| format!("{}={}", tag.key.as_deref().unwrap_or_default(), tag.value.as_deref().unwrap_or_default()))
+                    .collect();
+                write!(f, "tags {}", tags.join(", "))
+            },
+            Action::CreateVolume(volume) => write!(f, "create {}GiB {} volume at {}", volume.options.size, volume.options.volume_type, volume.device),
+            Action::GrowVolume { device, volume_id, from, to } => write!(f, "grow {} ({}) {}GiB -> {}GiB", device, volume_id, from, to),
+            Action::AssociateElasticIp(allocation_id) => write!(f, "associate elastic ip {}", allocation_id),
+            Action::Start => write!(f, "start"),
+            Action::Stop => write!(f, "stop"),
+            Action::Terminate => write!(f, "terminate")
+        }
+    }
+}
+
+/// An action on the instance named name
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub name: String,
+    /// None for an instance created or replaced earlier in the same plan
+    pub instance_id: Option<String>,
+    pub action: Action
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self.action {
+            Action::Create(_) => "+",
+            Action::Replace { .. } => "-/+",
+            Action::Terminate => "-",
+            _ => "~"
+        };
+        match &self.instance_id {
+            Some(instance_id) => write!(f, "{} {} ({}): {}", symbol, self.name, instance_id, self.action),
+            None => write!(f, "{} {}: {}", symbol, self.name, self.action)
+        }
+    }
+}
+
+/// Changes that bring actual instances in line with desired ones, in the order they are made
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub changes: Vec<Change>
+}
+
+impl Plan {
+    /// Compares every desired instance with the actual instance it names.
+    /// Instances that aren't desired are left alone, set state to terminated to remove one.
+    ///     Errors if a change can't be planned, eg. a volume would have to shrink
+    pub fn new(desired: &[DesiredInstance], actual: &[ActualInstance]) -> Result<Plan, Box<dyn Error>> {
+        let mut changes = vec![];
+        //instances found by their launch tags are only matched to the first desired instance
+        let mut claimed: Vec<&str> = vec![];
+        for desired in desired {
+            let matches = Self::matching(desired, actual, &claimed);
+            claimed.extend(matches.iter().map(|actual| actual.instance_id.as_str()));
+            let actual = match matches.as_slice() {
+                [] => None,
+                [actual] => Some(*actual),
+                _ => {
+                    let ids: Vec<&str> = matches.iter().map(|actual| actual.instance_id.as_str()).collect();
+                    return Err(format!("<{}> matches several instances {:?}, terminate all but one", desired.name, ids).into());
+                }
+            };
+            let actions = match actual {
+                Some(actual) => Self::update(desired, actual)?,
+                None => Self::create(desired)?
+            };
+            let instance_id = actual.map(|actual| actual.instance_id.clone());
+            let mut created = false;
+            for action in actions {
+                changes.push(Change {
+                    name: desired.name.clone(),
+                    instance_id: if created { None } else { instance_id.clone() },
+                    action: action.clone()
+                });
+                created = created || matches!(action, Action::Create(_) | Action::Replace { .. });
+            }
+        }
+        Ok(Plan {
+            changes
+        })
+    }
+
+    /// actual instances desired describes: the one with its instance_id, otherwise those named
+    /// desired by NAME_TAG, otherwise unclaimed instances launched with its tags but not by a plan
+    fn matching<'a>(desired: &DesiredInstance, actual: &'a [ActualInstance], claimed: &[&str]) -> Vec<&'a ActualInstance> {
+        if let Some(instance_id) = &desired.instance_id {
+            return actual.iter().filter(|actual| &actual.instance_id == instance_id).collect();
+        }
+        let named: Vec<&ActualInstance> = actual.iter()
+            .filter(|actual| actual.name.as_deref() == Some(desired.name.as_str()))
+            .collect();
+        if !named.is_empty() {
+            return named;
+        }
+        actual.iter()
+            .filter(|actual| !claimed.contains(&actual.instance_id.as_str()) && desired.launched_like(actual))
+            .collect()
+    }
+
+    /// actions for a desired instance that doesn't exist
+    fn create(desired: &DesiredInstance) -> Result<Vec<Action>, Box<dyn Error>> {
+        if desired.state == DesiredState::Terminated {
+            return Ok(vec![]);
+        }
+        if let Some(instance_id) = &desired.instance_id {
+            return Err(format!("<{}> is set to instance <{}> which doesn't exist", desired.name, instance_id).into());
+        }
+        let mut actions = vec![Action::Create(desired.launch_options())];
+        actions.extend(Self::after_launch(desired));
+        Ok(actions)
+    }
+
+    /// what a freshly launched instance still needs after launching with desired.launch_options
+    fn after_launch(desired: &DesiredInstance) -> Vec<Action> {
+        let mut actions: Vec<Action> = desired.volumes.iter()
+            .map(|volume| Action::CreateVolume(volume.clone()))
+            .collect();
+        if let Some(allocation_id) = &desired.elastic_ip {
+            actions.push(Action::AssociateElasticIp(allocation_id.clone()));
+        }
+        if desired.state == DesiredState::Stopped {
+            actions.push(Action::Stop);
+        }
+        actions
+    }
+
+    /// actions that bring an existing instance in line with desired
+    fn update(desired: &DesiredInstance, actual: &ActualInstance) -> Result<Vec<Action>, Box<dyn Error>> {
+        if desired.state == DesiredState::Terminated {
+            return Ok(vec![Action::Terminate]);
+        }
+        if let Some(image_id) = &desired.image_id {
+            if image_id != &actual.image_id {
+                if desired.instance_id.is_some() {
+                    return Err(format!("<{}> has image <{}> not <{}>, remove its instance_id to let it be replaced",
+                                       desired.name, actual.image_id, image_id).into());
+                }
+                let mut actions = vec![Action::Replace {
+                    reason: format!("image {} -> {}", actual.image_id, image_id),
+                    launch: desired.launch_options()
+                }];
+                actions.extend(Self::after_launch(desired));
+                return Ok(actions);
+            }
+        }
+
+        let mut actions = vec![];
+        //stopping first saves resize stopping and starting it again
+        if desired.state == DesiredState::Stopped && actual.is_running() {
+            actions.push(Action::Stop);
+        }
+        if let Some(instance_type) = &desired.instance_type {
+            if instance_type != &actual.instance_type {
+                actions.push(Action::Resize {
+                    from: actual.instance_type.clone(),
+                    to: instance_type.clone()
+                });
+            }
+        }
+        if let Some(group_ids) = &desired.security_group_ids {
+            let mut from = actual.security_group_ids.clone();
+            let mut to = group_ids.clone();
+            from.sort();
+            to.sort();
+            if from != to {
+                actions.push(Action::SetSecurityGroups { from, to });
+            }
+        }
+        let mut tags: Vec<Tag> = desired.tags.iter()
+            .flatten()
+            .filter(|tag| !actual.tags.contains(tag))
+            .cloned()
+            .collect();
+        //found by its tags, naming it keeps it found if they change
+        if desired.instance_id.is_none() && actual.name.is_none() {
+            tags.push(Tag {
+                key: Some(NAME_TAG.to_string()),
+                value: Some(desired.name.clone())
+            });
+        }
+        if !tags.is_empty() {
+            actions.push(Action::SetTags(tags));
+        }
+        for volume in &desired.volumes {
+            match actual.volumes.iter().find(|actual| actual.device == volume.device) {
+                Some(ActualVolume { volume_id, size: Some(size), .. }) if *size < volume.options.size => {
+                    actions.push(Action::GrowVolume {
+                        device: volume.device.clone(),
+                        volume_id: volume_id.clone(),
+                        from: *size,
+                        to: volume.options.size
+                    });
+                },
+                Some(ActualVolume { size: Some(size), .. }) if *size > volume.options.size => {
+                    return Err(format!("<{}> {} is {}GiB, volumes can't shrink to {}GiB",
+                                       desired.name, volume.device, size, volume.options.size).into());
+                },
+                Some(_) => {},
+                None => actions.push(Action::CreateVolume(volume.clone()))
+            }
+        }
+        if let Some(allocation_id) = &desired.elastic_ip {
+            if actual.elastic_ip.as_ref() != Some(allocation_id) {
+                actions.push(Action::AssociateElasticIp(allocation_id.clone()));
+            }
+        }
+        if desired.state == DesiredState::Running && actual.is_stopped() {
+            actions.push(Action::Start);
+        }
+        Ok(actions)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// changes that replace or terminate an instance, which should be confirmed before applying
+    pub fn destructive(&self) -> Vec<&Change> {
+        self.changes.iter().filter(|change| change.action.is_destructive()).collect()
+    }
+
+    /// Makes every change in order using client, stopping at the first that fails.
+    /// waiter is used for new instances and volumes to become usable
+    pub async fn apply(&self, client: &Ec2Client, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        //instances created by this apply, by name
+        let mut launched: BTreeMap<String, Ec2Object> = BTreeMap::new();
+        for change in &self.changes {
+            let mut ec2 = match (&change.instance_id, launched.remove(&change.name)) {
+                (Some(instance_id), _) => match Ec2Object::retrieve_with(client.clone(), instance_id).await? {
+                    Some(ec2) => Some(ec2),
+                    None => return Err(format!("couldn't find instance <{}>", instance_id).into())
+                },
+                (None, ec2) => ec2
+            };
+            match &change.action {
+                Action::Create(launch) => {
+                    ec2 = Some(launch_and_wait(client, launch, waiter).await?);
+                },
+                Action::Replace { launch, .. } => {
+                    if let Some(old) = ec2.as_mut() {
+                        old.terminate().await?;
+                    }
+                    ec2 = Some(launch_and_wait(client, launch, waiter).await?);
+                },
+                action => {
+                    let ec2 = match ec2.as_mut() {
+                        Some(ec2) => ec2,
+                        None => return Err(format!("<{}> wasn't created before: {}", change.name, action).into())
+                    };
+                    Self::apply_to(client, ec2, action, waiter).await?;
+                }
+            }
+            if let Some(ec2) = ec2 {
+                launched.insert(change.name.clone(), ec2);
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply_to(client: &Ec2Client, ec2: &mut Ec2Object, action: &Action, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        match action {
+            Action::Resize { to, .. } => ec2.resize_with(to, waiter).await?,
+            Action::SetSecurityGroups { to, .. } => set_instance_groups(ec2, to.clone()).await?,
+            Action::SetTags(tags) => {
+                let tags_req = CreateTagsRequest {
+                    resources: vec![ec2.instance_id.clone()],
+                    tags: tags.clone(),
+                    ..Default::default()
+                };
+                client.create_tags(tags_req).await?;
+            },
+            Action::CreateVolume(volume) => {
+                let created = Volume::create_for(ec2, &volume.options).await?;
+                created.wait_until_available(waiter).await?;
+                created.attach(ec2, &volume.device).await?;
+                created.wait_until_in_use(waiter).await?;
+            },
+            Action::GrowVolume { volume_id, to, .. } => match Volume::retrieve(client.clone(), volume_id).await? {
+                Some(mut volume) => volume.modify(Some(*to), None, None).await?,
+                None => return Err(format!("couldn't find volume <{}>", volume_id).into())
+            },
+            Action::AssociateElasticIp(allocation_id) => {
+                let elastic_ip = ElasticIp::list(client).await?
+                    .into_iter()
+                    .find(|elastic_ip| &elastic_ip.allocation_id == allocation_id);
+                match elastic_ip {
+                    Some(mut elastic_ip) => elastic_ip.associate(ec2).await?,
+                    None => return Err(format!("couldn't find elastic ip <{}>", allocation_id).into())
+                }
+            },
+            //planned from a snapshot that may have been mid transition, which has to finish first
+            Action::Start => {
+                if ec2.wait_until_steady(waiter).await? != "running" {
+                    ec2.start_with(waiter).await?;
+                }
+            },
+            Action::Stop => {
+                if ec2.wait_until_steady(waiter).await? != "stopped" {
+                    ec2.stop_with(waiter).await?;
+                }
+            },
+            Action::Terminate => {
+                ec2.terminate().await?;
+            },
+            Action::Create(_) | Action::Replace { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no changes");
+        }
+        let lines: Vec<String> = self.changes.iter().map(Change::to_string).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// launches options and waits until the instance is running
+async fn launch_and_wait(client: &Ec2Client, options: &LaunchOptions, waiter: &Waiter) -> Result<Ec2Object, Box<dyn Error>> {
+    let ec2 = Ec2Object::launch_with(client.clone(), options).await?;
+    waiter.wait_for("running", &["pending"], || async {
+        match ec2.status().await {
+            Some(status) => Ok(status),
+            None => Err(format!("couldn't find instance <{}>", ec2.instance_id).into())
+        }
+    }).await?;
+    Ok(ec2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_machine::ec2::test_utils::mock_client_sequence;
+    use std::time::Duration;
+
+    fn desired() -> DesiredInstance {
+        DesiredInstance {
+            name: "survival".to_string(),
+            instance_id: None,
+            state: DesiredState::Running,
+            instance_type: Some("t3.medium".to_string()),
+            image_id: Some("ami-0123".to_string()),
+            security_group_ids: Some(vec!["sg-2".to_string(), "sg-1".to_string()]),
+            tags: Some(vec![survival_tag()]),
+            volumes: vec![DesiredVolume {
+                device: "/dev/sdf".to_string(),
+                options: VolumeOptions {
+                    size: 20,
+                    ..Default::default()
+                }
+            }],
+            elastic_ip: Some("eipalloc-0123".to_string()),
+            launch: LaunchOptions {
+                tags: vec![survival_tag()],
+                ..Default::default()
+            }
+        }
+    }
+
+    fn survival_tag() -> Tag {
+        Tag { key: Some("minecraft".to_string()), value: Some("survival".to_string()) }
+    }
+
+    fn name_tag() -> Tag {
+        Tag { key: Some(NAME_TAG.to_string()), value: Some("survival".to_string()) }
+    }
+
+    /// actual instance matching desired exactly
+    fn actual() -> ActualInstance {
+        ActualInstance {
+            instance_id: "i-0123".to_string(),
+            name: Some("survival".to_string()),
+            instance_type: "t3.medium".to_string(),
+            image_id: "ami-0123".to_string(),
+            state: "running".to_string(),
+            security_group_ids: vec!["sg-1".to_string(), "sg-2".to_string()],
+            tags: vec![survival_tag(), name_tag()],
+            volumes: vec![
+                ActualVolume { device: "/dev/sda1".to_string(), volume_id: "vol-root".to_string(), size: Some(8) },
+                ActualVolume { device: "/dev/sdf".to_string(), volume_id: "vol-0123".to_string(), size: Some(20) }
+            ],
+            elastic_ip: Some("eipalloc-0123".to_string())
+        }
+    }
+
+    fn actions(plan: &Plan) -> Vec<String> {
+        plan.changes.iter().map(|change| change.action.to_string()).collect()
+    }
+
+    #[test]
+    fn create_missing() -> Result<(), Box<dyn Error>> {
+        let mut desired = desired();
+        desired.state = DesiredState::Stopped;
+        let plan = Plan::new(&[desired], &[])?;
+
+        assert_eq!(actions(&plan), vec![
+            "create t3.medium from ami-0123",
+            "create 20GiB gp2 volume at /dev/sdf",
+            "associate elastic ip eipalloc-0123",
+            "stop"
+        ]);
+        assert!(plan.changes.iter().all(|change| change.instance_id.is_none()));
+        match &plan.changes[0].action {
+            Action::Create(launch) => {
+                assert!(launch.tags.contains(&Tag { key: Some(NAME_TAG.to_string()), value: Some("survival".to_string()) }));
+                assert_eq!(launch.security_group_ids, vec!["sg-2", "sg-1"]);
+            },
+            other => panic!("expected create, got {}", other)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_to_change() -> Result<(), Box<dyn Error>> {
+        let plan = Plan::new(&[desired()], &[actual()])?;
+        assert!(plan.is_empty());
+        assert_eq!(plan.to_string(), "no changes");
+
+        //unmanaged settings and undesired instances are left alone
+        let unmanaged = DesiredInstance {
+            instance_type: None,
+            image_id: None,
+            security_group_ids: None,
+            tags: None,
+            volumes: vec![],
+            elastic_ip: None,
+            ..desired()
+        };
+        let mut other = actual();
+        other.instance_type = "t2.micro".to_string();
+        let stray = ActualInstance { instance_id: "i-0456".to_string(), name: None, ..actual() };
+        assert!(Plan::new(&[unmanaged], &[other, stray])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn drifted() -> Result<(), Box<dyn Error>> {
+        let mut actual = actual();
+        actual.state = "stopped".to_string();
+        actual.instance_type = "t2.micro".to_string();
+        actual.security_group_ids = vec!["sg-1".to_string()];
+        actual.volumes[1].size = Some(10);
+        actual.elastic_ip = None;
+        actual.tags = vec![Tag { key: Some("minecraft".to_string()), value: Some("creative".to_string()) }, name_tag()];
+        let plan = Plan::new(&[desired()], &[actual])?;
+
+        assert_eq!(plan.to_string(), "\
+~ survival (i-0123): resize t2.micro -> t3.medium
+~ survival (i-0123): security groups [sg-1] -> [sg-1, sg-2]
+~ survival (i-0123): tags minecraft=survival
+~ survival (i-0123): grow /dev/sdf (vol-0123) 10GiB -> 20GiB
+~ survival (i-0123): associate elastic ip eipalloc-0123
+~ survival (i-0123): start");
+        assert!(plan.destructive().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn found_by_launch_tags() -> Result<(), Box<dyn Error>> {
+        //launched with `launch survival`, so tagged like the config but not named
+        let launched = ActualInstance { name: None, tags: vec![survival_tag()], ..actual() };
+        let plan = Plan::new(&[desired()], std::slice::from_ref(&launched))?;
+        assert_eq!(plan.to_string(), "~ survival (i-0123): tags rust_ec2:name=survival");
+
+        //only the first desired instance with those tags gets it
+        let creative = DesiredInstance { name: "creative".to_string(), ..desired() };
+        let plan = Plan::new(&[desired(), creative], std::slice::from_ref(&launched))?;
+        assert_eq!(plan.changes[0].to_string(), "~ survival (i-0123): tags rust_ec2:name=survival");
+        assert_eq!(plan.changes[1].to_string(), "+ creative: create t3.medium from ami-0123");
+
+        //named instances win, and other instances with the tags are left alone
+        let named = ActualInstance { instance_id: "i-0456".to_string(), ..actual() };
+        assert!(Plan::new(&[desired()], &[launched, named])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn replace_and_terminate() -> Result<(), Box<dyn Error>> {
+        let mut actual = actual();
+        actual.image_id = "ami-old".to_string();
+        actual.volumes.pop();
+        let plan = Plan::new(&[desired()], &[actual.clone()])?;
+        assert_eq!(plan.to_string(), "\
+-/+ survival (i-0123): replace (image ami-old -> ami-0123) with t3.medium from ami-0123
+~ survival: create 20GiB gp2 volume at /dev/sdf
+~ survival: associate elastic ip eipalloc-0123");
+
+        //an adopted instance would lose track of its replacement
+        let adopted = DesiredInstance { instance_id: Some("i-0123".to_string()), ..desired() };
+        assert!(Plan::new(&[adopted], &[actual.clone()]).is_err());
+
+        assert_eq!(plan.destructive().len(), 1);
+
+        let terminated = DesiredInstance { state: DesiredState::Terminated, ..desired() };
+        let plan = Plan::new(std::slice::from_ref(&terminated), &[actual])?;
+        assert_eq!(plan.to_string(), "- survival (i-0123): terminate");
+        assert_eq!(plan.destructive().len(), 1);
+        assert!(Plan::new(&[terminated], &[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unplannable() {
+        let mut shrunk = actual();
+        shrunk.volumes[1].size = Some(40);
+        assert!(Plan::new(&[desired()], &[shrunk]).is_err());
+
+        let twice = ActualInstance { instance_id: "i-0456".to_string(), ..actual() };
+        assert!(Plan::new(&[desired()], &[actual(), twice]).is_err());
+
+        let missing = DesiredInstance { instance_id: Some("i-0789".to_string()), ..desired() };
+        assert!(Plan::new(&[missing], &[]).is_err());
+    }
+
+    #[test]
+    fn apply_to_existing() -> Result<(), Box<dyn Error>> {
+        let describe = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <reservationSet><item><instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <imageId>ami-0123</imageId>
+                <instanceState><code>16</code><name>running</name></instanceState>
+                <instanceType>t3.medium</instanceType>
+                <groupSet><item><groupId>sg-1</groupId></item></groupSet>
+            </item></instancesSet></item></reservationSet>
+        </DescribeInstancesResponse>"#;
+        let modify = "<ModifyInstanceAttributeResponse><return>true</return></ModifyInstanceAttributeResponse>";
+        let terminate = r#"<TerminateInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <currentState><code>32</code><name>shutting-down</name></currentState>
+            </item></instancesSet>
+        </TerminateInstancesResponse>"#;
+        let change = |action: Action| Change {
+            name: "survival".to_string(),
+            instance_id: Some("i-0123".to_string()),
+            action
+        };
+        let plan = Plan {
+            changes: vec![
+                change(Action::SetSecurityGroups { from: vec!["sg-1".to_string()], to: vec!["sg-2".to_string()] }),
+                change(Action::Terminate)
+            ]
+        };
+        let client = mock_client_sequence(&[describe, modify, describe, terminate]);
+
+        tokio_test::block_on(plan.apply(&client, &Waiter::default()))?;
+        Ok(())
+    }
+
+    #[test]
+    fn transitions_are_waited_out() -> Result<(), Box<dyn Error>> {
+        let mut desired = desired();
+        desired.instance_id = Some("i-0123".to_string());
+        let mut actual = actual();
+        actual.state = "stopping".to_string();
+        assert_eq!(actions(&Plan::new(&[desired.clone()], &[actual.clone()])?), vec!["start"]);
+        actual.state = "pending".to_string();
+        assert!(Plan::new(&[desired], &[actual])?.is_empty());
+
+        let describe = |state: &str, code: u32| format!(r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <reservationSet><item><instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <imageId>ami-0123</imageId>
+                <instanceState><code>{}</code><name>{}</name></instanceState>
+                <instanceType>t3.medium</instanceType>
+            </item></instancesSet></item></reservationSet>
+        </DescribeInstancesResponse>"#, code, state);
+        let start = r#"<StartInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <currentState><code>0</code><name>pending</name></currentState>
+            </item></instancesSet>
+        </StartInstancesResponse>"#.to_string();
+        let plan = Plan {
+            changes: vec![Change {
+                name: "survival".to_string(),
+                instance_id: Some("i-0123".to_string()),
+                action: Action::Start
+            }]
+        };
+        //still stopping when applied, starting it straight away would fail with IncorrectInstanceState
+        let client = mock_client_sequence(&[
+            describe("stopping", 64), describe("stopping", 64), describe("stopped", 80), start, describe("running", 16)
+        ]);
+
+        tokio_test::block_on(plan.apply(&client, &Waiter::new(Duration::from_millis(1), Duration::from_secs(1))))?;
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_instances() -> Result<(), Box<dyn Error>> {
+        let describe_instances = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <reservationSet><item><instancesSet>
+                <item>
+                    <instanceId>i-0123</instanceId>
+                    <imageId>ami-0123</imageId>
+                    <instanceState><code>80</code><name>stopped</name></instanceState>
+                    <instanceType>t3.medium</instanceType>
+                    <groupSet><item><groupId>sg-1</groupId></item></groupSet>
+                    <blockDeviceMapping><item>
+                        <deviceName>/dev/sdf</deviceName>
+                        <ebs><volumeId>vol-0123</volumeId></ebs>
+                    </item></blockDeviceMapping>
+                    <tagSet><item><key>rust_ec2:name</key><value>survival</value></item></tagSet>
+                </item>
+                <item>
+                    <instanceId>i-0456</instanceId>
+                    <imageId>ami-0123</imageId>
+                    <instanceState><code>48</code><name>terminated</name></instanceState>
+                    <instanceType>t3.medium</instanceType>
+                </item>
+            </instancesSet></item></reservationSet>
+        </DescribeInstancesResponse>"#;
+        let describe_volumes = r#"<DescribeVolumesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <volumeSet><item><volumeId>vol-0123</volumeId><size>20</size></item></volumeSet>
+        </DescribeVolumesResponse>"#;
+        let describe_addresses = r#"<DescribeAddressesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <addressesSet><item>
+                <publicIp>1.2.3.4</publicIp>
+                <allocationId>eipalloc-0123</allocationId>
+                <instanceId>i-0123</instanceId>
+                <associationId>eipassoc-0123</associationId>
+            </item></addressesSet>
+        </DescribeAddressesResponse>"#;
+        let client = mock_client_sequence(&[describe_instances, describe_volumes, describe_addresses]);
+
+        let instances = tokio_test::block_on(snapshot(&client))?;
+        assert_eq!(instances, vec![ActualInstance {
+            instance_id: "i-0123".to_string(),
+            name: Some("survival".to_string()),
+            instance_type: "t3.medium".to_string(),
+            image_id: "ami-0123".to_string(),
+            state: "stopped".to_string(),
+            security_group_ids: vec!["sg-1".to_string()],
+            tags: vec![name_tag()],
+            volumes: vec![ActualVolume { device: "/dev/sdf".to_string(), volume_id: "vol-0123".to_string(), size: Some(20) }],
+            elastic_ip: Some("eipalloc-0123".to_string())
+        }]);
+        Ok(())
+    }
+}
@@ -0,0 +1,402 @@
+use std::error::Error;
+use std::net::IpAddr;
+
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{IpPermission, IpRange, Ipv6Range, UserIdGroupPair};
+use rusoto_ec2::{CreateSecurityGroupRequest, DescribeSecurityGroupsRequest, DeleteSecurityGroupRequest};
+use rusoto_ec2::{AuthorizeSecurityGroupIngressRequest, RevokeSecurityGroupIngressRequest};
+use rusoto_ec2::{AuthorizeSecurityGroupEgressRequest, RevokeSecurityGroupEgressRequest};
+use rusoto_ec2::ModifyInstanceAttributeRequest;
+use hyper::{Body, Client};
+use hyper_tls::HttpsConnector;
+
+use crate::virtual_machine::ec2::instance::Ec2Object;
+
+/// Port the minecraft server listens on
+pub const MINECRAFT_PORT: i64 = 25565;
+
+/// Answers a GET with the caller's public ip
+const CHECK_IP_URL: &str = "https://checkip.amazonaws.com";
+
+/// Where traffic allowed by a rule comes from (ingress) or goes to (egress)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// ipv4 cidr block, eg. 0.0.0.0/0
+    Cidr(String),
+    /// ipv6 cidr block, eg. ::/0
+    Ipv6Cidr(String),
+    /// another security group, by id
+    Group(String)
+}
+
+/// A single security group rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// tcp, udp, icmp or -1 for every protocol
+    pub protocol: String,
+    pub from_port: i64,
+    pub to_port: i64,
+    pub source: Source
+}
+
+impl Rule {
+    /// Rule for protocol traffic on ports from_port through to_port
+    pub fn new(protocol: &str, from_port: i64, to_port: i64, source: Source) -> Rule {
+        Rule {
+            protocol: protocol.to_string(),
+            from_port,
+            to_port,
+            source
+        }
+    }
+    /// Rule for tcp traffic on a single port
+    pub fn tcp(port: i64, source: Source) -> Rule {
+        Self::new("tcp", port, port, source)
+    }
+
+    fn to_ip_permission(&self) -> IpPermission {
+        let mut permission = IpPermission {
+            ip_protocol: Some(self.protocol.clone()),
+            from_port: Some(self.from_port),
+            to_port: Some(self.to_port),
+            ..Default::default()
+        };
+        match &self.source {
+            Source::Cidr(cidr) => permission.ip_ranges = Some(vec![IpRange {
+                cidr_ip: Some(cidr.clone()),
+                ..Default::default()
+            }]),
+            Source::Ipv6Cidr(cidr) => permission.ipv_6_ranges = Some(vec![Ipv6Range {
+                cidr_ipv_6: Some(cidr.clone()),
+                ..Default::default()
+            }]),
+            Source::Group(group_id) => permission.user_id_group_pairs = Some(vec![UserIdGroupPair {
+                group_id: Some(group_id.clone()),
+                ..Default::default()
+            }])
+        }
+        permission
+    }
+
+    /// One IpPermission can hold several sources, each becomes its own Rule
+    fn from_ip_permission(permission: &IpPermission) -> Vec<Rule> {
+        let protocol = permission.ip_protocol.clone().unwrap_or_else(|| "-1".to_string());
+        //ports are absent when every port is allowed
+        let from_port = permission.from_port.unwrap_or(-1);
+        let to_port = permission.to_port.unwrap_or(-1);
+
+        let mut sources: Vec<Source> = vec![];
+        for range in permission.ip_ranges.iter().flatten() {
+            sources.extend(range.cidr_ip.clone().map(Source::Cidr));
+        }
+        for range in permission.ipv_6_ranges.iter().flatten() {
+            sources.extend(range.cidr_ipv_6.clone().map(Source::Ipv6Cidr));
+        }
+        for pair in permission.user_id_group_pairs.iter().flatten() {
+            sources.extend(pair.group_id.clone().map(Source::Group));
+        }
+
+        sources.into_iter()
+            .map(|source| Rule::new(&protocol, from_port, to_port, source))
+            .collect()
+    }
+}
+
+/// An ec2 security group and the rules it had when last retrieved
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityGroup {
+    pub group_id: String,
+    pub group_name: String,
+    pub description: String,
+    pub vpc_id: Option<String>,
+    pub ingress: Vec<Rule>,
+    pub egress: Vec<Rule>
+}
+
+impl SecurityGroup {
+    /// Creates a new security group. Uses the default vpc if vpc_id is None
+    pub async fn create(client: &Ec2Client, group_name: &str, description: &str, vpc_id: Option<&str>) -> Result<SecurityGroup, Box<dyn Error>> {
+        let create_req = CreateSecurityGroupRequest {
+            group_name: group_name.to_string(),
+            description: description.to_string(),
+            vpc_id: vpc_id.map(|id| id.to_string()),
+            ..Default::default()
+        };
+        let create_res = client.create_security_group(create_req).await?;
+
+        match create_res.group_id {
+            Some(group_id) => Ok(SecurityGroup {
+                group_id,
+                group_name: group_name.to_string(),
+                description: description.to_string(),
+                vpc_id: vpc_id.map(|id| id.to_string()),
+                ingress: vec![],
+                egress: vec![]
+            }),
+            None => Err(format!("created security group <{}> but got no id", group_name).into())
+        }
+    }
+
+    /// Gets security group and its rules by id.
+    /// returns None if there is no group with group_id
+    pub async fn retrieve(client: &Ec2Client, group_id: &str) -> Result<Option<SecurityGroup>, Box<dyn Error>> {
+        Ok(Self::list(client).await?
+            .into_iter()
+            .find(|group| group.group_id == group_id))
+    }
+
+    /// Lists every security group in the client's region along with its rules
+    pub async fn list(client: &Ec2Client) -> Result<Vec<SecurityGroup>, Box<dyn Error>> {
+        let desc_res = client.describe_security_groups(DescribeSecurityGroupsRequest::default()).await?;
+
+        Ok(desc_res.security_groups.unwrap_or_default()
+            .iter()
+            .filter_map(Self::from_info)
+            .collect())
+    }
+
+    /// Deletes this security group. AWS refuses if an instance still uses it
+    pub async fn delete(self, client: &Ec2Client) -> Result<(), Box<dyn Error>> {
+        let delete_req = DeleteSecurityGroupRequest {
+            group_id: Some(self.group_id),
+            ..Default::default()
+        };
+        client.delete_security_group(delete_req).await?;
+        Ok(())
+    }
+
+    /// Allows incoming traffic matching rules
+    pub async fn authorize_ingress(&mut self, client: &Ec2Client, rules: &[Rule]) -> Result<(), Box<dyn Error>> {
+        let auth_req = AuthorizeSecurityGroupIngressRequest {
+            group_id: Some(self.group_id.clone()),
+            ip_permissions: Some(rules.iter().map(Rule::to_ip_permission).collect()),
+            ..Default::default()
+        };
+        client.authorize_security_group_ingress(auth_req).await?;
+        self.ingress.extend_from_slice(rules);
+        Ok(())
+    }
+
+    /// Removes rules allowing incoming traffic
+    pub async fn revoke_ingress(&mut self, client: &Ec2Client, rules: &[Rule]) -> Result<(), Box<dyn Error>> {
+        let revoke_req = RevokeSecurityGroupIngressRequest {
+            group_id: Some(self.group_id.clone()),
+            ip_permissions: Some(rules.iter().map(Rule::to_ip_permission).collect()),
+            ..Default::default()
+        };
+        client.revoke_security_group_ingress(revoke_req).await?;
+        self.ingress.retain(|rule| !rules.contains(rule));
+        Ok(())
+    }
+
+    /// Allows outgoing traffic matching rules. Only groups in a vpc have egress rules
+    pub async fn authorize_egress(&mut self, client: &Ec2Client, rules: &[Rule]) -> Result<(), Box<dyn Error>> {
+        let auth_req = AuthorizeSecurityGroupEgressRequest {
+            group_id: self.group_id.clone(),
+            ip_permissions: Some(rules.iter().map(Rule::to_ip_permission).collect()),
+            ..Default::default()
+        };
+        client.authorize_security_group_egress(auth_req).await?;
+        self.egress.extend_from_slice(rules);
+        Ok(())
+    }
+
+    /// Removes rules allowing outgoing traffic
+    pub async fn revoke_egress(&mut self, client: &Ec2Client, rules: &[Rule]) -> Result<(), Box<dyn Error>> {
+        let revoke_req = RevokeSecurityGroupEgressRequest {
+            group_id: self.group_id.clone(),
+            ip_permissions: Some(rules.iter().map(Rule::to_ip_permission).collect()),
+            ..Default::default()
+        };
+        client.revoke_security_group_egress(revoke_req).await?;
+        self.egress.retain(|rule| !rules.contains(rule));
+        Ok(())
+    }
+
+    /// Allows tcp traffic on port from the public ip of the machine running this, returning the rule added
+    pub async fn allow_my_ip(&mut self, client: &Ec2Client, port: i64) -> Result<Rule, Box<dyn Error>> {
+        let rule = match my_public_ip().await? {
+            IpAddr::V4(ip) => Rule::tcp(port, Source::Cidr(format!("{}/32", ip))),
+            IpAddr::V6(ip) => Rule::tcp(port, Source::Ipv6Cidr(format!("{}/128", ip)))
+        };
+        self.authorize_ingress(client, std::slice::from_ref(&rule)).await?;
+        Ok(rule)
+    }
+
+    /// Adds this group to the groups ec2 is in
+    pub async fn attach_to(&self, ec2: &Ec2Object) -> Result<(), Box<dyn Error>> {
+        let mut group_ids = instance_group_ids(ec2).await?;
+        if !group_ids.contains(&self.group_id) {
+            group_ids.push(self.group_id.clone());
+            set_instance_groups(ec2, group_ids).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes this group from the groups ec2 is in. An instance must always be in at least one group
+    pub async fn detach_from(&self, ec2: &Ec2Object) -> Result<(), Box<dyn Error>> {
+        let mut group_ids = instance_group_ids(ec2).await?;
+        if !group_ids.contains(&self.group_id) {
+            return Ok(());
+        }
+        group_ids.retain(|id| id != &self.group_id);
+        if group_ids.is_empty() {
+            return Err(format!("<{}> is the only security group of <{}>, can't detach it", self.group_id, ec2.instance_id).into());
+        }
+        set_instance_groups(ec2, group_ids).await
+    }
+
+    fn from_info(info: &rusoto_ec2::SecurityGroup) -> Option<SecurityGroup> {
+        let rules = |permissions: &Option<Vec<IpPermission>>| -> Vec<Rule> {
+            permissions.iter()
+                .flatten()
+                .flat_map(Rule::from_ip_permission)
+                .collect()
+        };
+        Some(SecurityGroup {
+            group_id: info.group_id.clone()?,
+            group_name: info.group_name.clone().unwrap_or_default(),
+            description: info.description.clone().unwrap_or_default(),
+            vpc_id: info.vpc_id.clone(),
+            ingress: rules(&info.ip_permissions),
+            egress: rules(&info.ip_permissions_egress)
+        })
+    }
+}
+
+/// ids of the security groups ec2 is currently in
+pub async fn instance_group_ids(ec2: &Ec2Object) -> Result<Vec<String>, Box<dyn Error>> {
+    match Ec2Object::get_instance(&ec2.client, &ec2.instance_id).await? {
+        Some(instance) => Ok(instance.security_groups.unwrap_or_default()
+            .into_iter()
+            .filter_map(|group| group.group_id)
+            .collect()),
+        None => Err(format!("couldn't find instance <{}>", ec2.instance_id).into())
+    }
+}
+
+/// Replaces every security group of ec2 with group_ids
+pub async fn set_instance_groups(ec2: &Ec2Object, group_ids: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let modify_req = ModifyInstanceAttributeRequest {
+        instance_id: ec2.instance_id.clone(),
+        groups: Some(group_ids),
+        ..Default::default()
+    };
+    ec2.client.modify_instance_attribute(modify_req).await?;
+    Ok(())
+}
+
+/// Asks CHECK_IP_URL for the public ip of the machine running this. Over verified tls as
+/// the answer is let through security groups, a spoofed one would let someone else in
+async fn my_public_ip() -> Result<IpAddr, Box<dyn Error>> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let response = client.get(CHECK_IP_URL.parse()?).await?;
+    if !response.status().is_success() {
+        return Err(format!("unexpected response from {}: <{}>", CHECK_IP_URL, response.status()).into());
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    parse_check_ip_body(&String::from_utf8_lossy(&body))
+}
+
+fn parse_check_ip_body(body: &str) -> Result<IpAddr, Box<dyn Error>> {
+    match body.trim().parse() {
+        Ok(ip) => Ok(ip),
+        Err(_) => Err(format!("<{}> from {} isn't an ip", body.trim(), CHECK_IP_URL).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::ssh_agent::SSH_PORT;
+    use crate::virtual_machine::ec2::test_utils::{mock_client, mock_client_checked, request_params};
+
+    const DESCRIBE_BODY: &str = r#"<DescribeSecurityGroupsResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <securityGroupInfo>
+            <item>
+                <ownerId>123456789012</ownerId>
+                <groupId>sg-0123</groupId>
+                <groupName>minecraft</groupName>
+                <groupDescription>game server</groupDescription>
+                <vpcId>vpc-0123</vpcId>
+                <ipPermissions>
+                    <item>
+                        <ipProtocol>tcp</ipProtocol>
+                        <fromPort>25565</fromPort>
+                        <toPort>25565</toPort>
+                        <groups/>
+                        <ipRanges>
+                            <item><cidrIp>0.0.0.0/0</cidrIp></item>
+                        </ipRanges>
+                        <ipv6Ranges>
+                            <item><cidrIpv6>::/0</cidrIpv6></item>
+                        </ipv6Ranges>
+                    </item>
+                    <item>
+                        <ipProtocol>tcp</ipProtocol>
+                        <fromPort>22</fromPort>
+                        <toPort>22</toPort>
+                        <groups>
+                            <item><userId>123456789012</userId><groupId>sg-admin</groupId></item>
+                        </groups>
+                    </item>
+                </ipPermissions>
+                <ipPermissionsEgress>
+                    <item>
+                        <ipProtocol>-1</ipProtocol>
+                        <ipRanges>
+                            <item><cidrIp>0.0.0.0/0</cidrIp></item>
+                        </ipRanges>
+                    </item>
+                </ipPermissionsEgress>
+            </item>
+        </securityGroupInfo>
+    </DescribeSecurityGroupsResponse>"#;
+
+    #[test]
+    fn describe_rules() -> Result<(), Box<dyn Error>> {
+        let client = mock_client(DESCRIBE_BODY);
+        let group = tokio_test::block_on(SecurityGroup::retrieve(&client, "sg-0123"))?.unwrap();
+
+        assert_eq!(group.group_name, "minecraft");
+        assert_eq!(group.ingress, vec![
+            Rule::tcp(MINECRAFT_PORT, Source::Cidr("0.0.0.0/0".to_string())),
+            Rule::tcp(MINECRAFT_PORT, Source::Ipv6Cidr("::/0".to_string())),
+            Rule::tcp(i64::from(SSH_PORT), Source::Group("sg-admin".to_string()))
+        ]);
+        assert_eq!(group.egress, vec![Rule::new("-1", -1, -1, Source::Cidr("0.0.0.0/0".to_string()))]);
+        Ok(())
+    }
+
+    #[test]
+    fn authorize_ingress_sends_rules() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked("<AuthorizeSecurityGroupIngressResponse><return>true</return></AuthorizeSecurityGroupIngressResponse>", |req| {
+            let params = request_params(req);
+            assert!(params.contains("GroupId=sg-0123"));
+            assert!(params.contains("IpPermissions.1.FromPort=25565"));
+            assert!(params.contains("IpPermissions.1.IpRanges.1.CidrIp=1.2.3.4%2F32"));
+        });
+        let mut group = SecurityGroup {
+            group_id: "sg-0123".to_string(),
+            group_name: "minecraft".to_string(),
+            description: String::new(),
+            vpc_id: None,
+            ingress: vec![],
+            egress: vec![]
+        };
+        let rule = Rule::tcp(MINECRAFT_PORT, Source::Cidr("1.2.3.4/32".to_string()));
+
+        tokio_test::block_on(group.authorize_ingress(&client, std::slice::from_ref(&rule)))?;
+        assert_eq!(group.ingress, vec![rule]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_ip_response() {
+        assert_eq!(parse_check_ip_body("1.2.3.4\n").unwrap(), "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_check_ip_body("2001:db8::1").unwrap(), "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert!(parse_check_ip_body("<html>1.2.3.4</html>").is_err());
+        assert!(parse_check_ip_body("1.2.3.4/0").is_err());
+    }
+}
@@ -0,0 +1,386 @@
+extern crate chrono;
+
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::error::Error;
+
+use self::chrono::{DateTime, Datelike, Utc};
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{CreateSnapshotRequest, DescribeSnapshotsRequest, DeleteSnapshotRequest};
+use rusoto_ec2::{Filter, TagSpecification, Tag};
+
+use crate::virtual_machine::ec2::instance::Ec2Object;
+use crate::virtual_machine::ec2::volume::{Volume, VolumeOptions};
+use crate::virtual_machine::ec2::waiter::Waiter;
+
+/// A point in time backup of an EBS volume
+pub struct Snapshot {
+    pub client: Ec2Client,
+    pub snapshot_id: String,
+    pub volume_id: String,
+    /// size in GiB of the volume the snapshot was taken of
+    pub volume_size: i64,
+    pub start_time: DateTime<Utc>,
+    pub description: String,
+    pub tags: Vec<Tag>
+}
+
+impl Snapshot {
+    /// Starts a snapshot of volume_id. Does not wait for it to complete, see wait_until_completed
+    pub async fn create(client: Ec2Client, volume_id: &str, description: &str, tags: &[Tag]) -> Result<Snapshot, Box<dyn Error>> {
+        let tag_specifications = if tags.is_empty() {
+            None
+        } else {
+            Some(vec![TagSpecification{
+                resource_type: Some("snapshot".to_string()),
+                tags: Some(tags.to_vec())
+            }])
+        };
+        let create_req = CreateSnapshotRequest {
+            volume_id: volume_id.to_string(),
+            description: Some(description.to_string()),
+            tag_specifications,
+            ..Default::default()
+        };
+        let snapshot = client.create_snapshot(create_req).await?;
+
+        Self::from_info(client, snapshot)
+    }
+
+    /// Starts a snapshot of every EBS volume attached to ec2.
+    ///     Errors if any snapshot can't be started, after deleting the ones already started
+    pub async fn create_for(ec2: &Ec2Object, description: &str, tags: &[Tag]) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let mut snapshots = vec![];
+        let volume_ids = ec2.volume_ids().await?;
+        for volume_id in volume_ids {
+            let error = match Self::create(ec2.client.clone(), &volume_id, description, tags).await {
+                Ok(snapshot) => {
+                    snapshots.push(snapshot);
+                    continue;
+                }
+                Err(e) => e.to_string()
+            };
+            let mut left_behind = vec![];
+            for snapshot in snapshots {
+                let snapshot_id = snapshot.snapshot_id.clone();
+                if snapshot.delete().await.is_err() {
+                    left_behind.push(snapshot_id);
+                }
+            }
+            return if left_behind.is_empty() {
+                Err(format!("couldn't snapshot <{}>: {}, deleted the snapshots already started", volume_id, error).into())
+            } else {
+                Err(format!("couldn't snapshot <{}>: {}, nor delete the snapshots already started <{}>",
+                    volume_id, error, left_behind.join(", ")).into())
+            };
+        }
+        Ok(snapshots)
+    }
+
+    /// Lists snapshots owned by this account tagged with key=val, newest first
+    pub async fn list_by_tag(client: &Ec2Client, key: &str, val: &str) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let desc_req = DescribeSnapshotsRequest {
+            owner_ids: Some(vec!["self".to_string()]),
+            filters: Some(vec![Filter {
+                name: Some(format!("tag:{}", key)),
+                values: Some(vec![val.to_string()])
+            }]),
+            ..Default::default()
+        };
+        let desc_res = client.describe_snapshots(desc_req).await?;
+
+        let mut snapshots = desc_res.snapshots.unwrap_or_default()
+            .into_iter()
+            .map(|snapshot| Self::from_info(client.clone(), snapshot))
+            .collect::<Result<Vec<Snapshot>, Box<dyn Error>>>()?;
+        snapshots.sort_by_key(|snapshot| Reverse(snapshot.start_time));
+        Ok(snapshots)
+    }
+
+    /// Gets current state of this snapshot: pending, completed or error
+    pub async fn state(&self) -> Result<String, Box<dyn Error>> {
+        let desc_req = DescribeSnapshotsRequest {
+            snapshot_ids: Some(vec![self.snapshot_id.clone()]),
+            ..Default::default()
+        };
+        let desc_res = self.client.describe_snapshots(desc_req).await?;
+        match desc_res.snapshots.unwrap_or_default().into_iter().find_map(|snapshot| snapshot.state) {
+            Some(state) => Ok(state),
+            None => Err(format!("couldn't find state of snapshot <{}>", self.snapshot_id).into())
+        }
+    }
+
+    /// Blocks until the snapshot has completed. Large volumes can take hours
+    pub async fn wait_until_completed(&self, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        waiter.wait_for("completed", &["pending"], || self.state()).await
+    }
+
+    /// Creates a new volume in availability_zone holding the data in this snapshot.
+    /// The volume is at least as big as the one the snapshot was taken of
+    pub async fn restore(&self, availability_zone: &str, options: &VolumeOptions) -> Result<Volume, Box<dyn Error>> {
+        let options = VolumeOptions {
+            size: options.size.max(self.volume_size),
+            snapshot_id: Some(self.snapshot_id.clone()),
+            ..options.clone()
+        };
+        Volume::create(self.client.clone(), availability_zone, &options).await
+    }
+
+    /// Deletes this snapshot
+    pub async fn delete(self) -> Result<(), Box<dyn Error>> {
+        let delete_req = DeleteSnapshotRequest {
+            snapshot_id: self.snapshot_id,
+            ..Default::default()
+        };
+        self.client.delete_snapshot(delete_req).await?;
+        Ok(())
+    }
+
+    fn from_info(client: Ec2Client, snapshot: rusoto_ec2::Snapshot) -> Result<Snapshot, Box<dyn Error>> {
+        let snapshot_id = match snapshot.snapshot_id {
+            Some(id) => id,
+            None => return Err("snapshot is missing its id".into())
+        };
+        let start_time = match &snapshot.start_time {
+            Some(time) => DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc),
+            None => return Err(format!("snapshot <{}> has no start time", snapshot_id).into())
+        };
+        Ok(Snapshot {
+            client,
+            snapshot_id,
+            volume_id: snapshot.volume_id.unwrap_or_default(),
+            volume_size: snapshot.volume_size.unwrap_or_default(),
+            start_time,
+            description: snapshot.description.unwrap_or_default(),
+            tags: snapshot.tags.unwrap_or_default()
+        })
+    }
+}
+
+/// Which snapshots to keep when pruning. A snapshot is kept if any rule keeps it.
+/// There's no default, a policy of all zeros would delete everything
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    /// keep this many of the newest snapshots
+    pub keep_last: usize,
+    /// keep the newest snapshot of each of the last daily days that have a snapshot
+    pub daily: usize,
+    /// keep the newest snapshot of each of the last weekly weeks that have a snapshot
+    pub weekly: usize
+}
+
+impl RetentionPolicy {
+    /// true if every rule is 0, so pruning would delete every snapshot
+    pub fn keeps_nothing(&self) -> bool {
+        self.keep_last == 0 && self.daily == 0 && self.weekly == 0
+    }
+
+    /// ids of snapshots this policy does not keep
+    pub fn to_prune(&self, snapshots: &[Snapshot]) -> Vec<String> {
+        let times: Vec<(&str, DateTime<Utc>)> = snapshots.iter()
+            .map(|snapshot| (snapshot.snapshot_id.as_str(), snapshot.start_time))
+            .collect();
+        let keep = self.keep(&times);
+        times.into_iter()
+            .filter(|(id, _)| !keep.contains(id))
+            .map(|(id, _)| id.to_string())
+            .collect()
+    }
+
+    /// ids out of (id, start time) pairs this policy keeps
+    fn keep<'a>(&self, times: &[(&'a str, DateTime<Utc>)]) -> HashSet<&'a str> {
+        let mut newest_first = times.to_vec();
+        newest_first.sort_by_key(|(_, time)| Reverse(*time));
+
+        let mut keep: HashSet<&str> = newest_first.iter()
+            .take(self.keep_last)
+            .map(|(id, _)| *id)
+            .collect();
+
+        //newest_first means the first snapshot seen in each day or week is the one to keep
+        let mut days_seen = HashSet::new();
+        for (id, time) in &newest_first {
+            if days_seen.len() == self.daily {
+                break;
+            }
+            if days_seen.insert(time.naive_utc().date()) {
+                keep.insert(*id);
+            }
+        }
+
+        let mut weeks_seen = HashSet::new();
+        for (id, time) in &newest_first {
+            if weeks_seen.len() == self.weekly {
+                break;
+            }
+            let week = time.iso_week();
+            if weeks_seen.insert((week.year(), week.week())) {
+                keep.insert(*id);
+            }
+        }
+        keep
+    }
+}
+
+/// Deletes the snapshots tagged key=val that policy doesn't keep, returning the deleted ids.
+///     Errors without deleting anything if policy keeps nothing
+pub async fn prune(client: &Ec2Client, key: &str, val: &str, policy: &RetentionPolicy) -> Result<Vec<String>, Box<dyn Error>> {
+    if policy.keeps_nothing() {
+        return Err(format!("retention policy keeps nothing, refusing to delete every snapshot tagged {}={}", key, val).into());
+    }
+    let snapshots = Snapshot::list_by_tag(client, key, val).await?;
+    let to_prune = policy.to_prune(&snapshots);
+
+    for snapshot in snapshots {
+        if to_prune.contains(&snapshot.snapshot_id) {
+            snapshot.delete().await?;
+        }
+    }
+    Ok(to_prune)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::chrono::TimeZone;
+    use crate::virtual_machine::ec2::test_utils::{mock_client, request_params};
+    use rusoto_core::Region;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher};
+    use std::sync::{Arc, Mutex};
+
+    const DESCRIBE_BODY: &str = r#"<DescribeSnapshotsResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+        <requestId>req</requestId>
+        <snapshotSet>
+            <item>
+                <snapshotId>snap-old</snapshotId>
+                <volumeId>vol-0123</volumeId>
+                <status>completed</status>
+                <startTime>2020-10-01T04:00:00.000Z</startTime>
+                <volumeSize>20</volumeSize>
+                <description>world backup</description>
+            </item>
+            <item>
+                <snapshotId>snap-new</snapshotId>
+                <volumeId>vol-0123</volumeId>
+                <status>completed</status>
+                <startTime>2020-10-02T04:00:00.000Z</startTime>
+                <volumeSize>20</volumeSize>
+                <description>world backup</description>
+            </item>
+        </snapshotSet>
+    </DescribeSnapshotsResponse>"#;
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 10, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn failed_create_deletes_snapshots_already_started() {
+        let describe = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <reservationSet>
+                <item>
+                    <reservationId>r-0123</reservationId>
+                    <instancesSet>
+                        <item>
+                            <instanceId>i-0123</instanceId>
+                            <blockDeviceMapping>
+                                <item><deviceName>/dev/xvda</deviceName><ebs><volumeId>vol-0123</volumeId></ebs></item>
+                                <item><deviceName>/dev/xvdb</deviceName><ebs><volumeId>vol-4567</volumeId></ebs></item>
+                            </blockDeviceMapping>
+                        </item>
+                    </instancesSet>
+                </item>
+            </reservationSet>
+        </DescribeInstancesResponse>"#;
+        let created = r#"<CreateSnapshotResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <snapshotId>snap-0123</snapshotId>
+            <volumeId>vol-0123</volumeId>
+            <status>pending</status>
+            <startTime>2020-10-01T04:00:00.000Z</startTime>
+            <volumeSize>20</volumeSize>
+            <description>world backup</description>
+        </CreateSnapshotResponse>"#;
+        let failed = "<Response><Errors><Error><Code>SnapshotCreationPerVolumeRateExceeded</Code><Message>slow down</Message></Error></Errors></Response>";
+        let deleted = r#"<DeleteSnapshotResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <return>true</return>
+        </DeleteSnapshotResponse>"#;
+
+        let deletes = Arc::new(Mutex::new(vec![]));
+        let seen = deletes.clone();
+        let client = Ec2Client::new_with(MultipleMockRequestDispatcher::new(vec![
+            MockRequestDispatcher::default().with_body(describe),
+            MockRequestDispatcher::default().with_body(created),
+            MockRequestDispatcher::with_status(400).with_body(failed),
+            MockRequestDispatcher::default().with_body(deleted)
+                .with_request_checker(move |request| seen.lock().unwrap().push(request_params(request)))
+        ]), MockCredentialsProvider, Region::UsEast2);
+        let ec2 = Ec2Object {
+            client,
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        let error = tokio_test::block_on(Snapshot::create_for(&ec2, "world backup", &[])).err().unwrap().to_string();
+        assert!(error.contains("<vol-4567>"), "{}", error);
+        assert!(error.contains("deleted the snapshots already started"), "{}", error);
+        let deletes = deletes.lock().unwrap();
+        assert_eq!(deletes.len(), 1);
+        assert!(deletes[0].contains("SnapshotId=snap-0123"), "{}", deletes[0]);
+    }
+
+    #[test]
+    fn list_newest_first() -> Result<(), Box<dyn Error>> {
+        let client = mock_client(DESCRIBE_BODY);
+        let snapshots = tokio_test::block_on(Snapshot::list_by_tag(&client, "backup", "world"))?;
+
+        let ids: Vec<&str> = snapshots.iter().map(|snapshot| snapshot.snapshot_id.as_str()).collect();
+        assert_eq!(ids, vec!["snap-new", "snap-old"]);
+        assert_eq!(snapshots[0].start_time, at(2, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn keep_last() {
+        let times = vec![("a", at(1, 0)), ("b", at(2, 0)), ("c", at(3, 0))];
+        let policy = RetentionPolicy { keep_last: 2, daily: 0, weekly: 0 };
+
+        let keep = policy.keep(&times);
+        assert_eq!(keep, vec!["b", "c"].into_iter().collect());
+    }
+
+    #[test]
+    fn keep_newest_per_day() {
+        //three days of two snapshots each, only the newest of the last two days are kept
+        let times = vec![
+            ("1-early", at(1, 1)), ("1-late", at(1, 23)),
+            ("2-early", at(2, 1)), ("2-late", at(2, 23)),
+            ("3-early", at(3, 1)), ("3-late", at(3, 12))
+        ];
+        let policy = RetentionPolicy { keep_last: 0, daily: 2, weekly: 0 };
+
+        let keep = policy.keep(&times);
+        assert_eq!(keep, vec!["2-late", "3-late"].into_iter().collect());
+    }
+
+    #[test]
+    fn keep_newest_per_week() {
+        //2020-10-05 and 2020-10-12 are mondays
+        let times = vec![("week1", at(4, 0)), ("week2-a", at(6, 0)), ("week2-b", at(10, 0)), ("week3", at(12, 0))];
+        let policy = RetentionPolicy { keep_last: 0, daily: 0, weekly: 3 };
+
+        let keep = policy.keep(&times);
+        assert_eq!(keep, vec!["week1", "week2-b", "week3"].into_iter().collect());
+    }
+
+    #[test]
+    fn prune_refuses_to_keep_nothing() {
+        let nothing = RetentionPolicy { keep_last: 0, daily: 0, weekly: 0 };
+        assert!(nothing.keeps_nothing());
+        let pruned = tokio_test::block_on(prune(&mock_client(DESCRIBE_BODY), "minecraft", "minecraft", &nothing));
+        assert!(pruned.is_err());
+    }
+}
@@ -0,0 +1,273 @@
+extern crate chrono;
+extern crate serde_json;
+
+use std::error::Error;
+use std::time::Duration;
+
+use self::chrono::{DateTime, Utc};
+use rusoto_ec2::Ec2;
+use rusoto_ec2::{InstanceMarketOptionsRequest, SpotMarketOptions, DescribeSpotInstanceRequestsRequest};
+
+use crate::ssh::ssh_agent::SSHAgent;
+use crate::virtual_machine::ec2::instance::Ec2Object;
+
+/// Prints the spot instance-action document then its http status on the last line.
+/// Uses an IMDSv2 token so it works whether or not IMDSv1 is disabled
+const INSTANCE_ACTION_COMMAND: &str = "TOKEN=$(curl -s -X PUT http://169.254.169.254/latest/api/token \
+    -H 'X-aws-ec2-metadata-token-ttl-seconds: 60') && \
+    curl -s -w '\\n%{http_code}' -H \"X-aws-ec2-metadata-token: $TOKEN\" \
+    http://169.254.169.254/latest/meta-data/spot/instance-action";
+
+/// How long after a spot request is marked for interruption the action happens
+const NOTICE_MINUTES: i64 = 2;
+
+/// Whether a spot request is fulfilled once or relaunches the instance after every interruption
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpotRequestType {
+    OneTime,
+    Persistent
+}
+
+/// What AWS does to a spot instance when it takes the capacity back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptionBehavior {
+    Stop,
+    Hibernate,
+    Terminate
+}
+
+/// Settings for launching as a spot instance, see instance::LaunchOptions::spot.
+/// Defaults to a one time request capped at the on demand price that terminates on interruption
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotOptions {
+    /// highest hourly price in USD, eg. "0.0035". None caps at the on demand price
+    pub max_price: Option<String>,
+    pub request_type: SpotRequestType,
+    pub interruption_behavior: InterruptionBehavior
+}
+
+impl Default for SpotOptions {
+    fn default() -> Self {
+        SpotOptions {
+            max_price: None,
+            request_type: SpotRequestType::OneTime,
+            interruption_behavior: InterruptionBehavior::Terminate
+        }
+    }
+}
+
+impl SpotOptions {
+    /// Converts to the market options of a RunInstances request.
+    ///     Errors if the combination isn't one AWS accepts
+    pub fn to_market_options(&self) -> Result<InstanceMarketOptionsRequest, Box<dyn Error>> {
+        //a one time request has nothing to restart a stopped or hibernated instance
+        if self.request_type == SpotRequestType::OneTime && self.interruption_behavior != InterruptionBehavior::Terminate {
+            return Err(format!("one time spot requests can only terminate on interruption, not {:?}", self.interruption_behavior).into());
+        }
+        let spot_instance_type = match self.request_type {
+            SpotRequestType::OneTime => "one-time",
+            SpotRequestType::Persistent => "persistent"
+        };
+        let instance_interruption_behavior = match self.interruption_behavior {
+            InterruptionBehavior::Stop => "stop",
+            InterruptionBehavior::Hibernate => "hibernate",
+            InterruptionBehavior::Terminate => "terminate"
+        };
+        Ok(InstanceMarketOptionsRequest {
+            market_type: Some("spot".to_string()),
+            spot_options: Some(SpotMarketOptions {
+                max_price: self.max_price.clone(),
+                spot_instance_type: Some(spot_instance_type.to_string()),
+                instance_interruption_behavior: Some(instance_interruption_behavior.to_string()),
+                ..Default::default()
+            })
+        })
+    }
+}
+
+/// Warning that AWS is taking a spot instance back
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterruptionNotice {
+    /// stop, hibernate or terminate
+    pub action: String,
+    /// when the action happens, about two minutes after the notice. None if it already happened
+    pub time: Option<DateTime<Utc>>
+}
+
+/// Checks the instance metadata of the instance agent is connected to for an interruption notice.
+/// returns None if no interruption is scheduled. Blocks the current thread
+pub fn check_metadata(agent: &SSHAgent) -> Result<Option<InterruptionNotice>, Box<dyn Error>> {
+    let output = agent.exec(INSTANCE_ACTION_COMMAND)?;
+    parse_instance_action(&output.stdout)
+}
+
+/// Checks the spot request and state of ec2 for an interruption that is scheduled or already happened.
+/// returns None if there is none or ec2 isn't a spot instance
+pub async fn check_state(ec2: &Ec2Object) -> Result<Option<InterruptionNotice>, Box<dyn Error>> {
+    let instance = match Ec2Object::get_instance(&ec2.client, &ec2.instance_id).await? {
+        Some(instance) => instance,
+        None => return Err(format!("couldn't find instance <{}>", ec2.instance_id).into())
+    };
+
+    //already interrupted
+    let reason = instance.state_reason.and_then(|reason| reason.code);
+    let action = match reason.as_deref() {
+        Some("Server.SpotInstanceShutdown") => Some("stop"),
+        Some("Server.SpotInstanceTermination") => Some("terminate"),
+        _ => None
+    };
+    if let Some(action) = action {
+        return Ok(Some(InterruptionNotice {
+            action: action.to_string(),
+            time: None
+        }));
+    }
+
+    //about to be interrupted
+    let request_id = match instance.spot_instance_request_id {
+        Some(id) => id,
+        None => return Ok(None)
+    };
+    let desc_req = DescribeSpotInstanceRequestsRequest {
+        spot_instance_request_ids: Some(vec![request_id]),
+        ..Default::default()
+    };
+    let desc_res = ec2.client.describe_spot_instance_requests(desc_req).await?;
+    let status = desc_res.spot_instance_requests.unwrap_or_default()
+        .into_iter()
+        .find_map(|request| request.status);
+    let status = match status {
+        Some(status) => status,
+        None => return Ok(None)
+    };
+    let action = match status.code.as_deref() {
+        Some("marked-for-stop") => "stop",
+        Some("marked-for-hibernation") => "hibernate",
+        Some("marked-for-termination") => "terminate",
+        _ => return Ok(None)
+    };
+    //update_time is when the request was marked, the action follows after the notice period
+    let time = match status.update_time {
+        Some(time) => {
+            let marked = DateTime::parse_from_rfc3339(&time)?.with_timezone(&Utc);
+            Some(marked + chrono::Duration::minutes(NOTICE_MINUTES))
+        },
+        None => None
+    };
+    Ok(Some(InterruptionNotice {
+        action: action.to_string(),
+        time
+    }))
+}
+
+/// Blocks until ec2 gets an interruption notice, checking every interval
+pub async fn wait_for_interruption(ec2: &Ec2Object, interval: Duration) -> Result<InterruptionNotice, Box<dyn Error>> {
+    loop {
+        if let Some(notice) = check_state(ec2).await? {
+            return Ok(notice);
+        }
+        tokio::time::delay_for(interval).await;
+    }
+}
+
+/// Parses the output of INSTANCE_ACTION_COMMAND
+fn parse_instance_action(output: &str) -> Result<Option<InterruptionNotice>, Box<dyn Error>> {
+    let output = output.trim_end();
+    let (body, status) = match output.rfind('\n') {
+        Some(i) => (&output[..i], &output[i + 1..]),
+        None => ("", output)
+    };
+    match status {
+        "404" => Ok(None),
+        "200" => {
+            let action: serde_json::Value = serde_json::from_str(body)?;
+            let time = match action["time"].as_str() {
+                Some(time) => Some(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc)),
+                None => None
+            };
+            match action["action"].as_str() {
+                Some(action) => Ok(Some(InterruptionNotice {
+                    action: action.to_string(),
+                    time
+                })),
+                None => Err(format!("instance-action has no action: <{}>", body).into())
+            }
+        },
+        other => Err(format!("unexpected metadata status <{}>, is this a spot instance?", other).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self::chrono::TimeZone;
+    use crate::virtual_machine::ec2::test_utils::mock_client_sequence;
+
+    #[test]
+    fn one_time_must_terminate() {
+        let options = SpotOptions {
+            interruption_behavior: InterruptionBehavior::Stop,
+            ..Default::default()
+        };
+        assert!(options.to_market_options().is_err());
+
+        let options = SpotOptions {
+            max_price: Some("0.01".to_string()),
+            request_type: SpotRequestType::Persistent,
+            interruption_behavior: InterruptionBehavior::Hibernate
+        };
+        let spot_options = options.to_market_options().unwrap().spot_options.unwrap();
+        assert_eq!(spot_options.spot_instance_type, Some("persistent".to_string()));
+        assert_eq!(spot_options.instance_interruption_behavior, Some("hibernate".to_string()));
+        assert_eq!(spot_options.max_price, Some("0.01".to_string()));
+    }
+
+    #[test]
+    fn metadata_notice() -> Result<(), Box<dyn Error>> {
+        assert_eq!(parse_instance_action("<html>Not Found</html>\n404")?, None);
+
+        let notice = parse_instance_action("{\"action\": \"terminate\", \"time\": \"2020-10-18T08:22:00Z\"}\n200\n")?;
+        assert_eq!(notice, Some(InterruptionNotice {
+            action: "terminate".to_string(),
+            time: Some(Utc.with_ymd_and_hms(2020, 10, 18, 8, 22, 0).unwrap())
+        }));
+
+        assert!(parse_instance_action("\n000").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn marked_for_stop() -> Result<(), Box<dyn Error>> {
+        let describe_instances = r#"<DescribeInstancesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <reservationSet><item><instancesSet><item>
+                <instanceId>i-0123</instanceId>
+                <imageId>ami-07efac79022b86107</imageId>
+                <instanceType>t2.micro</instanceType>
+                <instanceState><code>16</code><name>running</name></instanceState>
+                <instanceLifecycle>spot</instanceLifecycle>
+                <spotInstanceRequestId>sir-0123</spotInstanceRequestId>
+            </item></instancesSet></item></reservationSet>
+        </DescribeInstancesResponse>"#;
+        let describe_spot = r#"<DescribeSpotInstanceRequestsResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <spotInstanceRequestSet><item>
+                <spotInstanceRequestId>sir-0123</spotInstanceRequestId>
+                <status>
+                    <code>marked-for-stop</code>
+                    <updateTime>2020-10-18T08:20:00.000Z</updateTime>
+                </status>
+            </item></spotInstanceRequestSet>
+        </DescribeSpotInstanceRequestsResponse>"#;
+        let ec2 = Ec2Object {
+            client: mock_client_sequence(&[describe_instances, describe_spot]),
+            image_id: "ami-07efac79022b86107".to_string(),
+            instance_type: "t2.micro".to_string(),
+            instance_id: "i-0123".to_string()
+        };
+
+        let notice = tokio_test::block_on(check_state(&ec2))?.unwrap();
+        assert_eq!(notice.action, "stop");
+        //marked at 8:20, stopped two minutes later
+        assert_eq!(notice.time, Some(Utc.with_ymd_and_hms(2020, 10, 18, 8, 22, 0).unwrap()));
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! Helpers for testing against mocked aws clients
+use rusoto_core::Region;
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_ec2::Ec2Client;
+use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher};
+
+/// ec2 client that answers every request with body
+pub fn mock_client(body: &str) -> Ec2Client {
+    Ec2Client::new_with(MockRequestDispatcher::default().with_body(body), MockCredentialsProvider, Region::UsEast2)
+}
+
+/// ec2 client that passes every request to checker before answering with body
+pub fn mock_client_checked<F>(body: &str, checker: F) -> Ec2Client
+    where F: Fn(&SignedRequest) + Send + Sync + 'static {
+    let dispatcher = MockRequestDispatcher::default()
+        .with_body(body)
+        .with_request_checker(checker);
+    Ec2Client::new_with(dispatcher, MockCredentialsProvider, Region::UsEast2)
+}
+
+/// ec2 client that answers each request with the next body in bodies
+pub fn mock_client_sequence<S: AsRef<str>>(bodies: &[S]) -> Ec2Client {
+    let dispatchers: Vec<MockRequestDispatcher> = bodies.iter()
+        .map(|body| MockRequestDispatcher::default().with_body(body.as_ref()))
+        .collect();
+    Ec2Client::new_with(MultipleMockRequestDispatcher::new(dispatchers), MockCredentialsProvider, Region::UsEast2)
+}
+
+/// form encoded parameters a query api like ec2, iam or sts was sent with request
+pub fn request_params(request: &SignedRequest) -> String {
+    match &request.payload {
+        Some(SignedRequestPayload::Buffer(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+        _ => String::new()
+    }
+}
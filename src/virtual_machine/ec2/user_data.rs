@@ -0,0 +1,225 @@
+extern crate base64;
+extern crate serde_json;
+
+use std::error::Error;
+
+use crate::ssh::ssh_agent::SSHAgent;
+use crate::virtual_machine::ec2::waiter::Waiter;
+
+/// Most user data ec2 accepts, before base64 encoding
+pub const MAX_USER_DATA_BYTES: usize = 16 * 1024;
+
+/// What an instance runs on its first boot
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserData {
+    /// raw user data, eg. a shell script starting with #!/bin/bash
+    Script(String),
+    CloudConfig(CloudConfig)
+}
+
+impl UserData {
+    /// user data as the instance sees it
+    pub fn render(&self) -> String {
+        match self {
+            UserData::Script(script) => script.clone(),
+            UserData::CloudConfig(config) => config.render()
+        }
+    }
+
+    /// base64 user data for a RunInstances request.
+    ///     Errors if the rendered user data is bigger than MAX_USER_DATA_BYTES
+    pub fn encode(&self) -> Result<String, Box<dyn Error>> {
+        let rendered = self.render();
+        if rendered.len() > MAX_USER_DATA_BYTES {
+            return Err(format!("user data is {} bytes, ec2 accepts at most {}", rendered.len(), MAX_USER_DATA_BYTES).into());
+        }
+        Ok(base64::encode(rendered))
+    }
+}
+
+/// A file cloud-init writes before running commands
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteFile {
+    pub path: String,
+    pub content: String,
+    /// octal, eg. 0644
+    pub permissions: Option<String>,
+    /// user:group
+    pub owner: Option<String>
+}
+
+/// A user cloud-init creates
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct User {
+    pub name: String,
+    pub groups: Vec<String>,
+    pub shell: Option<String>,
+    /// eg. ALL=(ALL) NOPASSWD:ALL
+    pub sudo: Option<String>,
+    pub ssh_authorized_keys: Vec<String>
+}
+
+/// A #cloud-config document, see https://cloudinit.readthedocs.io/en/latest/topics/examples.html
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CloudConfig {
+    pub package_update: bool,
+    pub packages: Vec<String>,
+    pub write_files: Vec<WriteFile>,
+    pub users: Vec<User>,
+    /// shell commands run in order, last thing on first boot
+    pub runcmd: Vec<String>
+}
+
+impl CloudConfig {
+    pub fn new() -> CloudConfig {
+        Self::default()
+    }
+    /// updates the package index before installing packages
+    pub fn package_update(mut self) -> CloudConfig {
+        self.package_update = true;
+        self
+    }
+    pub fn package(mut self, package: &str) -> CloudConfig {
+        self.packages.push(package.to_string());
+        self
+    }
+    pub fn write_file(mut self, file: WriteFile) -> CloudConfig {
+        self.write_files.push(file);
+        self
+    }
+    pub fn user(mut self, user: User) -> CloudConfig {
+        self.users.push(user);
+        self
+    }
+    pub fn runcmd(mut self, command: &str) -> CloudConfig {
+        self.runcmd.push(command.to_string());
+        self
+    }
+
+    /// Renders as yaml. Every string is written json quoted, which yaml reads as a double quoted string
+    pub fn render(&self) -> String {
+        let quote = |value: &str| serde_json::to_string(value).unwrap();
+        let mut yaml = String::from("#cloud-config\n");
+
+        if self.package_update {
+            yaml.push_str("package_update: true\n");
+        }
+        if !self.packages.is_empty() {
+            yaml.push_str("packages:\n");
+            for package in &self.packages {
+                yaml.push_str(&format!("  - {}\n", quote(package)));
+            }
+        }
+        if !self.users.is_empty() {
+            //default keeps the image's own user (ubuntu) which SSHAgent logs in as
+            yaml.push_str("users:\n  - default\n");
+            for user in &self.users {
+                yaml.push_str(&format!("  - name: {}\n", quote(&user.name)));
+                if !user.groups.is_empty() {
+                    yaml.push_str(&format!("    groups: {}\n", quote(&user.groups.join(", "))));
+                }
+                if let Some(shell) = &user.shell {
+                    yaml.push_str(&format!("    shell: {}\n", quote(shell)));
+                }
+                if let Some(sudo) = &user.sudo {
+                    yaml.push_str(&format!("    sudo: {}\n", quote(sudo)));
+                }
+                if !user.ssh_authorized_keys.is_empty() {
+                    yaml.push_str("    ssh_authorized_keys:\n");
+                    for key in &user.ssh_authorized_keys {
+                        yaml.push_str(&format!("      - {}\n", quote(key)));
+                    }
+                }
+            }
+        }
+        if !self.write_files.is_empty() {
+            yaml.push_str("write_files:\n");
+            for file in &self.write_files {
+                yaml.push_str(&format!("  - path: {}\n", quote(&file.path)));
+                yaml.push_str(&format!("    content: {}\n", quote(&file.content)));
+                if let Some(permissions) = &file.permissions {
+                    yaml.push_str(&format!("    permissions: {}\n", quote(permissions)));
+                }
+                if let Some(owner) = &file.owner {
+                    yaml.push_str(&format!("    owner: {}\n", quote(owner)));
+                }
+            }
+        }
+        if !self.runcmd.is_empty() {
+            yaml.push_str("runcmd:\n");
+            for command in &self.runcmd {
+                yaml.push_str(&format!("  - {}\n", quote(command)));
+            }
+        }
+        yaml
+    }
+}
+
+/// Blocks until cloud-init on the instance agent is connected to has finished.
+///     Errors if cloud-init failed or waiter times out
+pub async fn wait_for_cloud_init(agent: &SSHAgent, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+    waiter.wait_for("done", &["not run", "not started", "running"], || async {
+        let output = agent.exec("cloud-init status")?;
+        parse_cloud_init_status(&output.stdout)
+    }).await
+}
+
+/// Gets status out of the output of `cloud-init status`, eg. "status: running"
+fn parse_cloud_init_status(output: &str) -> Result<String, Box<dyn Error>> {
+    match output.lines().find_map(|line| line.trim().strip_prefix("status:")) {
+        Some(status) => Ok(status.trim().to_string()),
+        None => Err(format!("unexpected cloud-init status output: <{}>", output.trim()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_cloud_config() {
+        let config = CloudConfig::new()
+            .package_update()
+            .package("openjdk-17-jre-headless")
+            .write_file(WriteFile {
+                path: "/opt/minecraft/eula.txt".to_string(),
+                content: "eula=true\n".to_string(),
+                permissions: Some("0644".to_string()),
+                owner: None
+            })
+            .runcmd("echo \"hello\"");
+
+        assert_eq!(config.render(), "#cloud-config
+package_update: true
+packages:
+  - \"openjdk-17-jre-headless\"
+write_files:
+  - path: \"/opt/minecraft/eula.txt\"
+    content: \"eula=true\\n\"
+    permissions: \"0644\"
+runcmd:
+  - \"echo \\\"hello\\\"\"
+");
+    }
+
+    #[test]
+    fn encode_script() -> Result<(), Box<dyn Error>> {
+        let user_data = UserData::Script("#!/bin/bash\necho hi\n".to_string());
+        assert_eq!(base64::decode(user_data.encode()?)?, b"#!/bin/bash\necho hi\n");
+        Ok(())
+    }
+
+    #[test]
+    fn encode_enforces_limit() {
+        let user_data = UserData::Script("#".repeat(MAX_USER_DATA_BYTES + 1));
+        assert!(user_data.encode().is_err());
+        assert!(UserData::Script("#".repeat(MAX_USER_DATA_BYTES)).encode().is_ok());
+    }
+
+    #[test]
+    fn cloud_init_status() {
+        assert_eq!(parse_cloud_init_status("status: running\n").unwrap(), "running");
+        assert_eq!(parse_cloud_init_status("\nstatus: done\n").unwrap(), "done");
+        assert!(parse_cloud_init_status("command not found").is_err());
+    }
+}
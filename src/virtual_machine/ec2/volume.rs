@@ -0,0 +1,311 @@
+use std::error::Error;
+
+use rusoto_ec2::{Ec2Client, Ec2};
+use rusoto_ec2::{CreateVolumeRequest, DescribeVolumesRequest, DeleteVolumeRequest};
+use rusoto_ec2::{AttachVolumeRequest, DetachVolumeRequest, ModifyVolumeRequest};
+use rusoto_ec2::{TagSpecification, Tag};
+
+use crate::virtual_machine::ec2::instance::Ec2Object;
+use crate::virtual_machine::ec2::waiter::Waiter;
+
+/// Device name ec2 suggests for the first extra volume of a linux instance
+pub const DEFAULT_DEVICE: &str = "/dev/sdf";
+
+/// Settings for creating a new EBS volume.
+/// Defaults to an unencrypted 8GiB gp2 volume
+#[derive(Debug, Clone)]
+pub struct VolumeOptions {
+    /// size in GiB
+    pub size: i64,
+    /// gp2, gp3, io1, io2, st1, sc1 or standard
+    pub volume_type: String,
+    /// provisioned iops, only for io1, io2 and gp3
+    pub iops: Option<i64>,
+    pub encrypted: bool,
+    /// kms key to encrypt with, uses the account's default EBS key if None
+    pub kms_key_id: Option<String>,
+    /// snapshot to fill the volume from, see snapshot::Snapshot::restore
+    pub snapshot_id: Option<String>,
+    pub tags: Vec<Tag>
+}
+
+impl Default for VolumeOptions {
+    fn default() -> Self {
+        VolumeOptions {
+            size: 8,
+            volume_type: "gp2".to_string(),
+            iops: None,
+            encrypted: false,
+            kms_key_id: None,
+            snapshot_id: None,
+            tags: vec![]
+        }
+    }
+}
+
+/// An EBS volume that can be attached to an Ec2Object
+pub struct Volume {
+    pub client: Ec2Client,
+    pub volume_id: String,
+    pub availability_zone: String,
+    /// size in GiB
+    pub size: i64,
+    pub volume_type: String,
+    //Anything with &mut self needs to update this
+}
+
+impl Volume {
+    /// Creates a new volume in availability_zone
+    pub async fn create(client: Ec2Client, availability_zone: &str, options: &VolumeOptions) -> Result<Volume, Box<dyn Error>> {
+        let tag_specifications = if options.tags.is_empty() {
+            None
+        } else {
+            Some(vec![TagSpecification{
+                resource_type: Some("volume".to_string()),
+                tags: Some(options.tags.clone())
+            }])
+        };
+        let create_req = CreateVolumeRequest {
+            availability_zone: availability_zone.to_string(),
+            size: Some(options.size),
+            volume_type: Some(options.volume_type.clone()),
+            iops: options.iops,
+            encrypted: Some(options.encrypted),
+            kms_key_id: options.kms_key_id.clone(),
+            snapshot_id: options.snapshot_id.clone(),
+            tag_specifications,
+            ..Default::default()
+        };
+        let volume = client.create_volume(create_req).await?;
+
+        match Self::from_info(client, volume) {
+            Some(volume) => Ok(volume),
+            None => Err("created volume is missing its id".into())
+        }
+    }
+
+    /// Creates a new volume in the same availability zone as ec2 so it can be attached to it
+    pub async fn create_for(ec2: &Ec2Object, options: &VolumeOptions) -> Result<Volume, Box<dyn Error>> {
+        let availability_zone = ec2.availability_zone().await?;
+        Self::create(ec2.client.clone(), &availability_zone, options).await
+    }
+
+    /// Retrieves volume by id.
+    /// returns None if there is no volume with volume_id
+    pub async fn retrieve(client: Ec2Client, volume_id: &str) -> Result<Option<Volume>, Box<dyn Error>> {
+        match Self::describe(&client, volume_id).await? {
+            Some(volume) => Ok(Self::from_info(client, volume)),
+            None => Ok(None)
+        }
+    }
+
+    /// Gets current state of this volume: creating, available, in-use, deleting, deleted or error
+    pub async fn state(&self) -> Result<String, Box<dyn Error>> {
+        match Self::describe(&self.client, &self.volume_id).await? {
+            Some(volume) => match volume.state {
+                Some(state) => Ok(state),
+                None => Err(format!("volume <{}> has no state", self.volume_id).into())
+            },
+            None => Err(format!("couldn't find volume <{}>", self.volume_id).into())
+        }
+    }
+
+    /// id of the instance this volume is attached to, None if it isn't attached
+    pub async fn attached_instance_id(&self) -> Result<Option<String>, Box<dyn Error>> {
+        match Self::describe(&self.client, &self.volume_id).await? {
+            Some(volume) => Ok(volume.attachments.unwrap_or_default()
+                .into_iter()
+                .find_map(|attachment| attachment.instance_id)),
+            None => Err(format!("couldn't find volume <{}>", self.volume_id).into())
+        }
+    }
+
+    /// Attaches this volume to ec2 as device (eg. DEFAULT_DEVICE).
+    /// Does not wait for the attachment to finish, see wait_until_in_use
+    pub async fn attach(&self, ec2: &Ec2Object, device: &str) -> Result<(), Box<dyn Error>> {
+        let attach_req = AttachVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            instance_id: ec2.instance_id.clone(),
+            device: device.to_string(),
+            ..Default::default()
+        };
+        self.client.attach_volume(attach_req).await?;
+        Ok(())
+    }
+
+    /// Detaches this volume from whatever instance it is attached to.
+    /// force may lose data that hasn't been flushed, only use it if a normal detach is stuck
+    pub async fn detach(&self, force: bool) -> Result<(), Box<dyn Error>> {
+        let detach_req = DetachVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            force: Some(force),
+            ..Default::default()
+        };
+        self.client.detach_volume(detach_req).await?;
+        Ok(())
+    }
+
+    /// Blocks until this volume is available, eg. after being created or detached
+    pub async fn wait_until_available(&self, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        waiter.wait_for("available", &["creating", "in-use"], || self.state()).await
+    }
+
+    /// Blocks until this volume is in use, eg. after being attached
+    pub async fn wait_until_in_use(&self, waiter: &Waiter) -> Result<(), Box<dyn Error>> {
+        waiter.wait_for("in-use", &["available"], || self.state()).await
+    }
+
+    /// Changes the size, type and/or iops of this volume. Values that are None are left as they are.
+    /// Volumes can only grow, and the file system has to be extended separately to use the new space
+    pub async fn modify(&mut self, size: Option<i64>, volume_type: Option<&str>, iops: Option<i64>) -> Result<(), Box<dyn Error>> {
+        if let Some(size) = size {
+            if size < self.size {
+                return Err(format!("volumes can't shrink, <{}> is {}GiB", self.volume_id, self.size).into());
+            }
+        }
+        let modify_req = ModifyVolumeRequest {
+            volume_id: self.volume_id.clone(),
+            size,
+            volume_type: volume_type.map(|volume_type| volume_type.to_string()),
+            iops,
+            ..Default::default()
+        };
+        self.client.modify_volume(modify_req).await?;
+
+        if let Some(size) = size {
+            self.size = size;
+        }
+        if let Some(volume_type) = volume_type {
+            self.volume_type = volume_type.to_string();
+        }
+        Ok(())
+    }
+
+    /// Deletes this volume. It must be detached first
+    pub async fn delete(self) -> Result<(), Box<dyn Error>> {
+        let delete_req = DeleteVolumeRequest {
+            volume_id: self.volume_id,
+            ..Default::default()
+        };
+        self.client.delete_volume(delete_req).await?;
+        Ok(())
+    }
+
+    async fn describe(client: &Ec2Client, volume_id: &str) -> Result<Option<rusoto_ec2::Volume>, Box<dyn Error>> {
+        let desc_req = DescribeVolumesRequest {
+            volume_ids: Some(vec![volume_id.to_string()]),
+            ..Default::default()
+        };
+        let desc_res = client.describe_volumes(desc_req).await?;
+        Ok(desc_res.volumes.unwrap_or_default()
+            .into_iter()
+            .find(|volume| volume.volume_id.as_deref() == Some(volume_id)))
+    }
+
+    fn from_info(client: Ec2Client, volume: rusoto_ec2::Volume) -> Option<Volume> {
+        Some(Volume {
+            client,
+            volume_id: volume.volume_id?,
+            availability_zone: volume.availability_zone.unwrap_or_default(),
+            size: volume.size.unwrap_or_default(),
+            volume_type: volume.volume_type.unwrap_or_default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::virtual_machine::ec2::test_utils::{mock_client_checked, mock_client_sequence, request_params};
+
+    fn describe_body(state: &str) -> String {
+        format!(r#"<DescribeVolumesResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <volumeSet>
+                <item>
+                    <volumeId>vol-0123</volumeId>
+                    <size>20</size>
+                    <availabilityZone>us-east-2a</availabilityZone>
+                    <status>{}</status>
+                    <volumeType>gp2</volumeType>
+                </item>
+            </volumeSet>
+        </DescribeVolumesResponse>"#, state)
+    }
+
+    fn volume(client: Ec2Client) -> Volume {
+        Volume {
+            client,
+            volume_id: "vol-0123".to_string(),
+            availability_zone: "us-east-2a".to_string(),
+            size: 20,
+            volume_type: "gp2".to_string()
+        }
+    }
+
+    #[test]
+    fn create_sends_options() -> Result<(), Box<dyn Error>> {
+        let body = r#"<CreateVolumeResponse xmlns="http://ec2.amazonaws.com/doc/2016-11-15/">
+            <requestId>req</requestId>
+            <volumeId>vol-0123</volumeId>
+            <size>20</size>
+            <availabilityZone>us-east-2a</availabilityZone>
+            <status>creating</status>
+            <volumeType>io1</volumeType>
+            <iops>1000</iops>
+            <encrypted>true</encrypted>
+        </CreateVolumeResponse>"#;
+        let client = mock_client_checked(body, |req| {
+            let params = request_params(req);
+            assert!(params.contains("AvailabilityZone=us-east-2a"));
+            assert!(params.contains("Size=20"));
+            assert!(params.contains("Iops=1000"));
+            assert!(params.contains("Encrypted=true"));
+        });
+        let options = VolumeOptions {
+            size: 20,
+            volume_type: "io1".to_string(),
+            iops: Some(1000),
+            encrypted: true,
+            ..Default::default()
+        };
+
+        let volume = tokio_test::block_on(Volume::create(client, "us-east-2a", &options))?;
+        assert_eq!(volume.volume_id, "vol-0123");
+        assert_eq!(volume.volume_type, "io1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waits_until_available() -> Result<(), Box<dyn Error>> {
+        let bodies = vec![describe_body("creating"), describe_body("creating"), describe_body("available")];
+        let volume = volume(mock_client_sequence(&bodies));
+
+        volume.wait_until_available(&Waiter::new(Duration::from_millis(1), Duration::from_secs(1))).await
+    }
+
+    #[test]
+    fn modify_refuses_to_shrink() {
+        let client = mock_client_checked("", |_req| panic!("should not modify volume"));
+        let mut volume = volume(client);
+
+        assert!(tokio_test::block_on(volume.modify(Some(10), None, None)).is_err());
+        assert_eq!(volume.size, 20);
+    }
+
+    #[test]
+    fn modify_updates_size_and_type() -> Result<(), Box<dyn Error>> {
+        let client = mock_client_checked("<ModifyVolumeResponse></ModifyVolumeResponse>", |req| {
+            let params = request_params(req);
+            assert!(params.contains("Size=40"));
+            assert!(params.contains("VolumeType=gp3"));
+        });
+        let mut volume = volume(client);
+
+        tokio_test::block_on(volume.modify(Some(40), Some("gp3"), None))?;
+        assert_eq!(volume.size, 40);
+        assert_eq!(volume.volume_type, "gp3");
+        Ok(())
+    }
+}
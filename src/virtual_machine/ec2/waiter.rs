@@ -0,0 +1,93 @@
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Polls the state of an AWS resource until it reaches a target state
+#[derive(Debug, Clone, Copy)]
+pub struct Waiter {
+    /// time between two polls
+    pub interval: Duration,
+    /// time after which waiting gives up
+    pub timeout: Duration
+}
+
+impl Default for Waiter {
+    fn default() -> Self {
+        Waiter {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10 * 60)
+        }
+    }
+}
+
+impl Waiter {
+    pub fn new(interval: Duration, timeout: Duration) -> Waiter {
+        Waiter {
+            interval,
+            timeout
+        }
+    }
+
+    /// Calls get_state until it returns target.
+    ///     Errors if a state that isn't target or in transitional is returned, or on timeout
+    pub async fn wait_for<F, Fut>(&self, target: &str, transitional: &[&str], get_state: F) -> Result<(), Box<dyn Error>>
+        where F: Fn() -> Fut, Fut: Future<Output = Result<String, Box<dyn Error>>> {
+        let start = Instant::now();
+        loop {
+            let state = get_state().await?;
+            if state == target {
+                return Ok(());
+            }
+            if !transitional.contains(&state.as_str()) {
+                return Err(format!("waited for <{}> but got <{}>", target, state).into());
+            }
+            if start.elapsed() >= self.timeout {
+                return Err(format!("timed out after {:?} waiting for <{}>, still <{}>", self.timeout, target, state).into());
+            }
+            tokio::time::delay_for(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn states(states: &[&str]) -> Mutex<Vec<String>> {
+        Mutex::new(states.iter().rev().map(|state| state.to_string()).collect())
+    }
+
+    #[tokio::test]
+    async fn waits_through_transitional() {
+        let remaining = states(&["pending", "pending", "running"]);
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_secs(1));
+
+        let waited = waiter.wait_for("running", &["pending"], || async {
+            Ok(remaining.lock().unwrap().pop().unwrap())
+        }).await;
+        assert!(waited.is_ok());
+        assert!(remaining.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unexpected_state_errors() {
+        let remaining = states(&["pending", "terminated"]);
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_secs(1));
+
+        let waited = waiter.wait_for("running", &["pending"], || async {
+            Ok(remaining.lock().unwrap().pop().unwrap())
+        }).await;
+        assert!(waited.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let waiter = Waiter::new(Duration::from_millis(1), Duration::from_millis(5));
+
+        let waited = waiter.wait_for("running", &["pending"], || async {
+            Ok("pending".to_string())
+        }).await;
+        assert!(waited.is_err());
+    }
+}
@@ -0,0 +1,88 @@
+//! A vm that only exists in memory, for testing code written against the vm traits
+use std::error::Error;
+
+use async_trait::async_trait;
+
+use crate::virtual_machine::vm::{VMCore, VMNetwork, VMSnapshot, NetworkInfo};
+
+pub struct MockVM {
+    /// running or stopped
+    pub state: String,
+    /// reported while running
+    pub public_ip: Option<String>,
+    /// every start and stop made, in order
+    pub calls: Vec<String>
+}
+
+impl MockVM {
+    pub fn running(public_ip: &str) -> MockVM {
+        MockVM {
+            state: "running".to_string(),
+            public_ip: Some(public_ip.to_string()),
+            calls: vec![]
+        }
+    }
+
+    pub fn stopped() -> MockVM {
+        MockVM {
+            state: "stopped".to_string(),
+            public_ip: None,
+            calls: vec![]
+        }
+    }
+}
+
+#[async_trait]
+impl VMCore for MockVM {
+    async fn retrieve(_instance_id: &str, _role_arn: &str) -> Option<Self> {
+        None
+    }
+
+    async fn status(&self) -> Option<String> {
+        Some(self.state.clone())
+    }
+
+    async fn stop(&mut self) -> Result<String, Box<dyn Error>> {
+        self.calls.push("stop".to_string());
+        if self.state != "running" {
+            return Err(format!("can't stop a vm that is {}", self.state).into());
+        }
+        self.state = "stopped".to_string();
+        Ok(self.state.clone())
+    }
+
+    async fn start(&mut self) -> Result<String, Box<dyn Error>> {
+        self.calls.push("start".to_string());
+        if self.state != "stopped" {
+            return Err(format!("can't start a vm that is {}", self.state).into());
+        }
+        self.state = "running".to_string();
+        Ok(self.state.clone())
+    }
+}
+
+#[async_trait]
+impl VMNetwork for MockVM {
+    async fn get_public_ip(&self) -> Option<String> {
+        self.public_ip.clone().filter(|_| self.state == "running")
+    }
+
+    async fn is_public_ip_elastic(&self) -> Option<bool> {
+        self.get_public_ip().await.map(|_| false)
+    }
+
+    async fn network_info(&self) -> Result<NetworkInfo, Box<dyn Error>> {
+        Ok(NetworkInfo {
+            state: Some(self.state.clone()),
+            public_ip: self.get_public_ip().await,
+            ..Default::default()
+        })
+    }
+}
+
+#[async_trait]
+impl VMSnapshot for MockVM {
+    async fn snapshot(&self, volume_id: Option<&str>, _description: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(vec![format!("snap-{}", volume_id.unwrap_or("all"))])
+    }
+}
@@ -1,2 +1,5 @@
 pub mod vm;
-pub mod ec2;
\ No newline at end of file
+pub mod ec2;
+
+#[cfg(test)]
+pub(crate) mod mock;
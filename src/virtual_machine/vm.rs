@@ -46,7 +46,52 @@ pub trait VMAdmin: Default {
     async fn terminate(&mut self) -> Result<String, Box<dyn Error>>;
 }
 #[async_trait]
-pub trait VMNetwork {
+pub trait VMNetwork: Sync {
     ///returns public ip address of this ec2. Ec2 returns None if ec2 not running
     async fn get_public_ip(&self) -> Option<String>;
+    ///returns whether the public ip is elastic, ie. stays the same across stops and starts.
+    /// None if there is no public ip or it couldn't be checked, which is all the default knows
+    async fn is_public_ip_elastic(&self) -> Option<bool> {
+        None
+    }
+    ///returns every address, name and network this vm is reachable through.
+    /// Errors if the vm couldn't be found or looked up, or by default as it isn't supported
+    async fn network_info(&self) -> Result<NetworkInfo, Box<dyn Error>> {
+        Err("network info is not supported".into())
+    }
+}
+#[async_trait]
+pub trait VMSnapshot {
+    ///starts a backup of the vm's disk volume_id, or of all its disks if None, returning the ids of the backups.
+    /// Doesn't wait for them to complete
+    async fn snapshot(&self, volume_id: Option<&str>, description: &str) -> Result<Vec<String>, Box<dyn Error>>;
+}
+
+/// Everything about how a vm is connected to the network
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NetworkInfo {
+    /// state of the vm, explains a missing public ip (eg. stopped)
+    pub state: Option<String>,
+    pub public_ip: Option<String>,
+    pub private_ip: Option<String>,
+    pub public_dns_name: Option<String>,
+    pub private_dns_name: Option<String>,
+    pub ipv6_addresses: Vec<String>,
+    pub vpc_id: Option<String>,
+    pub subnet_id: Option<String>,
+    pub security_group_ids: Vec<String>,
+    pub network_interfaces: Vec<NetworkInterfaceInfo>
+}
+
+/// A single network interface attached to a vm
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NetworkInterfaceInfo {
+    pub network_interface_id: String,
+    pub mac_address: Option<String>,
+    pub subnet_id: Option<String>,
+    /// primary private ip first
+    pub private_ips: Vec<String>,
+    pub public_ip: Option<String>,
+    pub ipv6_addresses: Vec<String>,
+    pub security_group_ids: Vec<String>
 }
\ No newline at end of file